@@ -1,91 +1,125 @@
 use crate::glob_matcher::GLOB_CHARS;
 
+mod checksum;
+mod collisions;
+mod scheduler;
+
+pub(crate) use self::checksum::manifest_line;
+use self::checksum::{Digests, EtagEncryption, Expected};
+use self::scheduler::Link;
+pub(crate) use self::scheduler::{Scheduler, SchedulerLimits};
+use super::IfExists;
+use super::OnCollision;
 use super::PathMode;
 use super::S3Object;
-use super::add_atomic;
+use super::UnsafeKeys;
+use crate::rate_limit::RequestRate;
+use crate::stats::{Api, ApiCalls, Phase};
+use crate::{progressln, retry};
+use anyhow::Context as _;
 use aws_sdk_s3::Client;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::get_object::builders::GetObjectFluentBuilder;
+use aws_sdk_s3::types::ChecksumMode;
+use aws_smithy_checksums::ChecksumAlgorithm;
+use futures::{StreamExt as _, TryStreamExt as _};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime};
 use tokio::io::AsyncWriteExt as _;
-use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::mpsc::UnboundedSender;
-use tracing::warn;
+use tokio::sync::watch;
+use tracing::{debug, warn};
 
-/// A collection of pools for downloading objects
-///
-/// The general idea is that we want to saturate pretty fast internet,
-/// while still prioritizing completing downloads over starting a lot of
-/// concurrent downloads.
-///
-/// So, we have different numbers of parallel downloads allowed simultaneously
-/// (e.g. 500 parallel for objects that are less than 200kb, but 4 for objects
-/// that are over 10mb).
+/// How large objects are split into concurrent ranged GETs
 ///
-/// These numbers are loosely based on my experience, I haven't done a ton of
-/// benchmarking.
-pub(crate) struct DlPools {
-    pub(crate) two_hundred_kb: UnboundedSender<(Downloader, S3Object)>,
-    pub(crate) one_mb: UnboundedSender<(Downloader, S3Object)>,
-    pub(crate) ten_mb: UnboundedSender<(Downloader, S3Object)>,
-    pub(crate) more: UnboundedSender<(Downloader, S3Object)>,
+/// A single `get_object` body stream tops out well below what a fast link
+/// can do, so big objects are fetched as several byte ranges at once and
+/// written straight into place in the temp file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RangedGets {
+    /// Objects at least this big are downloaded in parts
+    pub(crate) threshold: u64,
+    /// The size of each part, the last one may be smaller
+    pub(crate) part_size: u64,
+    /// How many parts of a single object to fetch at once
+    pub(crate) concurrency: usize,
 }
 
-impl DlPools {
-    /// Create a new set of downloader pools
-    pub(crate) fn new(max_parallelism: usize) -> DlPools {
-        let (two_hundred_kb, rx) = tokio::sync::mpsc::unbounded_channel();
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(max_parallelism.min(500)));
-        start_threadpool(semaphore, rx);
-        let (one_mb, rx) = tokio::sync::mpsc::unbounded_channel();
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(max_parallelism.min(50)));
-        start_threadpool(semaphore, rx);
-
-        let (ten_mb, rx) = tokio::sync::mpsc::unbounded_channel();
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(max_parallelism.min(10)));
-        start_threadpool(semaphore, rx);
-
-        let (more, rx) = tokio::sync::mpsc::unbounded_channel();
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(max_parallelism.min(5)));
-        start_threadpool(semaphore, rx);
-
+impl Default for RangedGets {
+    fn default() -> Self {
         Self {
-            two_hundred_kb,
-            one_mb,
-            ten_mb,
-            more,
+            threshold: 32 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+            concurrency: 8,
         }
     }
+}
 
-    pub(crate) fn download_object(&self, dl: Downloader, object: S3Object) {
-        let size = object.size;
-        let tx = if size < 200_000 {
-            &self.two_hundred_kb
-        } else if size < 1_000_000 {
-            &self.one_mb
-        } else if size < 10_000_000 {
-            &self.ten_mb
+impl RangedGets {
+    fn applies_to(&self, size: u64) -> bool {
+        size >= self.threshold && size > self.part_size
+    }
+
+    /// How many connections downloading an object of `size` bytes can use
+    fn connections(&self, size: u64) -> usize {
+        if self.applies_to(size) {
+            let parts = size.div_ceil(self.part_size);
+            (parts as usize).min(self.concurrency).max(1)
         } else {
-            &self.more
-        };
-        tx.send((dl, object))
-            .expect("send on channel should succeed");
+            1
+        }
     }
 }
 
-pub(crate) fn start_threadpool(
-    semaphore: Arc<tokio::sync::Semaphore>,
-    mut rx: UnboundedReceiver<(Downloader, S3Object)>,
-) {
-    tokio::spawn(async move {
-        while let Some((dl, obj)) = rx.recv().await {
-            let permit = semaphore.clone().acquire_owned().await;
-            tokio::spawn(async move {
-                dl.download_object(obj).await;
-                drop(permit);
-            });
+/// Set once the user asks us to stop, so downloads can leave resumable files
+///
+/// The first Ctrl-C stops listing and starting downloads, and in-progress
+/// downloads keep what they have so far. A second Ctrl-C exits immediately.
+#[derive(Debug, Clone)]
+pub(crate) struct Interrupt(watch::Receiver<bool>);
+
+impl Interrupt {
+    /// Start listening for Ctrl-C
+    pub(crate) fn on_ctrl_c() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_err() {
+                // no signal handling on this platform, we'll never be interrupted
+                std::future::pending::<()>().await;
+            }
+            progressln!(
+                "Interrupted, keeping partial downloads to resume next time (Ctrl-C again to quit now)"
+            );
+            let _ = tx.send(true);
+            let _ = tokio::signal::ctrl_c().await;
+            std::process::exit(130);
+        });
+        Self(rx)
+    }
+
+    /// An interrupt that never fires
+    pub(crate) fn never() -> Self {
+        let (tx, rx) = watch::channel(false);
+        // keep the sender alive so `wait` never resolves
+        std::mem::forget(tx);
+        Self(rx)
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolve once the user has interrupted us
+    pub(crate) async fn wait(&self) {
+        let mut rx = self.0.clone();
+        if rx.wait_for(|set| *set).await.is_err() {
+            std::future::pending::<()>().await;
         }
-    });
+    }
 }
 
 #[derive(Debug)]
@@ -93,11 +127,25 @@ pub(crate) struct Downloader {
     pub(crate) client: Client,
     pub(crate) bucket: String,
     pub(crate) prefix_to_strip: String,
+    pub(crate) delimiter: String,
     pub(crate) flatten: bool,
     pub(crate) base_path: PathBuf,
-    pub(crate) obj_counter: Arc<AtomicUsize>,
-    pub(crate) obj_id: usize,
     pub(crate) notifier: UnboundedSender<Notification>,
+    pub(crate) ranged: RangedGets,
+    pub(crate) interrupt: Interrupt,
+    pub(crate) if_exists: IfExists,
+    pub(crate) preserve: bool,
+    pub(crate) verify: bool,
+    pub(crate) compute_sha256: bool,
+    pub(crate) unsafe_keys: UnsafeKeys,
+    pub(crate) on_collision: OnCollision,
+    pub(crate) link: Arc<Link>,
+    pub(crate) request_rate: Arc<RequestRate>,
+    pub(crate) api_calls: Arc<ApiCalls>,
+    /// Paths given out by [`Self::place`]
+    claims: collisions::Claims,
+    /// How many of them are new names
+    renamed_claims: usize,
 }
 
 #[derive(Debug)]
@@ -105,8 +153,28 @@ pub(crate) enum Notification {
     ObjectDownloaded {
         object: S3Object,
         local_path: PathBuf,
+        /// Saved under a new name because the destination already existed
+        renamed: bool,
+        /// The hex SHA256 of the contents, if asked for
+        sha256: Option<String>,
+    },
+    /// A file was already at the destination and `--if-exists` said to keep it
+    Skipped {
+        object: S3Object,
+        local_path: PathBuf,
     },
+    /// The object could not be downloaded, even after retrying
+    Failed {
+        object: S3Object,
+        error: String,
+    },
+    /// The user interrupted us, what we have so far is kept to resume later
+    Interrupted,
     BytesDownloaded(usize),
+    /// Bytes found in a temp file left behind by an earlier run
+    BytesResumed(usize),
+    /// Bytes from a failed attempt that were already reported as downloaded
+    BytesDiscarded(usize),
 }
 
 impl Downloader {
@@ -114,6 +182,7 @@ impl Downloader {
         client: Client,
         bucket: String,
         prefix_to_strip: String,
+        delimiter: String,
         flatten: bool,
         base_path: PathBuf,
         notifier: UnboundedSender<Notification>,
@@ -121,111 +190,1029 @@ impl Downloader {
         Self {
             client,
             bucket,
-            obj_counter: Arc::new(AtomicUsize::new(0)),
-            obj_id: 0,
             notifier,
             base_path,
             flatten,
             prefix_to_strip,
+            delimiter,
+            ranged: RangedGets::default(),
+            interrupt: Interrupt::never(),
+            if_exists: IfExists::Overwrite,
+            preserve: false,
+            verify: false,
+            compute_sha256: false,
+            unsafe_keys: UnsafeKeys::Reject,
+            on_collision: OnCollision::Suffix,
+            link: Arc::new(Link::unlimited()),
+            request_rate: Arc::default(),
+            api_calls: Arc::default(),
+            claims: collisions::Claims::default(),
+            renamed_claims: 0,
         }
     }
 
+    /// Set how large objects are split into ranged GETs
+    pub(crate) fn with_ranged_gets(mut self, ranged: RangedGets) -> Self {
+        self.ranged = ranged;
+        self
+    }
+
+    /// Stop downloads early, keeping partial files, when `interrupt` fires
+    pub(crate) fn with_interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+
+    /// Set what to do about files that are already at the destination
+    pub(crate) fn with_if_exists(mut self, if_exists: IfExists) -> Self {
+        self.if_exists = if_exists;
+        self
+    }
+
+    /// Copy object metadata onto downloaded files, see [`preserve_metadata`]
+    pub(crate) fn with_preserve(mut self, preserve: bool) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Check downloads against their ETag or additional checksum, and/or
+    /// compute their SHA256 for a checksum manifest
+    pub(crate) fn with_verification(mut self, verify: bool, compute_sha256: bool) -> Self {
+        self.verify = verify;
+        self.compute_sha256 = compute_sha256;
+        self
+    }
+
+    /// Set what to do with keys that aren't safe to use as local paths
+    pub(crate) fn with_unsafe_keys(mut self, unsafe_keys: UnsafeKeys) -> Self {
+        self.unsafe_keys = unsafe_keys;
+        self
+    }
+
+    /// Set what to do when several objects would get the same local path
+    pub(crate) fn with_on_collision(mut self, on_collision: OnCollision) -> Self {
+        self.on_collision = on_collision;
+        self
+    }
+
+    /// Report bandwidth and response times to, and be paced by, `scheduler`
+    pub(crate) fn with_scheduler(mut self, scheduler: &Scheduler) -> Self {
+        self.link = scheduler.link();
+        self
+    }
+
+    /// Keep GET and HEAD requests under `rate`
+    pub(crate) fn with_request_rate(mut self, rate: Arc<RequestRate>) -> Self {
+        self.request_rate = rate;
+        self
+    }
+
+    /// Count the GET and HEAD requests made in `calls`
+    pub(crate) fn with_api_calls(mut self, calls: Arc<ApiCalls>) -> Self {
+        self.api_calls = calls;
+        self
+    }
+
     /// Create a downloader that can safely download another object
     pub(crate) fn fresh(&self) -> Self {
-        let obj_id = add_atomic(&self.obj_counter, 1);
         Self {
             client: self.client.clone(),
             bucket: self.bucket.clone(),
-            obj_counter: Arc::clone(&self.obj_counter),
-            obj_id,
             notifier: self.notifier.clone(),
             prefix_to_strip: self.prefix_to_strip.clone(),
+            delimiter: self.delimiter.clone(),
             flatten: self.flatten,
             base_path: self.base_path.clone(),
+            ranged: self.ranged,
+            interrupt: self.interrupt.clone(),
+            if_exists: self.if_exists,
+            preserve: self.preserve,
+            verify: self.verify,
+            compute_sha256: self.compute_sha256,
+            unsafe_keys: self.unsafe_keys,
+            on_collision: self.on_collision,
+            link: self.link.clone(),
+            request_rate: self.request_rate.clone(),
+            api_calls: self.api_calls.clone(),
+            claims: collisions::Claims::default(),
+            renamed_claims: 0,
+        }
+    }
+
+    fn notify(&self, notification: Notification) {
+        self.notifier
+            .send(notification)
+            .expect("send on our channel should succeed");
+    }
+
+    /// Report `bytes` as downloaded, waiting here if that's over `--limit-rate`
+    async fn received(&self, bytes: usize) {
+        self.notify(Notification::BytesDownloaded(bytes));
+        let wait = self.link.received(bytes);
+        if !wait.is_zero() {
+            tokio::select! {
+                () = tokio::time::sleep(wait) => {}
+                () = self.interrupt.wait() => {}
+            }
+        }
+    }
+
+    /// Wait until `--max-requests-per-second` allows a request for `key`,
+    /// and count it
+    async fn request_slot(&self, api: Api, key: &str) {
+        tokio::select! {
+            () = self.request_rate.wait(key) => {}
+            () = self.interrupt.wait() => {}
         }
+        self.api_calls.record(Phase::Download, api);
     }
 
-    pub(crate) async fn download_object(self, obj: S3Object) {
-        let mut key_suffix = obj
-            .key
-            .strip_prefix(&self.prefix_to_strip)
-            .expect("all found objects will include the prefix")
-            .to_string();
-        if self.flatten {
-            key_suffix = key_suffix.replace(std::path::MAIN_SEPARATOR_STR, "-");
+    /// Work out the local path of every object before downloading any
+    ///
+    /// Objects whose keys can't be used as local paths are reported as
+    /// failed and left out, and objects that would land on the same path
+    /// are dealt with according to `--on-collision`.
+    pub(crate) fn plan(&self, objects: Vec<S3Object>) -> anyhow::Result<Vec<(S3Object, PathBuf)>> {
+        let mut planned = Vec::with_capacity(objects.len());
+        for obj in objects {
+            match self.relative_path(&obj.key) {
+                Ok(relative) => planned.push((obj, relative)),
+                Err(problem) => {
+                    let error = format!("Refusing to download {}: {problem}", obj.key);
+                    self.notify(Notification::Failed { object: obj, error });
+                }
+            }
+        }
+        let case_insensitive = collisions::is_case_insensitive(&self.base_path);
+        let renamed =
+            collisions::resolve_collisions(&mut planned, self.on_collision, case_insensitive)?;
+        report_renamed(renamed);
+        for (_, path) in &mut planned {
+            *path = self.base_path.join(&*path);
         }
-        let path = self.base_path.join(key_suffix);
+        Ok(planned)
+    }
+
+    /// Whether collisions have to be found across every match before
+    /// anything is downloaded, with [`Self::plan`]
+    ///
+    /// They're common when flattening or on a case-insensitive filesystem,
+    /// where which object keeps a contested path shouldn't depend on the
+    /// order they're listed in, and `--on-collision error` has to refuse
+    /// before downloading anything. Otherwise objects can be downloaded as
+    /// they're listed, with [`Self::place`].
+    pub(crate) fn needs_every_path(&self) -> bool {
+        self.flatten
+            || self.on_collision == OnCollision::Error
+            || collisions::is_case_insensitive(&self.base_path)
+    }
+
+    /// Work out the local path of one object, to download it right away
+    ///
+    /// The first object to claim a path keeps it, later ones get a new name
+    /// according to `--on-collision`. Objects whose keys can't be used as
+    /// local paths are reported as failed and `None` is returned.
+    pub(crate) fn place(&mut self, obj: S3Object) -> Option<(S3Object, PathBuf)> {
+        match self.relative_path(&obj.key) {
+            Ok(relative) => {
+                let (relative, renamed) = self.claims.claim(&obj.key, &relative, self.on_collision);
+                self.renamed_claims += usize::from(renamed);
+                let path = self.base_path.join(relative);
+                Some((obj, path))
+            }
+            Err(problem) => {
+                let error = format!("Refusing to download {}: {problem}", obj.key);
+                self.notify(Notification::Failed { object: obj, error });
+                None
+            }
+        }
+    }
+
+    /// Say how many objects [`Self::place`] gave a new name
+    pub(crate) fn report_placed(&self) {
+        report_renamed(self.renamed_claims);
+    }
+
+    /// The local path for `key` relative to the destination
+    fn relative_path(&self, key: &str) -> Result<PathBuf, String> {
+        let Some(key_suffix) = strip_key_prefix(key, &self.prefix_to_strip) else {
+            return Err(format!(
+                "the key doesn't start with {}",
+                self.prefix_to_strip
+            ));
+        };
+        local_suffix(key_suffix, &self.delimiter, self.flatten, self.unsafe_keys)
+    }
+
+    pub(crate) async fn download_object(self, obj: S3Object, path: PathBuf) {
+        if let Ok(existing) = std::fs::metadata(&path) {
+            let keep = match self.if_exists {
+                IfExists::Overwrite | IfExists::Rename => false,
+                IfExists::Skip => true,
+                IfExists::Newer => is_up_to_date(&existing, &obj),
+                IfExists::Error => {
+                    let error = format!("{} already exists", path.display());
+                    self.notify(Notification::Failed { object: obj, error });
+                    return;
+                }
+            };
+            if keep {
+                debug!(key = %obj.key, path = %path.display(), "keeping existing file");
+                self.notify(Notification::Skipped {
+                    object: obj,
+                    local_path: path,
+                });
+                return;
+            }
+        }
+        let temp_path = temp_path(&path, &obj);
+
+        // bytes of this object we've reported, from earlier runs or this one
+        let mut counted = 0;
+        let mut attempt = 1;
+        let notification = loop {
+            if self.interrupt.is_set() {
+                break Notification::Interrupted;
+            }
+            let offset = resume_offset(&temp_path, &obj);
+            let kept = offset as usize;
+            if kept > counted {
+                self.notify(Notification::BytesResumed(kept - counted));
+                debug!(key = %obj.key, offset, "resuming download");
+            } else if kept < counted {
+                self.notify(Notification::BytesDiscarded(counted - kept));
+            }
+            counted = kept;
+
+            match self.try_download(&obj, &path, &temp_path, offset).await {
+                Ok((local_path, sha256)) => {
+                    break Notification::ObjectDownloaded {
+                        renamed: local_path != path,
+                        object: obj,
+                        local_path,
+                        sha256,
+                    };
+                }
+                Err(failure) => {
+                    counted += failure.bytes_written;
+                    match failure.recovery {
+                        Recovery::Interrupted => break Notification::Interrupted,
+                        Recovery::Resume | Recovery::Restart if attempt < retry::MAX_ATTEMPTS => {
+                            if failure.recovery == Recovery::Restart {
+                                let _ = std::fs::remove_file(&temp_path);
+                            }
+                            let delay = retry::backoff(attempt);
+                            debug!(key = %obj.key, attempt, ?delay, error = %failure.error, "retrying download");
+                            tokio::time::sleep(delay).await;
+                            attempt += 1;
+                        }
+                        _ => {
+                            let _ = std::fs::remove_file(&temp_path);
+                            if counted > 0 {
+                                self.notify(Notification::BytesDiscarded(counted));
+                            }
+                            break Notification::Failed {
+                                object: obj,
+                                error: format!("{:#}", failure.error),
+                            };
+                        }
+                    }
+                }
+            }
+        };
+        self.notify(notification);
+    }
+
+    /// Make a single attempt at downloading `obj` to `path`, via `temp_path`
+    ///
+    /// The first `offset` bytes are already in `temp_path`. Returns where the
+    /// object ended up, which is only somewhere other than `path` when
+    /// renaming around existing files, and its SHA256 if asked for.
+    async fn try_download(
+        &self,
+        obj: &S3Object,
+        path: &Path,
+        temp_path: &Path,
+        offset: u64,
+    ) -> Result<(PathBuf, Option<String>), AttemptFailure> {
         let dir = path.parent().unwrap();
-        if let Err(e) = std::fs::create_dir_all(dir) {
-            warn!("Failed to create directory {}: {}", dir.display(), e);
-            return;
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))
+            .map_err(AttemptFailure::permanent)?;
+        let fetched = if self.ranged.applies_to(obj.size as u64 - offset) {
+            self.fetch_ranged(obj, temp_path, offset).await?
+        } else {
+            self.fetch_whole(obj, temp_path, offset).await?
+        };
+        let bytes_written = fetched.bytes_written;
+        let digests = match fetched.digests {
+            Some(digests) => digests,
+            None => self
+                .digest_file(obj, temp_path, &fetched.metadata)
+                .await
+                .map_err(|e| e.after(bytes_written))?,
         };
-        let result = self
-            .client
-            .get_object()
-            .bucket(self.bucket)
-            .key(&obj.key)
+        // a mismatch means the bytes got mangled somewhere, start over and
+        // never move them into place
+        let sha256 = digests.finish(&obj.key).map_err(|error| AttemptFailure {
+            error,
+            recovery: Recovery::Restart,
+            bytes_written,
+        })?;
+        if self.preserve {
+            // before the rename, so the file never shows up with the wrong times
+            preserve_metadata(temp_path, obj, &fetched.metadata);
+        }
+        let path = move_into_place(temp_path, path, self.if_exists == IfExists::Rename)
+            .map_err(|e| AttemptFailure::permanent(e).after(bytes_written))?;
+        Ok((path, sha256))
+    }
+
+    /// Hash a download that was assembled from several requests
+    ///
+    /// Ranged GETs don't come with full-object checksums, so those come from
+    /// a HEAD. An additional checksum is always checked when there is one, the
+    /// ETag only with `--verify`.
+    async fn digest_file(
+        &self,
+        obj: &S3Object,
+        temp_path: &Path,
+        metadata: &ResponseMetadata,
+    ) -> Result<Digests, AttemptFailure> {
+        let mut expected = None;
+        if obj.checksum_algorithms.is_some() {
+            expected = self.full_object_checksum(obj).await?;
+        }
+        if expected.is_none() && self.verify {
+            expected = Expected::from_etag(obj.etag.as_deref(), metadata.encryption);
+        }
+        let mut digests = Digests::new(expected, self.compute_sha256);
+        if digests.is_empty() {
+            return Ok(digests);
+        }
+        let path = temp_path.to_owned();
+        tokio::task::spawn_blocking(move || digests.update_from_file(&path).map(|()| digests))
+            .await
+            .expect("checksum task doesn't panic")
+            .with_context(|| format!("Failed to read back {}", temp_path.display()))
+            .map_err(AttemptFailure::permanent)
+    }
+
+    /// A GET for `obj`, pinned to its listed ETag when fetching a range
+    ///
+    /// Pinning means an object that is overwritten between two requests fails
+    /// instead of being stitched together from two versions. Whole-object GETs
+    /// ask for the object's checksum when verifying.
+    fn get_range(&self, obj: &S3Object, range: Option<String>) -> GetObjectFluentBuilder {
+        let mut request = self.client.get_object().bucket(&self.bucket).key(&obj.key);
+        if let Some(range) = range {
+            request = request.range(range);
+            if let Some(etag) = &obj.etag {
+                request = request.if_match(format!("\"{etag}\""));
+            }
+        } else if self.verify {
+            request = request.checksum_mode(ChecksumMode::Enabled);
+        }
+        request
+    }
+
+    /// Stream the object from `offset` onwards into `temp_path` with a single GET
+    ///
+    /// When that's the whole object it's hashed on the way through.
+    async fn fetch_whole(
+        &self,
+        obj: &S3Object,
+        temp_path: &Path,
+        offset: u64,
+    ) -> Result<Fetched, AttemptFailure> {
+        let range = (offset > 0).then(|| format!("bytes={offset}-"));
+        self.request_slot(Api::GetObject, &obj.key).await;
+        let started = Instant::now();
+        let mut response = self
+            .get_range(obj, range)
             .send()
-            .await;
-        let Ok(mut response) = result else {
-            warn!("Failed to download object {}", obj.key);
-            return;
+            .await
+            .map_err(|e| AttemptFailure::from_sdk(e, &obj.key))?;
+        self.link.responded_after(started.elapsed());
+        let metadata = ResponseMetadata::from_response(&response);
+        let mut digests = (offset == 0).then(|| {
+            let expected = if self.verify {
+                Expected::from_get(&response)
+                    .or_else(|| Expected::from_etag(obj.etag.as_deref(), metadata.encryption))
+            } else {
+                None
+            };
+            Digests::new(expected, self.compute_sha256)
+        });
+        let file = if offset > 0 {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(temp_path)
+                .await
+        } else {
+            tokio::fs::File::create(temp_path).await
         };
-        let temp_path = path.with_extension(format!(".s3glob-tmp-{}", self.obj_id));
-        let mut file = match tokio::fs::File::create(&temp_path).await {
-            Ok(file) => file,
-            Err(e) => {
-                warn!("Failed to create file {}: {}", temp_path.display(), e);
-                return;
+        let mut file = file
+            .with_context(|| format!("Failed to create file {}", temp_path.display()))
+            .map_err(AttemptFailure::permanent)?;
+        let mut bytes_written = 0;
+        let result = loop {
+            let next = tokio::select! {
+                next = response.body.try_next() => next,
+                () = self.interrupt.wait() => break Err(AttemptFailure::interrupted()),
+            };
+            match next {
+                Ok(Some(bytes)) => {
+                    if let Err(e) = file.write_all(&bytes).await {
+                        break Err(AttemptFailure::permanent(anyhow::Error::from(e).context(
+                            format!("Failed to write to file {}", temp_path.display()),
+                        )));
+                    }
+                    if let Some(digests) = &mut digests {
+                        digests.update(&bytes);
+                    }
+                    bytes_written += bytes.len();
+                    self.received(bytes.len()).await;
+                }
+                Ok(None) => break Ok(()),
+                // the connection dropped partway through, try again
+                Err(e) => break Err(AttemptFailure::from_body(e, &obj.key)),
             }
         };
-        let mut res = response.body.try_next().await;
+        // flush even on failure, what we've written is a prefix we can resume from
+        let flushed = file
+            .flush()
+            .await
+            .with_context(|| format!("Failed to flush file {}", temp_path.display()))
+            .map_err(AttemptFailure::permanent);
+        result.and(flushed).map_err(|e| e.after(bytes_written))?;
+        Ok(Fetched {
+            bytes_written,
+            metadata,
+            digests,
+        })
+    }
+
+    /// Fetch the object from `offset` as concurrent byte ranges written into `temp_path`
+    async fn fetch_ranged(
+        &self,
+        obj: &S3Object,
+        temp_path: &Path,
+        offset: u64,
+    ) -> Result<Fetched, AttemptFailure> {
+        let size = obj.size as u64;
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .open(temp_path)
+            .and_then(|file| file.set_len(size).map(|()| file))
+            .with_context(|| format!("Failed to create file {}", temp_path.display()))
+            .map_err(AttemptFailure::permanent)?;
+        let parts = part_ranges(offset..size, self.ranged.part_size).collect::<Vec<_>>();
+        let progress = parts.iter().map(|_| AtomicU64::new(0)).collect::<Vec<_>>();
+        let metadata = OnceLock::new();
+        let fetch = futures::stream::iter(0..parts.len())
+            .map(|i| {
+                let range = parts[i].clone();
+                self.fetch_part(obj, &file, temp_path, range, &progress[i], &metadata)
+            })
+            .buffer_unordered(self.ranged.concurrency)
+            .try_collect::<()>();
+        let fetched = tokio::select! {
+            fetched = fetch => fetched,
+            () = self.interrupt.wait() => Err(AttemptFailure::interrupted()),
+        };
+        let bytes_written = progress
+            .iter()
+            .map(|done| done.load(Ordering::Relaxed) as usize)
+            .sum();
+        if let Err(failure) = fetched {
+            // only the parts from the start that finished are worth keeping,
+            // cut the file down to those so it can be resumed
+            let keep = offset + contiguous_prefix(&parts, &progress);
+            if let Err(e) = file.set_len(keep) {
+                debug!(key = %obj.key, error = %e, "failed to truncate partial download");
+                let _ = std::fs::remove_file(temp_path);
+            }
+            return Err(failure.after(bytes_written));
+        }
+        Ok(Fetched {
+            bytes_written,
+            metadata: metadata.into_inner().unwrap_or_default(),
+            digests: None,
+        })
+    }
+
+    /// Fetch one byte range of `obj` and write it at the same offset in `file`
+    ///
+    /// `done` counts the bytes of the range that have been written, and
+    /// `metadata` is filled in by whichever part gets a response first.
+    async fn fetch_part(
+        &self,
+        obj: &S3Object,
+        file: &std::fs::File,
+        temp_path: &Path,
+        range: Range<u64>,
+        done: &AtomicU64,
+        metadata: &OnceLock<ResponseMetadata>,
+    ) -> Result<(), AttemptFailure> {
+        self.request_slot(Api::GetObject, &obj.key).await;
+        let started = Instant::now();
+        let mut response = self
+            .get_range(
+                obj,
+                Some(format!("bytes={}-{}", range.start, range.end - 1)),
+            )
+            .send()
+            .await
+            .map_err(|e| AttemptFailure::from_sdk(e, &obj.key))?;
+        self.link.responded_after(started.elapsed());
+        metadata.get_or_init(|| ResponseMetadata::from_response(&response));
+        let mut offset = range.start;
         loop {
-            match res {
+            match response.body.try_next().await {
                 Ok(Some(bytes)) => {
-                    if let Err(e) = file.write_all(&bytes).await {
-                        warn!("Failed to write to file {}: {}", path.display(), e);
-                        return;
-                    };
-                    self.notifier
-                        .send(Notification::BytesDownloaded(bytes.len()))
-                        .expect("can send on channel");
+                    // written in place rather than on the blocking pool so that
+                    // no write can land after an interrupted download has been
+                    // truncated to what it can resume from
+                    write_all_at(file, &bytes, offset)
+                        .with_context(|| format!("Failed to write to file {}", temp_path.display()))
+                        .map_err(AttemptFailure::permanent)?;
+                    offset += bytes.len() as u64;
+                    done.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    self.received(bytes.len()).await;
                 }
                 Ok(None) => break,
-                Err(e) => {
-                    warn!("Failed to download object {}: {}", obj.key, e);
-                    return;
-                }
+                Err(e) => return Err(AttemptFailure::from_body(e, &obj.key)),
+            }
+        }
+        if offset != range.end {
+            return Err(AttemptFailure {
+                error: anyhow::anyhow!(
+                    "Short read for {} bytes {}-{}: got {} bytes",
+                    obj.key,
+                    range.start,
+                    range.end - 1,
+                    offset - range.start,
+                ),
+                recovery: Recovery::Resume,
+                bytes_written: 0,
+            });
+        }
+        Ok(())
+    }
+
+    /// The full-object checksum S3 has stored for `obj`, if any
+    async fn full_object_checksum(
+        &self,
+        obj: &S3Object,
+    ) -> Result<Option<Expected>, AttemptFailure> {
+        let head = retry::retry(&format!("HEAD {}", obj.key), || async {
+            self.request_slot(Api::HeadObject, &obj.key).await;
+            self.client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(&obj.key)
+                .checksum_mode(ChecksumMode::Enabled)
+                .send()
+                .await
+        })
+        .await
+        .map_err(|e| AttemptFailure::from_sdk(e, &obj.key))?;
+        Ok(Expected::from_head(&head))
+    }
+}
+
+/// What one attempt at fetching an object got us
+struct Fetched {
+    /// Bytes this attempt reported as downloaded
+    bytes_written: usize,
+    metadata: ResponseMetadata,
+    /// Present if the contents were hashed while streaming them
+    digests: Option<Digests>,
+}
+
+/// What a GET told us about an object beyond its contents
+#[derive(Debug, Default)]
+struct ResponseMetadata {
+    content_type: Option<String>,
+    version_id: Option<String>,
+    user: HashMap<String, String>,
+    encryption: EtagEncryption,
+}
+
+impl ResponseMetadata {
+    fn from_response(response: &GetObjectOutput) -> Self {
+        Self {
+            content_type: response.content_type().map(str::to_owned),
+            version_id: response.version_id().map(str::to_owned),
+            user: response.metadata().cloned().unwrap_or_default(),
+            encryption: EtagEncryption::from_get(response),
+        }
+    }
+}
+
+/// Copy what we know about `obj` onto the downloaded file at `path`
+///
+/// The modification time becomes the object's last modified time. On Linux
+/// the ETag, content type, version id and user metadata are also stored as
+/// `user.s3glob.*` extended attributes. Failing to do any of this doesn't
+/// fail the download, the contents are still right.
+fn preserve_metadata(path: &Path, obj: &S3Object, metadata: &ResponseMetadata) {
+    let mtime = SystemTime::try_from(obj.last_modified)
+        .map_err(anyhow::Error::from)
+        .and_then(|mtime| {
+            let file = std::fs::File::options().write(true).open(path)?;
+            file.set_modified(mtime)?;
+            Ok(())
+        });
+    if let Err(e) = mtime {
+        warn!(key = %obj.key, error = %e, "failed to set modification time");
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let mut attrs = vec![
+            ("etag".to_owned(), obj.etag.as_deref()),
+            ("content_type".to_owned(), metadata.content_type.as_deref()),
+            ("version_id".to_owned(), metadata.version_id.as_deref()),
+        ];
+        attrs.extend(
+            metadata
+                .user
+                .iter()
+                .map(|(name, value)| (format!("meta.{name}"), Some(value.as_str()))),
+        );
+        for (name, value) in attrs {
+            let Some(value) = value else { continue };
+            if let Err(e) = xattr::set(path, format!("user.s3glob.{name}"), value.as_bytes()) {
+                // usually the filesystem doesn't support them, which won't change
+                static WARNED: std::sync::Once = std::sync::Once::new();
+                WARNED.call_once(|| {
+                    warn!(path = %path.display(), error = %e, "failed to set extended attributes");
+                });
+                break;
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = metadata;
+}
+
+/// True if a local file looks like it already has the contents of `obj`
+///
+/// It has to be the same size and no older than the object.
+fn is_up_to_date(local: &std::fs::Metadata, obj: &S3Object) -> bool {
+    let Ok(local_modified) = local.modified() else {
+        return false;
+    };
+    let Ok(remote_modified) = SystemTime::try_from(obj.last_modified) else {
+        return false;
+    };
+    local.len() == obj.size as u64 && local_modified >= remote_modified
+}
+
+/// `path`, or the first of `name-1.ext`, `name-2.ext`, ... that is free
+///
+/// The name is claimed by creating an empty file there, so two downloads
+/// can't pick the same one.
+fn reserve_unique_path(path: &Path) -> std::io::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|ext| ext.to_string_lossy());
+    for n in 0_u32.. {
+        let candidate = match (n, &extension) {
+            (0, _) => path.to_owned(),
+            (n, Some(ext)) => path.with_file_name(format!("{stem}-{n}.{ext}")),
+            (n, None) => path.with_file_name(format!("{stem}-{n}")),
+        };
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("ran out of numbered names for {}", path.display())
+}
+
+/// Move a finished download from `temp_path` to `path`, or to the first free
+/// name next to it if `rename` is set, and return where it ended up
+///
+/// A name reserved for the download is removed again if the move fails, so
+/// that a later run doesn't find an empty file in its place.
+fn move_into_place(temp_path: &Path, path: &Path, rename: bool) -> anyhow::Result<PathBuf> {
+    let (path, reserved) = if rename {
+        let reserved = reserve_unique_path(path)
+            .with_context(|| format!("Failed to pick a new name for {}", path.display()))?;
+        (reserved, true)
+    } else {
+        (path.to_owned(), false)
+    };
+    std::fs::rename(temp_path, &path)
+        .with_context(|| {
+            format!(
+                "Failed to rename file {} -> {}",
+                temp_path.display(),
+                path.display()
+            )
+        })
+        .inspect_err(|_| {
+            if reserved {
+                let _ = std::fs::remove_file(&path);
+            }
+        })?;
+    Ok(path)
+}
+
+/// Where `obj` is downloaded to before being moved to `path`
+///
+/// The name depends only on the key and ETag, so a later run finds the
+/// partial file an interrupted one left behind, and never mistakes one for
+/// a different version of the object.
+fn temp_path(path: &Path, obj: &S3Object) -> PathBuf {
+    let mut hasher = ChecksumAlgorithm::Sha256.into_impl();
+    hasher.update(obj.key.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(obj.etag.as_deref().unwrap_or_default().as_bytes());
+    let id = hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".s3glob-{id}.part"));
+    path.with_file_name(name)
+}
+
+/// How much of a leftover temp file for `obj` can be kept
+///
+/// Downloads only ever leave a valid prefix of the object behind, except
+/// for ranged downloads that were killed outright: those are already the
+/// full size, and are thrown away along with anything we can't pin to an
+/// ETag.
+fn resume_offset(temp_path: &Path, obj: &S3Object) -> u64 {
+    let len = match std::fs::metadata(temp_path) {
+        Ok(meta) => meta.len(),
+        Err(_) => return 0,
+    };
+    if obj.etag.is_some() && len < obj.size as u64 {
+        return len;
+    }
+    let _ = std::fs::remove_file(temp_path);
+    0
+}
+
+/// Split `range` into consecutive ranges of at most `part_size` bytes
+fn part_ranges(range: Range<u64>, part_size: u64) -> impl Iterator<Item = Range<u64>> {
+    let end = range.end;
+    range
+        .step_by(part_size as usize)
+        .map(move |start| start..(start + part_size).min(end))
+}
+
+/// How many bytes from the start of `parts` have been written without gaps
+fn contiguous_prefix(parts: &[Range<u64>], progress: &[AtomicU64]) -> u64 {
+    let mut total = 0;
+    for (range, done) in parts.iter().zip(progress) {
+        let done = done.load(Ordering::Relaxed);
+        total += done;
+        if done < range.end - range.start {
+            break;
+        }
+    }
+    total
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &std::fs::File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt as _;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
             }
-            res = response.body.try_next().await;
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// What to do after a download attempt fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+    /// Trying again won't help
+    GiveUp,
+    /// Try again from scratch
+    Restart,
+    /// Try again, continuing from what's in the temp file
+    Resume,
+    /// The user stopped us, leave the temp file for the next run
+    Interrupted,
+}
+
+/// Why a single download attempt failed
+struct AttemptFailure {
+    error: anyhow::Error,
+    recovery: Recovery,
+    /// Bytes this attempt reported as downloaded
+    bytes_written: usize,
+}
+
+impl AttemptFailure {
+    fn permanent(error: anyhow::Error) -> Self {
+        Self {
+            error,
+            recovery: Recovery::GiveUp,
+            bytes_written: 0,
+        }
+    }
+
+    fn interrupted() -> Self {
+        Self {
+            error: anyhow::anyhow!("Interrupted"),
+            recovery: Recovery::Interrupted,
+            bytes_written: 0,
+        }
+    }
+
+    /// A failed request, which is worth retrying if S3 says so
+    fn from_sdk<E>(
+        error: aws_sdk_s3::error::SdkError<E, aws_sdk_s3::config::http::HttpResponse>,
+        key: &str,
+    ) -> Self
+    where
+        E: aws_sdk_s3::error::ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    {
+        Self {
+            recovery: if retry::is_transient(&error) {
+                Recovery::Resume
+            } else {
+                Recovery::GiveUp
+            },
+            error: anyhow::Error::from(error).context(format!("Failed to download {key}")),
+            bytes_written: 0,
+        }
+    }
+
+    /// The response body broke off, which is always worth retrying
+    fn from_body(error: impl Into<anyhow::Error>, key: &str) -> Self {
+        Self {
+            error: error.into().context(format!("Failed to download {key}")),
+            recovery: Recovery::Resume,
+            bytes_written: 0,
+        }
+    }
+
+    fn after(mut self, bytes_written: usize) -> Self {
+        self.bytes_written = bytes_written;
+        self
+    }
+}
+
+fn report_renamed(renamed: usize) {
+    if renamed > 0 {
+        progressln!("Renamed {renamed} objects whose local paths would have collided");
+    }
+}
+
+/// `key` without `prefix`, which it may start with in a different case
+///
+/// The prefix comes from the pattern, and with `--ignore-case` the keys it
+/// matched don't have to be spelled the same way.
+fn strip_key_prefix<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    if let Some(rest) = key.strip_prefix(prefix) {
+        return Some(rest);
+    }
+    let mut key_chars = key.chars();
+    for p in prefix.chars() {
+        let k = key_chars.next()?;
+        if !(k == p || k.to_lowercase().eq(p.to_lowercase())) {
+            return None;
         }
-        if let Err(e) = file.flush().await {
-            warn!("Failed to flush file {}: {}", temp_path.display(), e);
-            drop(file);
-            return;
+    }
+    Some(key_chars.as_str())
+}
+
+/// The relative local path for a key with its prefix already stripped
+///
+/// Each delimiter becomes a directory separator, or a `-` when flattening.
+///
+/// Keys come from buckets we don't necessarily trust, so every part of the
+/// path has to be a plain file or directory name that stays inside the
+/// destination. Parts that are empty (doubled, leading or trailing
+/// delimiters), `.` or `..`, contain a path separator or NUL are either an
+/// error describing the problem or percent-encoded, depending on `unsafe_keys`.
+fn local_suffix(
+    key_suffix: &str,
+    delimiter: &str,
+    flatten: bool,
+    unsafe_keys: UnsafeKeys,
+) -> Result<PathBuf, String> {
+    if key_suffix.is_empty() {
+        // nothing is left to name the file, whatever we're told to do
+        return Err("no name left after removing the prefix".to_owned());
+    }
+    let mut parts: Vec<String> = Vec::new();
+    // an empty part stands for a delimiter that was doubled up, when encoding
+    // it sticks to the next part (or the last one, at the end)
+    let mut pending = String::new();
+    for part in key_suffix.split(delimiter) {
+        let part = if flatten {
+            part.replace(std::path::is_separator, "-")
+        } else {
+            part.to_owned()
         };
-        drop(file);
-        if let Err(e) = std::fs::rename(&temp_path, &path) {
-            warn!(
-                "Failed to rename file {} -> {}: {}",
-                &temp_path.display(),
-                path.display(),
-                e
-            );
-            return;
+        let problem = if part.is_empty() {
+            Some("an empty path segment")
+        } else if part == "." || part == ".." {
+            Some("a relative path segment")
+        } else if part.contains('\0') {
+            Some("a NUL byte")
+        } else if !is_plain_name(&part) {
+            Some("a path separator in a segment")
+        } else {
+            None
         };
-        self.notifier
-            .send(Notification::ObjectDownloaded {
-                object: obj,
-                local_path: path,
-            })
-            .expect("send on our channel should succeed");
+        match (problem, unsafe_keys) {
+            (None, _) => parts.push(std::mem::take(&mut pending) + &part),
+            (Some(problem), UnsafeKeys::Reject) => {
+                return Err(format!(
+                    "the key has {problem} (pass --unsafe-keys encode to download it anyway)"
+                ));
+            }
+            (Some(_), UnsafeKeys::Encode) if part.is_empty() => {
+                pending.push_str(&percent_encode(delimiter, |_| true));
+            }
+            (Some(_), UnsafeKeys::Encode) => {
+                let encoded = if part == "." || part == ".." {
+                    percent_encode(&part, |_| true)
+                } else {
+                    percent_encode(&part, is_unsafe_char)
+                };
+                parts.push(std::mem::take(&mut pending) + &encoded);
+            }
+        }
+    }
+    if !pending.is_empty() {
+        match parts.last_mut() {
+            Some(last) => last.push_str(&pending),
+            // a key that's nothing but delimiters
+            None => parts.push(percent_encode(key_suffix, |_| true)),
+        }
     }
+    debug_assert!(parts.iter().all(|part| is_plain_name(part)));
+    if flatten {
+        Ok(PathBuf::from(parts.join("-")))
+    } else {
+        Ok(parts.iter().collect())
+    }
+}
+
+/// True if `name` is a single normal path component on this platform
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(n)), None) if n == name
+    )
+}
+
+/// Characters that can't appear in a file name as-is
+fn is_unsafe_char(c: char) -> bool {
+    c == '\0' || std::path::is_separator(c) || (cfg!(windows) && c == ':')
+}
+
+/// Replace the characters of `s` that `encode` picks with `%XX` escapes
+fn percent_encode(s: &str, encode: impl Fn(char) -> bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if encode(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{b:02X}"));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
 }
 
 pub(crate) fn extract_prefix_to_strip(
     raw_pattern: &str,
     path_mode: PathMode,
+    delimiter: &str,
     keys: &[S3Object],
 ) -> String {
     match path_mode {
@@ -235,9 +1222,9 @@ pub(crate) fn extract_prefix_to_strip(
                 .chars()
                 .take_while(|c| !GLOB_CHARS.contains(c))
                 .collect();
-            // find the last slash in the prefix and only include that
-            match up_to_glob.rfind('/') {
-                Some(slash_idx) => up_to_glob[..slash_idx + 1].to_string(),
+            // find the last delimiter in the prefix and only include that
+            match up_to_glob.rfind(delimiter) {
+                Some(delim_idx) => up_to_glob[..delim_idx + delimiter.len()].to_string(),
                 None => up_to_glob,
             }
         }
@@ -254,9 +1241,9 @@ pub(crate) fn extract_prefix_to_strip(
                     .map(|(a, _)| a)
                     .collect();
             }
-            // get the prefix up to and including the last slash
-            let suffix = prefix.chars().rev().take_while(|c| *c != '/').count();
-            prefix.truncate(prefix.len() - suffix);
+            // get the prefix up to and including the last delimiter
+            let keep = prefix.rfind(delimiter).map_or(0, |i| i + delimiter.len());
+            prefix.truncate(keep);
             prefix
         }
     }
@@ -270,7 +1257,7 @@ mod tests {
 
     macro_rules! assert_extract_prefix_to_strip {
         ($pattern:expr, $path_mode:expr, $expected:expr) => {
-            let actual = extract_prefix_to_strip($pattern, $path_mode, &[]);
+            let actual = extract_prefix_to_strip($pattern, $path_mode, "/", &[]);
             assert2::check!(
                 actual == $expected,
                 "input: {} path_mode: {:?}",
@@ -280,7 +1267,7 @@ mod tests {
         };
         ($pattern:expr, $path_mode:expr, $expected:expr, $keys:expr) => {
             let keys: &[S3Object] = $keys;
-            let actual = extract_prefix_to_strip($pattern, $path_mode, keys);
+            let actual = extract_prefix_to_strip($pattern, $path_mode, "/", keys);
             assert2::check!(
                 actual == $expected,
                 "input: {} path_mode: {:?} keys: {:?}",
@@ -329,21 +1316,241 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_extract_prefix_to_strip_multi_char_delimiter() {
+        let actual = extract_prefix_to_strip("a::b::c*::d", PathMode::FromFirstGlob, "::", &[]);
+        assert2::check!(actual == "a::b::");
+        // a lone `:` is not a delimiter
+        let actual = extract_prefix_to_strip("a::b:c*", PathMode::FromFirstGlob, "::", &[]);
+        assert2::check!(actual == "a::");
+    }
+
+    fn suffix(key: &str, delimiter: &str, flatten: bool) -> Result<String, String> {
+        local_suffix(key, delimiter, flatten, UnsafeKeys::Reject)
+            .map(|path| path.display().to_string())
+    }
+
+    fn encoded(key: &str, delimiter: &str, flatten: bool) -> String {
+        local_suffix(key, delimiter, flatten, UnsafeKeys::Encode)
+            .unwrap()
+            .display()
+            .to_string()
+    }
+
+    #[test]
+    fn test_strip_key_prefix() {
+        assert2::check!(strip_key_prefix("Invoice/a.csv", "Invoice/") == Some("a.csv"));
+        assert2::check!(strip_key_prefix("INVOICE/a.csv", "Invoice/") == Some("a.csv"));
+        assert2::check!(strip_key_prefix("ÉTÉ/a.csv", "été/") == Some("a.csv"));
+        assert2::check!(strip_key_prefix("Inv/a.csv", "Invoice/").is_none());
+        assert2::check!(strip_key_prefix("other/a.csv", "Invoice/").is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_local_suffix() {
+        assert2::check!(suffix("a/b/c.txt", "/", false).as_deref() == Ok("a/b/c.txt"));
+        assert2::check!(suffix("a/b/c.txt", "/", true).as_deref() == Ok("a-b-c.txt"));
+        assert2::check!(suffix("a::b::c.txt", "::", false).as_deref() == Ok("a/b/c.txt"));
+        assert2::check!(suffix("a::b:c.txt", "::", true).as_deref() == Ok("a-b:c.txt"));
+        // dots that aren't a whole segment are just part of the name
+        assert2::check!(suffix("a/..b/c..", "/", false).as_deref() == Ok("a/..b/c.."));
+    }
+
+    #[rstest::rstest]
+    #[case::parent_dir("a/../../../home/user/.bashrc", "relative")]
+    #[case::current_dir("a/./b", "relative")]
+    #[case::only_parent("..", "relative")]
+    #[case::absolute("/etc/passwd", "empty")]
+    #[case::doubled_delimiter("a//b", "empty")]
+    #[case::trailing_delimiter("a/b/", "empty")]
+    #[case::nul("a/b\0c", "NUL")]
+    #[case::nothing_left("", "no name")]
+    fn test_local_suffix_rejects(#[case] key: &str, #[case] problem: &str) {
+        let err = suffix(key, "/", false).unwrap_err();
+        assert2::check!(err.contains(problem), "key: {key:?}");
+        let err = suffix(key, "/", true).unwrap_err();
+        assert2::check!(err.contains(problem), "flattened key: {key:?}");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_local_suffix_rejects_separators_with_other_delimiters() {
+        let err = suffix("a::/etc/passwd", "::", false).unwrap_err();
+        assert2::check!(err.contains("path separator"));
+        // flattening turns them into dashes like any other separator
+        assert2::check!(suffix("a::/etc/passwd", "::", true).as_deref() == Ok("a--etc-passwd"));
+    }
+
+    #[rstest::rstest]
+    #[case::parent_dir(
+        "a/../../../home/user/.bashrc",
+        "a/%2E%2E/%2E%2E/%2E%2E/home/user/.bashrc"
+    )]
+    #[case::current_dir("a/./b", "a/%2E/b")]
+    #[case::absolute("/etc/passwd", "%2Fetc/passwd")]
+    #[case::doubled_delimiter("a//b", "a/%2Fb")]
+    #[case::trailing_delimiter("a/b/", "a/b%2F")]
+    #[case::nul("a/b\0c", "a/b%00c")]
+    #[case::only_delimiter("/", "%2F")]
+    #[case::only_delimiters("//", "%2F%2F")]
+    #[cfg(unix)]
+    fn test_local_suffix_encodes(#[case] key: &str, #[case] expected: &str) {
+        assert2::check!(encoded(key, "/", false) == expected);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_local_suffix_encodes_other_delimiters() {
+        assert2::check!(encoded("a::/etc/passwd", "::", false) == "a/%2Fetc%2Fpasswd");
+        assert2::check!(encoded("a::::b", "::", false) == "a/%3A%3Ab");
+        assert2::check!(encoded("a/../b", "/", true) == "a-%2E%2E-b");
+    }
+
+    #[test]
+    fn test_part_ranges() {
+        let ranges = part_ranges(0..25, 10).collect::<Vec<_>>();
+        assert2::check!(ranges == [0..10, 10..20, 20..25]);
+        let ranges = part_ranges(0..20, 10).collect::<Vec<_>>();
+        assert2::check!(ranges == [0..10, 10..20]);
+    }
+
+    fn object(key: &str, size: i64, etag: Option<&str>) -> S3Object {
+        S3Object {
+            key: key.to_string(),
+            size,
+            last_modified: DateTime::from_millis(0),
+            etag: etag.map(str::to_owned),
+            storage_class: None,
+            checksum_algorithms: None,
+            restore_status: None,
+        }
+    }
+
+    #[test]
+    fn test_temp_path_is_stable_per_key_and_etag() {
+        let path = Path::new("out/a/b.txt");
+        let v1 = temp_path(path, &object("a/b.txt", 10, Some("abc")));
+        assert2::check!(v1 == temp_path(path, &object("a/b.txt", 10, Some("abc"))));
+        assert2::check!(v1.parent() == Some(Path::new("out/a")));
+        let name = v1.file_name().unwrap().to_str().unwrap();
+        assert2::check!(name.starts_with("b.txt.s3glob-"));
+        assert2::check!(name.ends_with(".part"));
+        assert2::check!(v1 != temp_path(path, &object("a/b.txt", 10, Some("def"))));
+        assert2::check!(v1 != temp_path(path, &object("a/c.txt", 10, Some("abc"))));
+    }
+
+    #[test]
+    fn test_resume_offset() -> anyhow::Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let temp = dir.path().join("partial");
+        assert2::check!(resume_offset(&temp, &object("k", 10, Some("e"))) == 0);
+
+        std::fs::write(&temp, "12345")?;
+        assert2::check!(resume_offset(&temp, &object("k", 10, Some("e"))) == 5);
+
+        // without an etag we can't make sure the rest is the same object
+        assert2::check!(resume_offset(&temp, &object("k", 10, None)) == 0);
+        assert2::check!(!temp.exists());
+
+        // a full-size file might be a killed ranged download with holes
+        std::fs::write(&temp, "1234567890")?;
+        assert2::check!(resume_offset(&temp, &object("k", 10, Some("e"))) == 0);
+        assert2::check!(!temp.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reserve_unique_path() -> anyhow::Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("report.csv");
+        assert2::check!(reserve_unique_path(&path)? == path);
+        assert2::check!(reserve_unique_path(&path)? == dir.path().join("report-1.csv"));
+        assert2::check!(reserve_unique_path(&path)? == dir.path().join("report-2.csv"));
+        let bare = dir.path().join("README");
+        std::fs::write(&bare, "")?;
+        assert2::check!(reserve_unique_path(&bare)? == dir.path().join("README-1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_into_place_removes_reserved_name_on_failure() -> anyhow::Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("report.csv");
+        std::fs::write(&path, "existing")?;
+        let temp = dir.path().join("report.csv.part");
+        std::fs::write(&temp, "downloaded")?;
+        assert2::check!(move_into_place(&temp, &path, true)? == dir.path().join("report-1.csv"));
+
+        // the download's temp file is gone, so moving it fails
+        assert2::check!(move_into_place(&temp, &path, true).is_err());
+        assert2::check!(!dir.path().join("report-2.csv").exists());
+        assert2::check!(std::fs::read_to_string(&path)? == "existing");
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_up_to_date() -> anyhow::Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("f");
+        std::fs::write(&path, "12345")?;
+        let meta = std::fs::metadata(&path)?;
+        let old = object("f", 5, None);
+        assert2::check!(is_up_to_date(&meta, &old));
+        // a different size always means a download
+        assert2::check!(!is_up_to_date(&meta, &object("f", 6, None)));
+        let mut newer = object("f", 5, None);
+        newer.last_modified =
+            DateTime::from(SystemTime::now() + std::time::Duration::from_secs(60));
+        assert2::check!(!is_up_to_date(&meta, &newer));
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_metadata_sets_mtime() -> anyhow::Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("f");
+        std::fs::write(&path, "12345")?;
+        let mut obj = object("f", 5, Some("abc"));
+        obj.last_modified = DateTime::from_secs(1_700_000_000);
+        preserve_metadata(&path, &obj, &ResponseMetadata::default());
+        let mtime = std::fs::metadata(&path)?.modified()?;
+        assert2::check!(mtime == SystemTime::try_from(obj.last_modified)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_contiguous_prefix() {
+        let parts = [0..10, 10..20, 20..25];
+        let progress = |done: [u64; 3]| done.map(AtomicU64::new);
+        assert2::check!(contiguous_prefix(&parts, &progress([10, 10, 5])) == 25);
+        assert2::check!(contiguous_prefix(&parts, &progress([10, 4, 5])) == 14);
+        assert2::check!(contiguous_prefix(&parts, &progress([0, 10, 5])) == 0);
+    }
+
+    #[test]
+    fn test_ranged_gets_applies_to() {
+        let ranged = RangedGets {
+            threshold: 100,
+            part_size: 10,
+            concurrency: 2,
+        };
+        assert2::check!(!ranged.applies_to(99));
+        assert2::check!(ranged.applies_to(100));
+        // a single part is just a slower whole-object GET
+        let ranged = RangedGets {
+            threshold: 10,
+            part_size: 100,
+            concurrency: 2,
+        };
+        assert2::check!(!ranged.applies_to(100));
+    }
+
     #[test]
     fn test_extract_prefix_to_strip_shortest() {
         // Helper function to create S3Objects for testing
         fn make_objects(keys: &[&str]) -> Vec<S3Object> {
-            keys.iter()
-                .map(|&key| S3Object {
-                    key: key.to_string(),
-                    size: 0,
-                    last_modified: DateTime::from_millis(0),
-                    etag: None,
-                    storage_class: None,
-                    checksum_algorithms: None,
-                    restore_status: None,
-                })
-                .collect()
+            keys.iter().map(|&key| object(key, 0, None)).collect()
         }
 
         // Different prefixes entirely - no common prefix
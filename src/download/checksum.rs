@@ -0,0 +1,308 @@
+//! Checking downloaded bytes against what S3 says they should be
+//!
+//! S3 can give us two kinds of checksum: an additional checksum (CRC32,
+//! SHA256, ...) if the object was uploaded with one, and the ETag, which is
+//! the MD5 of the contents for objects uploaded in one piece without KMS or
+//! customer-provided keys.
+
+use std::io::Read as _;
+use std::path::Path;
+
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::operation::head_object::HeadObjectOutput;
+use aws_sdk_s3::types::{ChecksumType, ServerSideEncryption};
+use aws_smithy_checksums::ChecksumAlgorithm;
+use aws_smithy_checksums::http::HttpChecksum;
+use md5::Digest as _;
+
+/// A checksum that S3 says an object's contents should have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Expected {
+    /// An additional checksum, base64 encoded the way S3 reports it
+    Checksum(ChecksumAlgorithm, String),
+    /// A single-part ETag, which is the hex MD5 of the contents
+    Md5(String),
+}
+
+impl Expected {
+    /// The full-object additional checksum in a HEAD response, if any
+    pub(super) fn from_head(head: &HeadObjectOutput) -> Option<Self> {
+        pick_checksum(
+            head.checksum_type(),
+            [
+                (ChecksumAlgorithm::Crc64Nvme, head.checksum_crc64_nvme()),
+                (ChecksumAlgorithm::Crc32c, head.checksum_crc32_c()),
+                (ChecksumAlgorithm::Crc32, head.checksum_crc32()),
+                (ChecksumAlgorithm::Sha256, head.checksum_sha256()),
+                (ChecksumAlgorithm::Sha1, head.checksum_sha1()),
+            ],
+        )
+    }
+
+    /// The full-object additional checksum in a GET response, if any
+    ///
+    /// Only whole-object GETs with checksum mode enabled include one.
+    pub(super) fn from_get(response: &GetObjectOutput) -> Option<Self> {
+        pick_checksum(
+            response.checksum_type(),
+            [
+                (ChecksumAlgorithm::Crc64Nvme, response.checksum_crc64_nvme()),
+                (ChecksumAlgorithm::Crc32c, response.checksum_crc32_c()),
+                (ChecksumAlgorithm::Crc32, response.checksum_crc32()),
+                (ChecksumAlgorithm::Sha256, response.checksum_sha256()),
+                (ChecksumAlgorithm::Sha1, response.checksum_sha1()),
+            ],
+        )
+    }
+
+    /// The MD5 in `etag`, if it is one
+    ///
+    /// Multipart ETags (`...-N`) and ETags of objects encrypted with KMS or
+    /// a customer key aren't MD5s of the contents.
+    pub(super) fn from_etag(etag: Option<&str>, encryption: EtagEncryption) -> Option<Self> {
+        let etag = etag?;
+        let is_md5 = etag.len() == 32 && etag.bytes().all(|b| b.is_ascii_hexdigit());
+        (is_md5 && encryption == EtagEncryption::Plain)
+            .then(|| Self::Md5(etag.to_ascii_lowercase()))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Checksum(algorithm, _) => algorithm.as_str(),
+            Self::Md5(_) => "md5 (etag)",
+        }
+    }
+
+    fn hasher(&self) -> Hasher {
+        match self {
+            Self::Checksum(algorithm, _) => Hasher::Checksum(algorithm.into_impl()),
+            Self::Md5(_) => Hasher::Md5(md5::Md5::new()),
+        }
+    }
+
+    /// Check `digest` against this, in whichever encoding S3 uses for it
+    fn check(&self, digest: &[u8], key: &str) -> anyhow::Result<()> {
+        let (expected, actual) = match self {
+            Self::Checksum(_, expected) => (expected, aws_smithy_types::base64::encode(digest)),
+            Self::Md5(expected) => (expected, hex(digest)),
+        };
+        if *expected != actual {
+            anyhow::bail!(
+                "Checksum mismatch for {key}: expected {} {expected}, got {actual}",
+                self.name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether an object's encryption keeps its ETag from being an MD5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(super) enum EtagEncryption {
+    #[default]
+    Plain,
+    Opaque,
+}
+
+impl EtagEncryption {
+    pub(super) fn from_get(response: &GetObjectOutput) -> Self {
+        let kms = matches!(
+            response.server_side_encryption(),
+            Some(ServerSideEncryption::AwsKms | ServerSideEncryption::AwsKmsDsse)
+        );
+        if kms || response.sse_customer_algorithm().is_some() {
+            Self::Opaque
+        } else {
+            Self::Plain
+        }
+    }
+}
+
+/// Pick a checksum we can compute over the whole file
+///
+/// Composite checksums of multipart uploads are checksums of the part
+/// checksums, which we can't reproduce without knowing the upload's part
+/// boundaries, so those are skipped.
+fn pick_checksum(
+    checksum_type: Option<&ChecksumType>,
+    values: [(ChecksumAlgorithm, Option<&str>); 5],
+) -> Option<Expected> {
+    if checksum_type == Some(&ChecksumType::Composite) {
+        return None;
+    }
+    values.into_iter().find_map(|(algorithm, value)| {
+        // older objects without a checksum type mark composite values with -N
+        value
+            .filter(|v| !v.contains('-'))
+            .map(|v| Expected::Checksum(algorithm, v.to_owned()))
+    })
+}
+
+enum Hasher {
+    Checksum(Box<dyn HttpChecksum>),
+    Md5(md5::Md5),
+}
+
+impl Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Checksum(checksum) => checksum.update(bytes),
+            Self::Md5(md5) => md5.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Checksum(checksum) => checksum.finalize().to_vec(),
+            Self::Md5(md5) => md5.finalize().to_vec(),
+        }
+    }
+}
+
+/// Everything we compute over a download's contents
+///
+/// That's the checksum to verify against, if any, and the SHA256 for
+/// `--write-checksums` if it was asked for.
+pub(super) struct Digests {
+    expected: Option<(Expected, Hasher)>,
+    sha256: Option<Hasher>,
+}
+
+impl Digests {
+    pub(super) fn new(expected: Option<Expected>, sha256: bool) -> Self {
+        Self {
+            expected: expected.map(|expected| {
+                let hasher = expected.hasher();
+                (expected, hasher)
+            }),
+            sha256: sha256.then(|| Hasher::Checksum(ChecksumAlgorithm::Sha256.into_impl())),
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.expected.is_none() && self.sha256.is_none()
+    }
+
+    pub(super) fn update(&mut self, bytes: &[u8]) {
+        if let Some((_, hasher)) = &mut self.expected {
+            hasher.update(bytes);
+        }
+        if let Some(hasher) = &mut self.sha256 {
+            hasher.update(bytes);
+        }
+    }
+
+    /// Feed the whole file at `path` through
+    pub(super) fn update_from_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::File::open(path)?;
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.update(&buf[..n]);
+        }
+    }
+
+    /// Check the contents, returning the hex SHA256 if it was asked for
+    pub(super) fn finish(self, key: &str) -> anyhow::Result<Option<String>> {
+        if let Some((expected, hasher)) = self.expected {
+            expected.check(&hasher.finalize(), key)?;
+            tracing::debug!(key, algorithm = expected.name(), "verified checksum");
+        }
+        Ok(self.sha256.map(|hasher| hex(&hasher.finalize())))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// One line of a `sha256sum -c` manifest
+///
+/// Like GNU coreutils, a name with a backslash or newline in it is escaped
+/// and the line starts with a backslash.
+pub(crate) fn manifest_line(sha256: &str, path: &Path) -> String {
+    let name = path.display().to_string();
+    if name.contains(['\\', '\n']) {
+        let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{sha256}  {escaped}\n")
+    } else {
+        format!("{sha256}  {name}\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+
+    use super::*;
+
+    fn digest_of(expected: Option<Expected>, sha256: bool, data: &[u8]) -> Digests {
+        let mut digests = Digests::new(expected, sha256);
+        digests.update(data);
+        digests
+    }
+
+    #[test]
+    fn test_digests_verify_checksums() {
+        let crc32 = Expected::Checksum(ChecksumAlgorithm::Crc32, "DUoRhQ==".to_owned());
+        check!(
+            digest_of(Some(crc32.clone()), false, b"hello world")
+                .finish("k")
+                .is_ok()
+        );
+        let err = digest_of(Some(crc32), false, b"hello there")
+            .finish("k")
+            .unwrap_err();
+        check!(
+            err.to_string()
+                .contains("Checksum mismatch for k: expected crc32 DUoRhQ==")
+        );
+
+        let md5 = Expected::Md5("5eb63bbbe01eeed093cb22bb8f5acdc3".to_owned());
+        check!(
+            digest_of(Some(md5.clone()), false, b"hello world")
+                .finish("k")
+                .is_ok()
+        );
+        check!(digest_of(Some(md5), false, b"hello").finish("k").is_err());
+    }
+
+    #[test]
+    fn test_digests_sha256() -> anyhow::Result<()> {
+        let sha256 = digest_of(None, true, b"hello world").finish("k")?;
+        check!(
+            sha256.as_deref()
+                == Some("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+        );
+
+        let dir = assert_fs::TempDir::new()?;
+        let path = dir.path().join("hello");
+        std::fs::write(&path, "hello world")?;
+        let mut digests = Digests::new(None, true);
+        digests.update_from_file(&path)?;
+        check!(digests.finish("k")? == sha256);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expected_from_etag() {
+        let etag = "5EB63BBBE01EEED093CB22BB8F5ACDC3";
+        check!(
+            Expected::from_etag(Some(etag), EtagEncryption::Plain)
+                == Some(Expected::Md5("5eb63bbbe01eeed093cb22bb8f5acdc3".to_owned()))
+        );
+        check!(Expected::from_etag(Some(etag), EtagEncryption::Opaque).is_none());
+        let multipart = "5eb63bbbe01eeed093cb22bb8f5acdc3-12";
+        check!(Expected::from_etag(Some(multipart), EtagEncryption::Plain).is_none());
+        check!(Expected::from_etag(None, EtagEncryption::Plain).is_none());
+    }
+
+    #[test]
+    fn test_manifest_line() {
+        check!(manifest_line("abc", Path::new("out/a.txt")) == "abc  out/a.txt\n");
+        check!(manifest_line("abc", Path::new("out/a\nb")) == "\\abc  out/a\\nb\n");
+    }
+}
@@ -0,0 +1,400 @@
+//! Making sure no two objects are downloaded to the same local path
+//!
+//! Different keys can end up with the same local path: `--flatten` turns
+//! both `a/b-c` and `a-b/c` into `a-b-c`, a case-insensitive filesystem
+//! can't tell `Foo` from `foo`, and a key that is also a "directory" of
+//! other keys (`x` and `x/y`) can't be both a file and a directory locally.
+//! Where they're common (flattening, case-insensitive filesystems) they are
+//! found across all the matches before anything is downloaded. Otherwise
+//! objects are downloaded as they're listed, and each one claims its path as
+//! it comes.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use aws_smithy_checksums::ChecksumAlgorithm;
+
+use super::super::OnCollision;
+use super::super::S3Object;
+
+/// The most collisions listed when refusing to download
+const MAX_REPORTED: usize = 10;
+
+/// Give every object in `planned` a local path of its own
+///
+/// The object with the first key keeps a contested path, the others get a
+/// new name next to it according to `on_collision`, or the whole download
+/// is refused. A file that would be in the way of a directory is always the
+/// one that moves. Returns how many objects were given a new name, and
+/// sorts `planned` by key.
+pub(super) fn resolve_collisions(
+    planned: &mut [(S3Object, PathBuf)],
+    on_collision: OnCollision,
+    case_insensitive: bool,
+) -> anyhow::Result<usize> {
+    planned.sort_by(|(a, _), (b, _)| a.key.cmp(&b.key));
+    let fold = |path: &Path| {
+        let path = path.to_string_lossy();
+        if case_insensitive {
+            path.to_lowercase()
+        } else {
+            path.into_owned()
+        }
+    };
+
+    // every directory that has to exist, and the first key that needs it
+    let mut dirs: HashMap<String, &str> = HashMap::new();
+    for (obj, path) in planned.iter() {
+        for dir in path.ancestors().skip(1) {
+            if dir.as_os_str().is_empty() {
+                break;
+            }
+            dirs.entry(fold(dir)).or_insert(&obj.key);
+        }
+    }
+    let natural: HashSet<String> = planned.iter().map(|(_, path)| fold(path)).collect();
+
+    let mut taken: HashMap<String, &str> = HashMap::new();
+    let mut collisions = Vec::new();
+    for (obj, path) in planned.iter() {
+        let folded = fold(path);
+        let conflict = match (taken.get(&folded), dirs.get(&folded)) {
+            (Some(other), _) => format!(
+                "{} and {} would both be downloaded to {}",
+                other,
+                obj.key,
+                path.display()
+            ),
+            (None, Some(other)) => format!(
+                "{} would be downloaded to {}, which has to be a directory for {}",
+                obj.key,
+                path.display(),
+                other
+            ),
+            (None, None) => {
+                taken.insert(folded, &obj.key);
+                continue;
+            }
+        };
+        collisions.push((obj.key.clone(), path.clone(), conflict));
+    }
+    if collisions.is_empty() {
+        return Ok(0);
+    }
+    if on_collision == OnCollision::Error {
+        let mut message = format!(
+            "{} objects would collide with others on the local filesystem:",
+            collisions.len()
+        );
+        for (_, _, conflict) in collisions.iter().take(MAX_REPORTED) {
+            message.push_str("\n    ");
+            message.push_str(conflict);
+        }
+        if collisions.len() > MAX_REPORTED {
+            message.push_str(&format!(
+                "\n    ... and {} more",
+                collisions.len() - MAX_REPORTED
+            ));
+        }
+        message.push_str("\nPass --on-collision suffix or hash to download them under new names");
+        anyhow::bail!(message);
+    }
+
+    let mut new_paths = HashMap::new();
+    for (key, path, conflict) in collisions {
+        let renamed = new_names(&key, &path, on_collision)
+            .find(|candidate| {
+                let folded = fold(candidate);
+                !natural.contains(&folded)
+                    && !dirs.contains_key(&folded)
+                    && !taken.contains_key(&folded)
+            })
+            .expect("there are always more names to try");
+        tracing::debug!(%conflict, renamed = %renamed.display(), "renaming to avoid a collision");
+        taken.insert(fold(&renamed), "");
+        new_paths.insert(key, renamed);
+    }
+    let renamed = new_paths.len();
+    for (obj, path) in planned.iter_mut() {
+        if let Some(new_path) = new_paths.remove(&obj.key) {
+            *path = new_path;
+        }
+    }
+    Ok(renamed)
+}
+
+/// The local paths claimed so far by objects downloaded as they're listed
+///
+/// Without every match to go by, the first object to claim a path keeps it.
+/// A file that would be in the way of a directory moves when it comes
+/// second, otherwise the directory moves for everything in it that comes
+/// after.
+#[derive(Debug, Default)]
+pub(super) struct Claims {
+    files: HashSet<PathBuf>,
+    dirs: HashSet<PathBuf>,
+    /// Directories that were in the way of a file, and where their
+    /// contents go instead
+    moved_dirs: HashMap<PathBuf, PathBuf>,
+}
+
+impl Claims {
+    /// Claim a local path for `key`, which would naturally go to `path`
+    ///
+    /// Returns the path it gets, and whether that's a new name.
+    pub(super) fn claim(
+        &mut self,
+        key: &str,
+        path: &Path,
+        on_collision: OnCollision,
+    ) -> (PathBuf, bool) {
+        let mut claimed = PathBuf::new();
+        let mut renamed = false;
+        let mut components = path.components().peekable();
+        while let Some(component) = components.next() {
+            claimed.push(component);
+            if components.peek().is_none() {
+                break;
+            }
+            if let Some(moved) = self.moved_dirs.get(&claimed) {
+                claimed = moved.clone();
+                renamed = true;
+            } else if self.files.contains(&claimed) {
+                let moved = self.free_name(key, &claimed, on_collision);
+                tracing::debug!(key, dir = %claimed.display(), moved = %moved.display(), "moving a directory out of the way of a file");
+                self.moved_dirs.insert(claimed, moved.clone());
+                claimed = moved;
+                renamed = true;
+            }
+            self.dirs.insert(claimed.clone());
+        }
+        if self.files.contains(&claimed) || self.dirs.contains(&claimed) {
+            let moved = self.free_name(key, &claimed, on_collision);
+            tracing::debug!(key, path = %claimed.display(), renamed = %moved.display(), "renaming to avoid a collision");
+            claimed = moved;
+            renamed = true;
+        }
+        self.files.insert(claimed.clone());
+        (claimed, renamed)
+    }
+
+    /// A name next to `path` that nothing has claimed
+    fn free_name(&self, key: &str, path: &Path, on_collision: OnCollision) -> PathBuf {
+        new_names(key, path, on_collision)
+            .find(|candidate| !self.files.contains(candidate) && !self.dirs.contains(candidate))
+            .expect("there are always more names to try")
+    }
+}
+
+/// The names to try, in order, for `key` when `path` is taken
+fn new_names<'a>(
+    key: &str,
+    path: &'a Path,
+    on_collision: OnCollision,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    let tag = match on_collision {
+        OnCollision::Hash => key_hash(key),
+        _ => String::new(),
+    };
+    (0_u32..)
+        .filter_map(move |n| match (tag.as_str(), n) {
+            ("", 0) => None,
+            ("", n) => Some(n.to_string()),
+            (tag, 0) => Some(tag.to_owned()),
+            (tag, n) => Some(format!("{tag}-{n}")),
+        })
+        .map(|tag| tagged(path, &tag))
+}
+
+/// `path` with `-tag` added to the end of its file stem
+fn tagged(path: &Path, tag: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{stem}-{tag}.{}", ext.to_string_lossy())),
+        None => path.with_file_name(format!("{stem}-{tag}")),
+    }
+}
+
+/// A short hash of `key`, so the same key always gets the same new name
+fn key_hash(key: &str) -> String {
+    let mut hasher = ChecksumAlgorithm::Sha256.into_impl();
+    hasher.update(key.as_bytes());
+    hasher.finalize()[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Whether the filesystem that `dir` is (or will be) on ignores case
+///
+/// Checked by creating a file in the closest directory that exists and
+/// looking for it under an upper-cased name. If that can't be done, we
+/// guess from the platform.
+pub(super) fn is_case_insensitive(dir: &Path) -> bool {
+    let guess = cfg!(any(windows, target_os = "macos"));
+    let Some(existing) = dir
+        .ancestors()
+        .map(|d| {
+            if d.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                d
+            }
+        })
+        .find(|d| d.is_dir())
+    else {
+        return guess;
+    };
+    let name = format!(".s3glob-case-probe-{}", std::process::id());
+    let probe = existing.join(&name);
+    if std::fs::File::create(&probe).is_err() {
+        return guess;
+    }
+    let insensitive = existing.join(name.to_uppercase()).exists();
+    let _ = std::fs::remove_file(&probe);
+    insensitive
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+    use aws_sdk_s3::primitives::DateTime;
+
+    use super::*;
+
+    fn planned(keys_and_paths: &[(&str, &str)]) -> Vec<(S3Object, PathBuf)> {
+        keys_and_paths
+            .iter()
+            .map(|(key, path)| {
+                let obj = S3Object {
+                    key: key.to_string(),
+                    size: 1,
+                    last_modified: DateTime::from_millis(0),
+                    etag: None,
+                    storage_class: None,
+                    checksum_algorithms: None,
+                    restore_status: None,
+                };
+                (obj, PathBuf::from(path))
+            })
+            .collect()
+    }
+
+    fn paths(planned: &[(S3Object, PathBuf)]) -> Vec<String> {
+        planned
+            .iter()
+            .map(|(_, path)| path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_no_collisions() -> anyhow::Result<()> {
+        let mut plan = planned(&[("a/b", "a/b"), ("a/c", "a/c"), ("d", "d")]);
+        check!(resolve_collisions(&mut plan, OnCollision::Error, true)? == 0);
+        check!(paths(&plan) == ["a/b", "a/c", "d"]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_flattened_collision_gets_a_suffix() -> anyhow::Result<()> {
+        let mut plan = planned(&[("a/b-c.txt", "a-b-c.txt"), ("a-b/c.txt", "a-b-c.txt")]);
+        check!(resolve_collisions(&mut plan, OnCollision::Suffix, false)? == 1);
+        // `a-b/...` sorts before `a/...`
+        check!(plan[0].0.key == "a-b/c.txt");
+        check!(paths(&plan) == ["a-b-c.txt", "a-b-c-1.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_suffix_skips_names_other_objects_use() -> anyhow::Result<()> {
+        let mut plan = planned(&[("x", "f"), ("y", "f"), ("z", "f-1")]);
+        resolve_collisions(&mut plan, OnCollision::Suffix, false)?;
+        check!(paths(&plan) == ["f", "f-2", "f-1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive_collision() -> anyhow::Result<()> {
+        let mut plan = planned(&[("Foo", "Foo"), ("foo", "foo")]);
+        check!(resolve_collisions(&mut plan, OnCollision::Suffix, false)? == 0);
+
+        check!(resolve_collisions(&mut plan, OnCollision::Suffix, true)? == 1);
+        check!(paths(&plan) == ["Foo", "foo-1"]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_file_in_the_way_of_a_directory_moves() -> anyhow::Result<()> {
+        let mut plan = planned(&[("x/y", "x/y"), ("x", "x")]);
+        check!(resolve_collisions(&mut plan, OnCollision::Suffix, false)? == 1);
+        check!(paths(&plan) == ["x-1", "x/y"]);
+
+        // the same with the file sorting after what's in the directory
+        let mut plan = planned(&[("x/y/z", "x/y/z"), ("x/y.txt", "x/y")]);
+        resolve_collisions(&mut plan, OnCollision::Suffix, false)?;
+        check!(paths(&plan) == ["x/y-1", "x/y/z"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_names_depend_only_on_the_key() -> anyhow::Result<()> {
+        let mut plan = planned(&[("A.txt", "a.txt"), ("a.txt", "a.txt")]);
+        resolve_collisions(&mut plan, OnCollision::Hash, false)?;
+        let hash = key_hash("a.txt");
+        check!(hash.len() == 8);
+        check!(paths(&plan) == ["a.txt".to_owned(), format!("a-{hash}.txt")]);
+
+        let mut plan = planned(&[("B.txt", "a.txt"), ("a.txt", "a.txt"), ("A.txt", "a.txt")]);
+        resolve_collisions(&mut plan, OnCollision::Hash, false)?;
+        check!(plan[2].1 == PathBuf::from(format!("a-{hash}.txt")));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_error_lists_collisions() {
+        let mut plan = planned(&[
+            ("a/b-c", "a-b-c"),
+            ("a-b/c", "a-b-c"),
+            ("x", "x"),
+            ("x/y", "x/y"),
+        ]);
+        let err = resolve_collisions(&mut plan, OnCollision::Error, false)
+            .unwrap_err()
+            .to_string();
+        check!(err.contains("2 objects would collide"));
+        check!(err.contains("a-b/c and a/b-c would both be downloaded to a-b-c"));
+        check!(err.contains("x would be downloaded to x, which has to be a directory for x/y"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_claims_keep_the_first_path() {
+        let mut claims = Claims::default();
+        let mut claim = |key: &str| {
+            let (path, renamed) = claims.claim(key, Path::new(key), OnCollision::Suffix);
+            (path.display().to_string(), renamed)
+        };
+        check!(claim("a/b") == ("a/b".to_owned(), false));
+        check!(claim("a/b") == ("a/b-1".to_owned(), true));
+        // a file in the way of a directory that's already there moves
+        check!(claim("a") == ("a-1".to_owned(), true));
+        // a directory in the way of a file that's already there moves, for
+        // everything in it
+        check!(claim("x") == ("x".to_owned(), false));
+        check!(claim("x/y/z") == ("x-1/y/z".to_owned(), true));
+        check!(claim("x/w") == ("x-1/w".to_owned(), true));
+        check!(claim("x-1") == ("x-1-1".to_owned(), true));
+    }
+
+    #[test]
+    fn test_is_case_insensitive_probes_existing_parent() -> anyhow::Result<()> {
+        let dir = assert_fs::TempDir::new()?;
+        let expected = is_case_insensitive(dir.path());
+        check!(is_case_insensitive(&dir.path().join("not/yet/created")) == expected);
+        check!(std::fs::read_dir(dir.path())?.count() == 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+//! Deciding how many downloads run at once
+//!
+//! Downloads are started while there's room in two budgets: connections (a
+//! large object fetched as ranged GETs uses several) and the total size of
+//! the objects being downloaded. The connection budget starts small and is
+//! adjusted every second: it grows while that makes downloads faster, and
+//! shrinks when adding connections stops paying off or responses start
+//! taking much longer than they used to, which is what an overloaded link
+//! or host looks like.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::debug;
+
+use super::super::S3Object;
+use super::super::rate_limit::TokenBucket;
+use super::Downloader;
+
+/// How often the connection budget is adjusted
+const ADJUST_EVERY: Duration = Duration::from_secs(1);
+
+/// How much faster downloads have to get for more connections to be worth it
+const GAIN: f64 = 1.05;
+
+/// How much slower downloads have to get for the last increase to be undone
+const LOSS: f64 = 0.9;
+
+/// How many times the best latency we've seen counts as congested
+const CONGESTED_LATENCY_FACTOR: u32 = 3;
+
+/// Latencies below this never count as congested, however fast the best was
+const MIN_CONGESTED_LATENCY: Duration = Duration::from_millis(250);
+
+/// How far down the queue to look for a download that fits when the next
+/// one doesn't
+const LOOKAHEAD: usize = 64;
+
+/// The limits the scheduler works within
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SchedulerLimits {
+    /// Connections to start with, and never go below while there's work
+    pub(crate) min_connections: usize,
+    pub(crate) max_connections: usize,
+    /// The most bytes of objects being downloaded at once
+    ///
+    /// A single object bigger than this is still downloaded, on its own.
+    pub(crate) max_in_flight_bytes: u64,
+    /// Cap on bandwidth across all downloads, in bytes per second
+    pub(crate) rate_limit: Option<u64>,
+}
+
+/// Runs downloads as the budgets allow
+pub(crate) struct Scheduler {
+    jobs: UnboundedSender<Job>,
+    link: Arc<Link>,
+}
+
+struct Job {
+    dl: Downloader,
+    obj: S3Object,
+    path: PathBuf,
+}
+
+impl Scheduler {
+    pub(crate) fn start(limits: SchedulerLimits) -> Self {
+        let link = Arc::new(Link::new(limits.rate_limit));
+        let (jobs, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(dispatch(rx, limits, link.clone()));
+        Self { jobs, link }
+    }
+
+    /// What downloads report received bytes and response times to
+    pub(crate) fn link(&self) -> Arc<Link> {
+        self.link.clone()
+    }
+
+    /// Queue `object` to be downloaded to `path` by `dl`
+    pub(crate) fn download_object(&self, dl: Downloader, obj: S3Object, path: PathBuf) {
+        self.jobs
+            .send(Job { dl, obj, path })
+            .expect("send on channel should succeed");
+    }
+}
+
+async fn dispatch(mut jobs: UnboundedReceiver<Job>, limits: SchedulerLimits, link: Arc<Link>) {
+    let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel::<Cost>();
+    let mut queue = VecDeque::new();
+    let mut controller = Controller::new(limits.min_connections, limits.max_connections);
+    let mut budget = Budget::new(controller.limit, limits.max_in_flight_bytes);
+    let mut tick = tokio::time::interval(ADJUST_EVERY);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_tick = Instant::now();
+    let mut open = true;
+    loop {
+        tokio::select! {
+            job = jobs.recv(), if open => match job {
+                Some(job) => queue.push_back(job),
+                None => open = false,
+            },
+            Some(cost) = done_rx.recv() => budget.release(cost),
+            _ = tick.tick() => {
+                let (bytes, latency) = link.sample();
+                let throughput = bytes as f64 / last_tick.elapsed().as_secs_f64();
+                last_tick = Instant::now();
+                budget.connections = controller.update(throughput, latency, !queue.is_empty());
+                debug!(
+                    throughput,
+                    ?latency,
+                    connections = budget.connections,
+                    in_flight = budget.used_connections,
+                    queued = queue.len(),
+                    "adjusted download concurrency"
+                );
+            }
+        }
+        // smaller downloads can go ahead of one that doesn't fit yet
+        let mut i = 0;
+        while i < queue.len().min(LOOKAHEAD) && !budget.is_full() {
+            let job: &Job = &queue[i];
+            let cost = Cost {
+                connections: job.dl.ranged.connections(job.obj.size as u64),
+                bytes: job.obj.size as u64,
+            };
+            if !budget.try_admit(cost) {
+                i += 1;
+                continue;
+            }
+            let job = queue.remove(i).expect("index is in bounds");
+            let done_tx = done_tx.clone();
+            tokio::spawn(async move {
+                job.dl.download_object(job.obj, job.path).await;
+                let _ = done_tx.send(cost);
+            });
+        }
+        if !open && queue.is_empty() && budget.used_connections == 0 {
+            return;
+        }
+    }
+}
+
+/// What a download counts against the budgets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cost {
+    connections: usize,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Budget {
+    connections: usize,
+    max_bytes: u64,
+    used_connections: usize,
+    used_bytes: u64,
+}
+
+impl Budget {
+    fn new(connections: usize, max_bytes: u64) -> Self {
+        Self {
+            connections,
+            max_bytes,
+            used_connections: 0,
+            used_bytes: 0,
+        }
+    }
+
+    /// Count `cost` as in use if it fits
+    ///
+    /// Anything fits when nothing else is running, so no download is too big
+    /// to ever start.
+    fn try_admit(&mut self, cost: Cost) -> bool {
+        let fits = self.used_connections == 0
+            || (self.used_connections + cost.connections <= self.connections
+                && self.used_bytes + cost.bytes <= self.max_bytes);
+        if fits {
+            self.used_connections += cost.connections;
+            self.used_bytes += cost.bytes;
+        }
+        fits
+    }
+
+    fn release(&mut self, cost: Cost) {
+        self.used_connections -= cost.connections;
+        self.used_bytes -= cost.bytes;
+    }
+
+    fn is_full(&self) -> bool {
+        self.used_connections >= self.connections
+    }
+}
+
+/// Hill-climbs the number of connections towards the best throughput
+#[derive(Debug)]
+struct Controller {
+    limit: usize,
+    min: usize,
+    max: usize,
+    /// Throughput over the last interval, in bytes per second
+    previous: f64,
+    /// Whether the last change was an increase
+    growing: bool,
+    /// The best average latency of an interval so far
+    base_latency: Option<Duration>,
+}
+
+impl Controller {
+    fn new(min: usize, max: usize) -> Self {
+        let min = min.min(max);
+        Self {
+            limit: min,
+            min,
+            max,
+            previous: 0.0,
+            growing: false,
+            base_latency: None,
+        }
+    }
+
+    /// The connection limit for the next interval
+    ///
+    /// `busy` is whether downloads were waiting for a connection, without
+    /// which there's nothing to learn from the throughput.
+    fn update(&mut self, throughput: f64, latency: Option<Duration>, busy: bool) -> usize {
+        let previous = std::mem::replace(&mut self.previous, throughput);
+        let congested = match (latency, self.base_latency) {
+            (Some(latency), Some(base)) => {
+                latency > base * CONGESTED_LATENCY_FACTOR && latency > MIN_CONGESTED_LATENCY
+            }
+            _ => false,
+        };
+        if let Some(latency) = latency {
+            self.base_latency = Some(self.base_latency.map_or(latency, |base| base.min(latency)));
+        }
+        if !busy {
+            self.growing = false;
+        } else if congested {
+            self.shrink();
+        } else if throughput > previous * GAIN {
+            self.grow();
+        } else if throughput < previous * LOSS && self.growing {
+            // the last increase made things worse
+            self.shrink();
+        } else {
+            self.growing = false;
+        }
+        self.limit
+    }
+
+    fn grow(&mut self) {
+        self.limit = (self.limit + (self.limit / 4).max(1)).min(self.max);
+        self.growing = true;
+    }
+
+    fn shrink(&mut self) {
+        self.limit = (self.limit * 3 / 4).max(self.min);
+        self.growing = false;
+    }
+}
+
+/// Shared by all downloads: what they've received and how long responses took
+#[derive(Debug)]
+pub(crate) struct Link {
+    bytes: AtomicU64,
+    /// Total and count of response times since the last sample
+    latency: Mutex<(Duration, u32)>,
+    rate_limit: Option<Mutex<TokenBucket>>,
+}
+
+impl Link {
+    fn new(rate_limit: Option<u64>) -> Self {
+        Self {
+            bytes: AtomicU64::new(0),
+            latency: Mutex::new((Duration::ZERO, 0)),
+            rate_limit: rate_limit
+                .map(|rate| Mutex::new(TokenBucket::new(rate as f64, Instant::now()))),
+        }
+    }
+
+    /// A link for downloads that aren't run by a scheduler
+    pub(crate) fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    /// Count `bytes` as received, returning how long to wait before reading more
+    pub(crate) fn received(&self, bytes: usize) -> Duration {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        match &self.rate_limit {
+            Some(bucket) => bucket
+                .lock()
+                .expect("rate limit lock is never poisoned")
+                .reserve(bytes as u64, Instant::now()),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Record how long a request took to respond
+    pub(crate) fn responded_after(&self, latency: Duration) {
+        let mut total = self.latency.lock().expect("latency lock is never poisoned");
+        total.0 += latency;
+        total.1 += 1;
+    }
+
+    /// The bytes received and average latency since the last sample
+    fn sample(&self) -> (u64, Option<Duration>) {
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        let (total, count) =
+            std::mem::take(&mut *self.latency.lock().expect("latency lock is never poisoned"));
+        (bytes, (count > 0).then(|| total / count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+
+    use super::*;
+
+    fn cost(connections: usize, bytes: u64) -> Cost {
+        Cost { connections, bytes }
+    }
+
+    #[test]
+    fn test_budget_limits_connections_and_bytes() {
+        let mut budget = Budget::new(4, 100);
+        check!(budget.try_admit(cost(1, 60)));
+        check!(!budget.try_admit(cost(1, 50)), "over the byte budget");
+        check!(budget.try_admit(cost(3, 40)));
+        check!(budget.is_full());
+        check!(!budget.try_admit(cost(1, 0)), "out of connections");
+        budget.release(cost(3, 40));
+        check!(budget.try_admit(cost(2, 10)));
+    }
+
+    #[test]
+    fn test_budget_always_admits_when_idle() {
+        let mut budget = Budget::new(4, 100);
+        check!(budget.try_admit(cost(8, 1_000)));
+        check!(!budget.try_admit(cost(1, 1)));
+        budget.release(cost(8, 1_000));
+        check!(budget.used_connections == 0 && budget.used_bytes == 0);
+    }
+
+    #[test]
+    fn test_controller_grows_while_throughput_improves() {
+        let mut controller = Controller::new(8, 20);
+        check!(controller.update(100.0, None, true) == 10);
+        check!(controller.update(200.0, None, true) == 12);
+        check!(controller.update(300.0, None, true) == 15);
+        check!(controller.update(400.0, None, true) == 18);
+        check!(
+            controller.update(500.0, None, true) == 20,
+            "capped at the max"
+        );
+        check!(controller.update(600.0, None, true) == 20);
+    }
+
+    #[test]
+    fn test_controller_backs_off_when_growth_hurts() {
+        let mut controller = Controller::new(8, 100);
+        check!(controller.update(100.0, None, true) == 10);
+        check!(
+            controller.update(80.0, None, true) == 8,
+            "undo the increase"
+        );
+        // a drop after shrinking isn't blamed on the shrink
+        check!(controller.update(60.0, None, true) == 8);
+        // flat throughput holds steady
+        check!(controller.update(61.0, None, true) == 8);
+    }
+
+    #[test]
+    fn test_controller_holds_when_not_busy() {
+        let mut controller = Controller::new(8, 100);
+        check!(controller.update(1_000.0, None, false) == 8);
+        check!(controller.update(2_000.0, None, false) == 8);
+    }
+
+    #[test]
+    fn test_controller_shrinks_on_congested_latency() {
+        let mut controller = Controller::new(4, 100);
+        let ms = Duration::from_millis;
+        check!(controller.update(100.0, Some(ms(50)), true) == 5);
+        check!(controller.update(200.0, Some(ms(60)), true) == 6);
+        check!(
+            controller.update(300.0, Some(ms(400)), true) == 4,
+            "shrinks despite faster downloads"
+        );
+        // slow but not many times worse than the best we've seen
+        let mut controller = Controller::new(4, 100);
+        check!(controller.update(100.0, Some(ms(200)), true) == 5);
+        check!(controller.update(200.0, Some(ms(240)), true) == 6);
+    }
+
+    #[test]
+    fn test_link_samples_reset() {
+        let link = Link::unlimited();
+        check!(link.received(10) == Duration::ZERO);
+        link.responded_after(Duration::from_millis(10));
+        link.responded_after(Duration::from_millis(30));
+        check!(link.sample() == (10, Some(Duration::from_millis(20))));
+        check!(link.sample() == (0, None));
+    }
+}
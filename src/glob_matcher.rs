@@ -1,6 +1,6 @@
 //! A pattern is a glob that knows how to split itself into a prefix and join with a partial prefix
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 
@@ -9,6 +9,7 @@ use aws_sdk_s3::types::Object;
 use engine::ScanResult;
 use glob::Glob;
 use itertools::Itertools as _;
+use key_regex::{KeyRegex, delimiter_re, literal_re};
 use regex::Regex;
 use tokio::sync::Semaphore;
 use tokio::sync::mpsc::UnboundedReceiver;
@@ -18,21 +19,37 @@ mod engine;
 pub use engine::{Engine, S3Engine};
 
 use crate::progress;
+use crate::stats::Phase;
 use crate::{S3Object, progressln};
 
+mod cost;
+mod date_range;
 mod glob;
+mod key_ranges;
+mod key_regex;
+mod ordered;
+mod regex_prefixes;
+mod trie;
+mod verify;
+
+pub(crate) use key_ranges::KeyBounds;
+use key_ranges::KeyRange;
 
 #[cfg(test)]
 mod proptests;
 
 pub(crate) const GLOB_CHARS: &[char] = &['*', '?', '[', '{'];
 
-/// The maximum number of prefixes that can be generated by the glob matcher
+/// The default for the most prefixes the glob matcher may generate
 ///
 /// Checking that constructed prefixes exist is significantly slower than
 /// scanning for objects.
 const MAX_PREFIXES: usize = 100_000;
 
+/// The most candidate prefixes that are checked against the bucket at once
+///
+/// Literal parts at the start of a pattern with more combinations than this
+/// are expanded and checked one part at a time.
 const MAX_CHECK_PREFIXES: usize = 10_000;
 
 /// Parallelism is determined by the number of prefixes, and 50 is much faster
@@ -46,13 +63,17 @@ const DESIRED_MIN_PREFIXES: usize = 25;
 /// without exploding into per-sub-prefix listings.
 const PROBE_MAX_KEYS: i32 = 1000;
 
+/// Default cap on the number of case variants a literal part may expand into
+/// under `--ignore-case` before it is resolved by scanning instead.
+pub(crate) const DEFAULT_MAX_CASE_VARIANTS: usize = 256;
+
 /// A thing that knows how to generate and filter S3 prefixes based on a glob pattern
 #[derive(Debug, Clone)]
 pub struct S3GlobMatcher {
     raw: String,
-    delimiter: char,
+    delimiter: String,
     parts: Vec<glob::Glob>,
-    regex: Regex,
+    regex: KeyRegex,
     max_parallelism: usize,
     min_prefixes: usize,
     max_prefixes: usize,
@@ -67,32 +88,105 @@ pub struct S3GlobMatcher {
     /// `[!abc]` compiles to `[^abc]` — both can match the delimiter,
     /// matching the historical (pre-strict) behavior.
     /// When `false`, both are restricted to a single segment by including
-    /// the delimiter in their negated set.
+    /// the delimiter (every character of it, if it is longer than one) in
+    /// their negated set.
     cross_delim: bool,
+    /// Whether the pattern matches keys case-insensitively
+    ignore_case: bool,
+    /// Which set of glob rules the pattern was parsed with
+    dialect: GlobDialect,
+    /// Only match keys within these, in addition to the pattern
+    bounds: KeyBounds,
+    /// Whether `get_objects` sends listed keys in order
+    sorted: bool,
+}
+
+/// The flavor of glob syntax a pattern is written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[clap(rename_all = "lowercase")]
+pub enum GlobDialect {
+    /// s3glob's own rules: `**` may appear anywhere and matches across
+    /// delimiters, `**/` makes the following delimiter optional
+    #[default]
+    S3glob,
+    /// globset rules: `**` must be a whole path component, `*` and `?`
+    /// never match the delimiter
+    Globset,
+    /// globset rules, plus gitignore anchoring: a pattern without a
+    /// delimiter (other than a trailing one) matches at any depth, and a
+    /// leading delimiter anchors the pattern at the bucket root
+    Gitignore,
+}
+
+impl GlobDialect {
+    fn is_strict(self) -> bool {
+        !matches!(self, GlobDialect::S3glob)
+    }
+
+    /// Rewrite the pattern into an equivalent one with globset semantics
+    fn rewrite(self, raw: &str, delimiter: &str) -> String {
+        if self != GlobDialect::Gitignore {
+            return raw.to_string();
+        }
+        if let Some(anchored) = raw.strip_prefix(delimiter) {
+            return anchored.to_string();
+        }
+        let body = raw.strip_suffix(delimiter).unwrap_or(raw);
+        if body.contains(delimiter) {
+            raw.to_string()
+        } else {
+            format!("**{delimiter}{raw}")
+        }
+    }
 }
 
 #[derive(Debug)]
 pub(crate) struct PrefixSearchResult {
     pub prefixes: Vec<String>,
     pub objects: Vec<Object>,
+    /// Parts of flat, dense prefixes, listed in parallel in addition to
+    /// `prefixes`
+    pub ranges: Vec<KeyRange>,
     /// Peak size of the candidate prefix set across the whole search,
     /// including intermediate sets pruned away before the next pattern
     /// part. A measure of how wide the search had to fan out; surfaced
     /// to the user by `ls` as "out of N candidates".
     pub max_candidate_prefixes: usize,
+    /// How many LIST requests expanding a `**` and listing what it found
+    /// was estimated to take, if the pattern has one
+    pub estimated_list_calls: Option<usize>,
+    /// True if the pattern had more candidate prefixes than the matcher's
+    /// `max_prefixes`, so `prefixes` are where the search stopped and
+    /// everything under them has to be listed and filtered
+    pub cut_off: bool,
 }
 
 /// A scanner takes a glob pattern and can efficiently generate a list of S3
 /// prefixes based on it.
 impl S3GlobMatcher {
-    /// Parse a pattern.
+    /// Parse a pattern using the s3glob dialect.
+    #[cfg(test)]
+    pub(crate) fn parse(raw: String, delimiter: &str, cross_delim: bool) -> Result<Self> {
+        Self::parse_with_dialect(raw, delimiter, cross_delim, GlobDialect::S3glob)
+    }
+
+    /// Parse a pattern using the rules of `dialect`.
     ///
     /// `cross_delim` controls whether `?` and negated character classes
     /// (`[!...]`) may match the delimiter character. Set `true` for the
-    /// historical lax behavior, `false` for strict single-segment.
-    pub fn parse(raw: String, delimiter: &str, cross_delim: bool) -> Result<Self> {
+    /// historical lax behavior, `false` for strict single-segment. The
+    /// strict dialects are always single-segment, so `cross_delim` only
+    /// affects [`GlobDialect::S3glob`].
+    pub fn parse_with_dialect(
+        raw: String,
+        delimiter: &str,
+        cross_delim: bool,
+        dialect: GlobDialect,
+    ) -> Result<Self> {
+        let cross_delim = cross_delim && !dialect.is_strict();
+        let rewritten = dialect.rewrite(&raw, delimiter);
         let mut parts = Vec::new();
-        let mut remaining = &*raw;
+        let mut remaining = &*rewritten;
         while !remaining.is_empty() {
             let next_idx = remaining.find(GLOB_CHARS);
             match next_idx {
@@ -104,7 +198,8 @@ impl S3GlobMatcher {
                             allowed: vec![next_part.clone()],
                         });
                     }
-                    let gl = glob::parse_pattern(&remaining[idx..]).context("Parsing pattern")?;
+                    let gl = glob::parse_pattern(&remaining[idx..], dialect)
+                        .context("Parsing pattern")?;
                     remaining = &remaining[idx + gl.pattern_len()..];
                     parts.push(gl);
                 }
@@ -120,8 +215,14 @@ impl S3GlobMatcher {
 
         let mut new_parts: Vec<glob::Glob> = Vec::new();
         for part in parts {
+            // a run of literals with too many combinations is kept as
+            // separate parts, so that find_prefixes can expand it one part at
+            // a time (see trie.rs)
             if let Some(last) = new_parts.last_mut() {
-                if last.is_choice() && part.is_choice() {
+                if last.is_choice()
+                    && part.is_choice()
+                    && last.combined_len(&part) <= MAX_CHECK_PREFIXES
+                {
                     last.combine_with(&part);
                 } else {
                     new_parts.push(part);
@@ -134,17 +235,21 @@ impl S3GlobMatcher {
             new_parts.push(glob::Glob::SyntheticAny);
         }
 
+        if dialect.is_strict() {
+            check_recursive_components(&new_parts, delimiter, dialect)
+                .with_context(|| format!("Parsing pattern {rewritten}"))?;
+        }
+
         debug!(pattern = %raw, parsed = ?new_parts, "parsed pattern");
         let is_complete = new_parts.iter().all(|p| !p.is_recursive());
-        let regex = Regex::new(&Self::build_full_regex(
-            &new_parts,
-            delimiter.chars().next().unwrap(),
-            cross_delim,
-        ))
+        let regex = KeyRegex::new(
+            &Self::build_full_regex(&new_parts, delimiter, cross_delim, false, dialect),
+            delimiter,
+        )
         .unwrap();
         Ok(S3GlobMatcher {
             raw,
-            delimiter: delimiter.chars().next().unwrap(),
+            delimiter: delimiter.to_string(),
             parts: new_parts,
             regex,
             max_parallelism: 500,
@@ -153,9 +258,129 @@ impl S3GlobMatcher {
             probe_max_keys: PROBE_MAX_KEYS,
             is_complete,
             cross_delim,
+            ignore_case: false,
+            dialect,
+            bounds: KeyBounds::default(),
+            sorted: false,
+        })
+    }
+
+    /// Build a matcher from a regex that must match the whole key.
+    ///
+    /// The regex's literal prefixes (see [`regex_prefixes::literal_prefixes`])
+    /// become a single literal part followed by `**`, so prefix discovery and
+    /// the recursive listing work exactly as they do for a glob like
+    /// `{prefix1,prefix2}**`. Keys are then filtered with the regex itself.
+    pub fn from_regex(raw: String, delimiter: &str, ignore_case: bool) -> Result<Self> {
+        let prefixes = regex_prefixes::literal_prefixes(&raw, ignore_case)?;
+        let mut parts = Vec::new();
+        if !prefixes.is_empty() {
+            parts.push(glob::Glob::Choice {
+                raw: raw.clone(),
+                allowed: prefixes,
+            });
+        }
+        parts.push(glob::Glob::Recursive);
+
+        let regex = KeyRegex::verbatim(
+            Regex::new(&format!("{}(?:{raw})$", Self::regex_start(ignore_case)))
+                .with_context(|| format!("Parsing regex {raw}"))?,
+        );
+        debug!(pattern = %raw, parsed = ?parts, "parsed regex pattern");
+        Ok(S3GlobMatcher {
+            raw,
+            delimiter: delimiter.to_string(),
+            parts,
+            regex,
+            max_parallelism: 500,
+            min_prefixes: DESIRED_MIN_PREFIXES,
+            max_prefixes: MAX_PREFIXES,
+            probe_max_keys: PROBE_MAX_KEYS,
+            is_complete: false,
+            cross_delim: false,
+            ignore_case,
+            dialect: GlobDialect::S3glob,
+            bounds: KeyBounds::default(),
+            sorted: false,
         })
     }
 
+    /// The glob that keys are matched against, after the dialect has
+    /// rewritten it
+    pub fn pattern(&self) -> String {
+        self.dialect.rewrite(&self.raw, &self.delimiter)
+    }
+
+    /// The longest literal text that every matching key starts with
+    pub fn literal_prefix(&self) -> String {
+        let Some(glob::Glob::Choice { allowed, .. }) = self.parts.first() else {
+            return String::new();
+        };
+        let mut common = allowed[0].as_str();
+        for alt in &allowed[1..] {
+            let len = common
+                .char_indices()
+                .zip(alt.chars())
+                .take_while(|((_, a), b)| a == b)
+                .last()
+                .map_or(0, |((i, a), _)| i + a.len_utf8());
+            common = &common[..len];
+        }
+        common.to_string()
+    }
+
+    /// Match the pattern case-insensitively.
+    ///
+    /// Literal parts are expanded into all of their case variants when
+    /// there are at most `max_case_variants` of them, so they still narrow
+    /// the prefixes that need to be listed. Literals with more variants than
+    /// that are resolved with a delimiter scan that compares
+    /// case-insensitively (see [`Glob::FoldedChoice`]).
+    pub fn set_ignore_case(&mut self, max_case_variants: usize) {
+        for part in &mut self.parts {
+            part.fold_case(max_case_variants);
+        }
+        self.ignore_case = true;
+        self.regex = KeyRegex::new(
+            &Self::build_full_regex(
+                &self.parts,
+                &self.delimiter,
+                self.cross_delim,
+                self.ignore_case,
+                self.dialect,
+            ),
+            &self.delimiter,
+        )
+        .unwrap();
+        debug!(parsed = ?self.parts, "folded pattern case");
+    }
+
+    /// Only match keys that sort after `start_after` and before `end_before`
+    pub(crate) fn set_key_bounds(
+        &mut self,
+        start_after: Option<String>,
+        end_before: Option<String>,
+    ) {
+        self.bounds = KeyBounds {
+            start_after,
+            end_before,
+        };
+    }
+
+    /// The range of keys that can match, from the pattern and
+    /// [`Self::set_key_bounds`]
+    ///
+    /// Every matching key starts with one of the alternatives of the
+    /// leading literal part, so e.g. `logs/2024-0[6-9]*` can only match
+    /// keys from `logs/2024-06` up to (but not including) `logs/2024-0:`.
+    pub(crate) fn key_bounds(&self) -> KeyBounds {
+        let pattern = match self.parts.first() {
+            Some(glob::Glob::Choice { allowed, .. }) => KeyBounds::of_alternatives(allowed),
+            _ => KeyBounds::default(),
+        };
+        pattern.intersect(&self.bounds)
+    }
+
     // TODO: this should be a constructor argument, but I don't want to change
     // all the tests right now
     pub fn set_max_parallelism(&mut self, max_parallelism: usize) {
@@ -167,29 +392,56 @@ impl S3GlobMatcher {
     /// This is the brute-force oracle equivalent of the prefix-enumeration
     /// algorithm in [`Self::find_prefixes`]: it skips all the S3-listing
     /// optimizations and simply checks the key against the full pattern regex.
-    #[cfg(test)]
     pub(crate) fn matches_key(&self, key: &str) -> bool {
         self.regex.is_match(key)
     }
 
-    fn build_full_regex(parts: &[glob::Glob], delimiter: char, cross_delim: bool) -> String {
-        let mut regex = "^".to_string();
+    fn build_full_regex(
+        parts: &[glob::Glob],
+        delimiter: &str,
+        cross_delim: bool,
+        ignore_case: bool,
+        dialect: GlobDialect,
+    ) -> String {
+        let delim = delimiter_re(delimiter);
+        let mut regex = Self::regex_start(ignore_case).to_string();
         for (i, part) in parts.iter().enumerate() {
+            if dialect.is_strict() {
+                // `**` is always a whole path component here (see
+                // check_recursive_components), so `**/` matches zero or
+                // more complete segments and the part after it starts
+                // with a delimiter that `**/` has already consumed.
+                if part.is_recursive() && i + 1 < parts.len() {
+                    regex.push_str(&format!("(?:.*{delim})?"));
+                } else if i > 0 && parts[i - 1].is_recursive() {
+                    regex.push_str(
+                        &part
+                            .strip_leading(delimiter)
+                            .re_string(delimiter, cross_delim),
+                    );
+                } else {
+                    regex.push_str(&part.re_string(delimiter, cross_delim));
+                }
+                continue;
+            }
             // TODO: This is the existing behavior, should it be kept?
             // the delimiter is optional if the previous part is recursive, so **/*.txt is equivalent to **.txt
-            if i > 0 && parts[i - 1].is_recursive() && part.is(&delimiter.to_string()) {
-                regex.push(delimiter);
-                regex.push('?');
+            if i > 0 && parts[i - 1].is_recursive() && part.is(delimiter) {
+                regex.push_str(&format!("(?:{delim})?"));
             } else {
-                regex.push_str(&part.re_string(&delimiter.to_string(), cross_delim));
+                regex.push_str(&part.re_string(delimiter, cross_delim));
             }
         }
         // prefixes end with the delimiter
-        regex.push(delimiter);
-        regex.push_str("?$");
+        regex.push_str(&format!("(?:{delim})?$"));
         regex
     }
 
+    /// The start of every regex built from this pattern's parts
+    fn regex_start(ignore_case: bool) -> &'static str {
+        if ignore_case { "(?i)^" } else { "^" }
+    }
+
     /// Find all S3 prefixes that could match this pattern
     ///
     /// This method works by incrementally building up prefixes and filtering them based on
@@ -219,23 +471,33 @@ impl S3GlobMatcher {
         mut engine: impl Engine + Clone,
     ) -> Result<PrefixSearchResult> {
         debug!("finding prefixes for {}", self.raw);
+        engine.set_phase(Phase::Discovery);
+        let bounds = self.key_bounds();
+        if !bounds.is_unbounded() {
+            debug!(?bounds, "only listing keys within bounds");
+        }
+        engine.set_bounds(bounds.clone());
         let prefix_progress = progress::get().spinner(progress::prefix_spinner_style());
         let _prefix_cleanup = progress::ClearOnDrop(&prefix_progress);
         let mut prefixes = BTreeSet::new();
         prefixes.insert("".to_string());
         let mut objects: Vec<Object> = Vec::new();
+        let mut ranges = Vec::new();
         let mut objects_updated = false;
-        let delimiter = self.delimiter.to_string();
-        let mut regex_so_far = "^".to_string();
+        let delimiter = self.delimiter.clone();
+        let mut regex_so_far = Self::regex_start(self.ignore_case).to_string();
         let mut prev_part = None;
         let mut part_iter = self.parts.iter().enumerate();
         let mut max_candidate_prefixes = 0;
+        let mut estimated_list_calls = None;
+        let mut cut_off = false;
+        // parts after the first of a leading run of literals, which are
+        // expanded together with it
+        let mut expanded_levels = 0;
         for (part_idx, part) in &mut part_iter {
-            if prefixes.len() >= MAX_PREFIXES {
-                warn!(
-                    new_prefix_count = prefixes.len(),
-                    "We have over {MAX_PREFIXES} prefixes, stopping prefix generation"
-                );
+            if prefixes.len() >= self.max_prefixes {
+                self.report_cut_off(part_idx, prefixes.len());
+                cut_off = true;
                 break;
             }
             max_candidate_prefixes = max_candidate_prefixes.max(prefixes.len());
@@ -257,7 +519,9 @@ impl S3GlobMatcher {
                             &delimiter,
                             initial,
                             &mut objects,
+                            &mut ranges,
                             &mut max_candidate_prefixes,
+                            estimated_list_calls.insert(0),
                         )
                         .await?;
                     max_candidate_prefixes = max_candidate_prefixes.max(prefixes.len());
@@ -328,10 +592,10 @@ impl S3GlobMatcher {
                             objects
                                 .extend(results.objects.into_iter().filter(|o| self.match_obj(o)));
                             new_prefix_count = new_prefixes.len();
-                            if new_prefix_count >= MAX_PREFIXES {
+                            if new_prefix_count >= self.max_prefixes {
                                 debug!(
                                     new_prefix_count,
-                                    "Scanning for any, found more than {MAX_PREFIXES} prefixes, aborting"
+                                    "Scanning for any, found too many prefixes, aborting"
                                 );
                                 for task in tasks {
                                     task.abort();
@@ -339,24 +603,31 @@ impl S3GlobMatcher {
                                 break;
                             }
                         }
-                        // don't overwrite if we gave up early, we'll break at the top of the loop
-                        if new_prefix_count < MAX_PREFIXES {
+                        // keep the prefixes that were scanned if we gave up early
+                        if new_prefix_count < self.max_prefixes {
                             prefixes = new_prefixes;
+                        } else {
+                            self.report_cut_off(part_idx, prefixes.len());
+                            cut_off = true;
+                            break;
                         }
                     }
                     max_candidate_prefixes = max_candidate_prefixes.max(prefixes.len());
                     if part.is_negated() {
                         // if this part is a negated character class then we should filter
-                        let matcher = Regex::new(&format!(
-                            "{regex_so_far}{}",
-                            part.re_string(&self.delimiter.to_string(), self.cross_delim)
-                        ))
+                        let matcher = KeyRegex::new(
+                            &format!(
+                                "{regex_so_far}{}",
+                                part.re_string(&self.delimiter.to_string(), self.cross_delim)
+                            ),
+                            &delimiter,
+                        )
                         .unwrap();
                         debug!(regex = %matcher.as_str(), "filtering for negated Any");
                         prefixes.retain(|p| matcher.is_match(p));
                     }
                 }
-                glob::Glob::Choice { allowed, .. } => {
+                glob::Glob::Choice { allowed, .. } | glob::Glob::FoldedChoice { allowed, .. } => {
                     // In an alternation we need to check for two cases:
                     // - we are verifying that the middle of the path matches
                     //   one of the alternatives -- this is just a regex filter
@@ -372,78 +643,69 @@ impl S3GlobMatcher {
                     //   the delimiter, or the pattern starts with an
                     //   alternation
 
-                    // if there is no previous part
-                    let is_simple_append = part_idx == 0;
-
-                    if is_simple_append {
-                        debug!(allowed = %allowed.join(","), "simple append");
-                        let mut new_prefixes = BTreeSet::new();
-                        for prefix in &prefixes {
-                            for alt in allowed {
-                                new_prefixes.insert(prefix_join(prefix, alt));
-                            }
-                        }
-                        if new_prefixes.len() >= MAX_CHECK_PREFIXES {
-                            debug!(
-                                new_prefix_count = new_prefixes.len(),
-                                "simple append generated over {MAX_CHECK_PREFIXES} prefixes, using prefixes from previous part"
-                            );
+                    if expanded_levels > 0 {
+                        debug!("already expanded with the part before");
+                        expanded_levels -= 1;
+                    } else if part_idx == 0 {
+                        let trie = trie::LiteralTrie::leading(&self.parts);
+                        let expansion = trie
+                            .expand(
+                                &mut engine,
+                                &delimiter,
+                                &bounds,
+                                self.max_prefixes,
+                                self.max_parallelism,
+                                &mut max_candidate_prefixes,
+                            )
+                            .await?;
+                        prefixes = expansion.prefixes;
+                        if expansion.depth < trie.depth() {
+                            self.report_cut_off(expansion.depth, prefixes.len());
+                            cut_off = true;
                             break;
                         }
-                        max_candidate_prefixes = max_candidate_prefixes.max(new_prefixes.len());
-                        prefixes = check_prefixes(
-                            &mut engine,
-                            &prefixes,
-                            new_prefixes,
-                            self.max_parallelism,
-                        )
-                        .await?;
+                        expanded_levels = trie.depth() - 1;
                     } else {
                         // Build up the filters and appends
                         let mut filters = BTreeSet::new();
                         let mut appends = BTreeSet::new();
                         for choice in allowed {
                             // the last part is guaranteed to be an Any,
-                            if choice.starts_with(self.delimiter) {
-                                let c = choice.chars().skip(1).collect::<String>();
+                            if let Some(c) = choice.strip_prefix(&delimiter) {
                                 if !c.is_empty() {
-                                    appends.insert(c);
+                                    appends.insert(c.to_string());
                                 }
-                                filters.insert(self.delimiter.to_string());
-                            } else if choice.contains(self.delimiter) {
-                                let up_to_delim = choice
-                                    .chars()
-                                    .take_while_inclusive(|c| *c != self.delimiter)
-                                    .collect::<String>();
-                                filters.insert(regex::escape(&up_to_delim));
-
-                                let after_delim = choice[up_to_delim.len()..].to_string();
+                                filters.insert(delimiter_re(&delimiter));
+                            } else if let Some(idx) = choice.find(&delimiter) {
+                                let (up_to_delim, after_delim) =
+                                    choice.split_at(idx + delimiter.len());
+                                filters.insert(literal_re(up_to_delim, &delimiter));
+
                                 if !after_delim.is_empty() {
-                                    appends.insert(after_delim);
+                                    appends.insert(after_delim.to_string());
                                 }
                             } else {
-                                filters.insert(regex::escape(choice));
+                                filters.insert(literal_re(choice, &delimiter));
                             }
                         }
 
                         let filter = if filters.is_empty() {
-                            Regex::new(&regex_so_far).unwrap()
+                            regex_so_far.clone()
                         } else if filters.len() == 1 {
-                            Regex::new(&format!(
-                                "{}{}",
-                                regex_so_far,
-                                filters.iter().next().unwrap()
-                            ))
-                            .unwrap()
+                            format!("{}{}", regex_so_far, filters.iter().next().unwrap())
                         } else {
                             let filters = filters.iter().join("|");
-                            Regex::new(&format!("{}({})", regex_so_far.as_str(), filters)).unwrap()
+                            format!("{}({})", regex_so_far.as_str(), filters)
                         };
-                        let append_matcher = Regex::new(&format!(
-                            "{}{}",
-                            regex_so_far,
-                            part.re_string(&delimiter, self.cross_delim)
-                        ))
+                        let filter = KeyRegex::new(&filter, &delimiter).unwrap();
+                        let append_matcher = KeyRegex::new(
+                            &format!(
+                                "{}{}",
+                                regex_so_far,
+                                part.re_string(&delimiter, self.cross_delim)
+                            ),
+                            &delimiter,
+                        )
                         .unwrap();
                         trace!(
                             ?filters, ?appends, filter_regex = %filter.as_str(), append_regex = %append_matcher.as_str(), ?prefixes,
@@ -497,24 +759,47 @@ impl S3GlobMatcher {
 
                         if !appends.is_empty() {
                             debug!("validating appends and filters");
-                            if new_prefixes.len() >= MAX_PREFIXES {
+                            if new_prefixes.len() >= self.max_prefixes {
                                 // checking prefixes is significantly slower
                                 // than scanning existing prefixes.
-                                debug!(
-                                    new_prefix_count = new_prefixes.len(),
-                                    "Appends generated over {MAX_PREFIXES} prefixes, using prefixes from previous part"
-                                );
+                                self.report_cut_off(part_idx, prefixes.len());
+                                cut_off = true;
                                 break;
                             }
                             trace!(new_prefixes = ?new_prefixes, new_prefix_count = new_prefixes.len(), "checking appended prefixes");
                             max_candidate_prefixes = max_candidate_prefixes.max(new_prefixes.len());
-                            let new_prefixes = check_prefixes(
-                                &mut engine,
-                                &prefixes,
-                                new_prefixes,
-                                self.max_parallelism,
-                            )
-                            .await?;
+                            let new_prefixes = if part.is_folded() {
+                                // The appended alternatives can't be checked
+                                // verbatim, so scan below each filtered
+                                // prefix for them instead.
+                                let bases: BTreeSet<String> = prefixes
+                                    .iter()
+                                    .filter(|p| filters.is_empty() || filter.is_match(p))
+                                    .cloned()
+                                    .collect();
+                                let appends = appends.iter().cloned().collect::<Vec<_>>();
+                                let mut found = resolve_folded(
+                                    &engine,
+                                    &bases,
+                                    &appends,
+                                    &delimiter,
+                                    self.max_parallelism,
+                                )
+                                .await?;
+                                found
+                                    .extend(new_prefixes.into_iter().filter(|p| bases.contains(p)));
+                                found
+                            } else {
+                                check_prefixes(
+                                    &mut engine,
+                                    &prefixes,
+                                    new_prefixes,
+                                    &delimiter,
+                                    &bounds,
+                                    self.max_parallelism,
+                                )
+                                .await?
+                            };
                             if new_prefixes.is_empty() {
                                 if objects.is_empty() {
                                     debug!("no prefixes matched and no objects exist");
@@ -566,6 +851,30 @@ impl S3GlobMatcher {
                                 objects_updated = false;
                             }
                         }
+
+                        // the rest of a run of literals that was too big to
+                        // combine with this part
+                        let rest = trie::LiteralTrie::starting_at(&self.parts, part_idx + 1);
+                        if rest.depth() > 0 && !prefixes.is_empty() {
+                            let expansion = rest
+                                .expand_from(
+                                    std::mem::take(&mut prefixes),
+                                    &mut engine,
+                                    &delimiter,
+                                    &bounds,
+                                    self.max_prefixes,
+                                    self.max_parallelism,
+                                    &mut max_candidate_prefixes,
+                                )
+                                .await?;
+                            prefixes = expansion.prefixes;
+                            if expansion.depth < rest.depth() {
+                                self.report_cut_off(part_idx + 1 + expansion.depth, prefixes.len());
+                                cut_off = true;
+                                break;
+                            }
+                            expanded_levels = rest.depth();
+                        }
                     }
                 }
             }
@@ -584,15 +893,15 @@ impl S3GlobMatcher {
         // match the full regex (including the trailing `[delim]?$`). The
         // Any handler scans subprefixes and adds them all without
         // verifying, this is the last place we check.
-        if self.is_complete {
-            prefixes.retain(|p| self.regex.is_match(p));
+        if self.is_complete && !cut_off {
+            prefixes.retain(|p| self.regex.is_match(p) && bounds.may_contain_prefix(p));
         }
-        let prefix_count = prefixes.len();
-        if !self.is_complete && prefix_count < self.min_prefixes {
+        let prefix_count = prefixes.len() + ranges.len();
+        if (!self.is_complete || cut_off) && prefix_count < self.min_prefixes {
             progressln!(
                 "Discovered prefixes: {prefix_count:>5} -- see `s3glob help parallelism` if it feels like this run is too slow"
             );
-        } else if self.is_complete {
+        } else if self.is_complete && !cut_off {
             // For a complete pattern the result is objects found directly
             // during scanning plus candidate prefixes that get_exact will
             // resolve into objects and/or directories.
@@ -604,12 +913,35 @@ impl S3GlobMatcher {
         Ok(PrefixSearchResult {
             prefixes: prefixes.into_iter().collect(),
             objects,
+            ranges,
             max_candidate_prefixes,
+            estimated_list_calls,
+            cut_off,
         })
     }
 
+    /// Tell the user that the pattern has more than `max_prefixes`
+    /// candidate prefixes past `part_idx`, so the `prefix_count` prefixes
+    /// found up to it will be listed in full and filtered instead
+    fn report_cut_off(&self, part_idx: usize, prefix_count: usize) {
+        let glob_so_far = self.parts[..part_idx].iter().map(|p| p.raw()).join("");
+        let past = if glob_so_far.is_empty() {
+            String::new()
+        } else {
+            format!(" past `{glob_so_far}`")
+        };
+        progressln!(
+            "Pattern has over {} candidate prefixes{past}, listing everything under the \
+             {prefix_count} prefixes up to there instead (raise the limit with --max-prefixes)",
+            self.max_prefixes,
+        );
+    }
+
     pub(crate) async fn get_objects<E: Engine + Clone>(&self, engine: E) -> Result<ListResult> {
         let presult = self.find_prefixes(engine.clone()).await?;
+        let mut engine = engine;
+        engine.set_phase(Phase::Listing);
+        engine.set_bounds(self.key_bounds());
         trace!(?presult.prefixes, "matcher generated prefixes");
         debug!(
             prefix_count = presult.prefixes.len(),
@@ -619,30 +951,113 @@ impl S3GlobMatcher {
             total_objects: Arc::new(AtomicUsize::new(0)),
             seen_prefixes: Arc::new(AtomicUsize::new(0)),
         };
-        let total_prefixes = presult.prefixes.len();
+        let total_prefixes = presult.prefixes.len() + presult.ranges.len();
         let max_candidate_prefixes = presult.max_candidate_prefixes;
+        let estimated_list_calls = presult.estimated_list_calls;
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Vec<PrefixResult>>();
         let re = self.regex.clone();
         debug!(regex = %re.as_str(), "full regex");
-        if self.is_complete() {
+        let exact = self.is_complete() && !presult.cut_off;
+        if exact {
+            let presult = self.verify_exact(&engine, presult).await?;
             let permit = Arc::new(Semaphore::new(self.max_parallelism));
             engine
-                .get_exact(presult, self.delimiter, &status, &re, &tx, permit)
+                .get_exact(presult, &self.delimiter, &status, &re, &tx, permit)
                 .await?;
+        } else if self.sorted {
+            let permit = Arc::new(Semaphore::new(self.max_parallelism));
+            ordered::get_all_children(engine, presult, Arc::new(re), status.clone(), tx, permit);
         } else {
             let permit = Arc::new(Semaphore::new(self.max_parallelism));
             engine
                 .get_all_children(presult, Arc::new(re), &status, &tx, permit)
                 .await?;
         }
-        drop(tx);
         Ok(ListResult {
             totals: Totals {
                 total_prefixes,
                 max_candidate_prefixes,
+                estimated_list_calls,
+            },
+            status,
+            rx,
+            sorted: self.sorted && !exact,
+        })
+    }
+
+    /// Resolve the candidates of a complete pattern that share a parent with
+    /// many others by listing the parent, leaving the rest for `get_exact`
+    ///
+    /// Candidates found to be keys move to `objects` and candidates found to
+    /// be directories are replaced by their directory form, which `get_exact`
+    /// passes through without further requests.
+    async fn verify_exact<E: Engine + Clone>(
+        &self,
+        engine: &E,
+        mut presult: PrefixSearchResult,
+    ) -> Result<PrefixSearchResult> {
+        let (verified, candidates): (Vec<_>, Vec<_>) = std::mem::take(&mut presult.prefixes)
+            .into_iter()
+            .filter(|p| !p.is_empty())
+            .partition(|p| p.ends_with(&self.delimiter));
+        let plan = verify::Plan::new(candidates, &self.delimiter);
+        let scanned = verify::scan_parents(
+            engine,
+            &self.key_bounds(),
+            plan.scans,
+            &self.delimiter,
+            self.max_parallelism,
+        )
+        .await?;
+        presult.objects.extend(scanned.objects);
+        presult.prefixes = verified
+            .into_iter()
+            .chain(scanned.directories)
+            .chain(plan.points)
+            .chain(scanned.unresolved)
+            .collect();
+        Ok(presult)
+    }
+
+    /// List every object under `prefixes`, whether or not it matches
+    ///
+    /// Used to download the "directories" that a complete pattern matched.
+    pub(crate) async fn get_all_under<E: Engine>(
+        &self,
+        mut engine: E,
+        prefixes: Vec<String>,
+    ) -> Result<ListResult> {
+        engine.set_phase(Phase::Listing);
+        engine.set_bounds(self.bounds.clone());
+        let status = LiveStatus {
+            total_objects: Arc::new(AtomicUsize::new(0)),
+            seen_prefixes: Arc::new(AtomicUsize::new(0)),
+        };
+        let total_prefixes = prefixes.len();
+        let presult = PrefixSearchResult {
+            prefixes,
+            objects: Vec::new(),
+            ranges: Vec::new(),
+            max_candidate_prefixes: total_prefixes,
+            estimated_list_calls: None,
+            cut_off: false,
+        };
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Vec<PrefixResult>>();
+        let everything = KeyRegex::verbatim(Regex::new("").expect("empty regex is valid"));
+        let permit = Arc::new(Semaphore::new(self.max_parallelism));
+        engine
+            .get_all_children(presult, Arc::new(everything), &status, &tx, permit)
+            .await?;
+        drop(tx);
+        Ok(ListResult {
+            totals: Totals {
+                total_prefixes,
+                max_candidate_prefixes: total_prefixes,
+                estimated_list_calls: None,
             },
             status,
             rx,
+            sorted: false,
         })
     }
 
@@ -659,35 +1074,58 @@ impl S3GlobMatcher {
     /// Bounded BFS prefix expansion at a `**` glob component.
     ///
     /// Expands `initial` one directory level at a time until we reach
-    /// `min_prefixes`, exhaust the frontier, or hit `max_prefixes`.
-    /// Returns the final settled-plus-frontier set; any objects
-    /// resolved along the way are appended to `objects`.
+    /// `min_prefixes`, exhaust the frontier, run out of prefixes that are
+    /// worth expanding, or hit `max_prefixes`. Returns the final
+    /// settled-plus-frontier set; any objects resolved along the way are
+    /// appended to `objects`, and flat-dense prefixes that could be
+    /// partitioned are appended to `ranges` instead of being settled.
+    /// `estimated_list_calls` is set to the LIST requests the expansion and
+    /// the listing after it should take, see [`cost`].
     ///
     /// Each round runs two phases per frontier prefix:
     ///   1. Probe with a single delimiter-less LIST (max_keys =
     ///      probe_max_keys). Non-truncated probes mean the prefix's
     ///      full content fits in one call; matching keys go to
     ///      `objects` and the prefix is dropped. Truncated probes
-    ///      queue the prefix for phase 2.
+    ///      queue the prefix for phase 2, unless what the probe saw of
+    ///      its sub-prefixes and the fan-out of the level above say that
+    ///      they are too small to list one by one, in which case the
+    ///      prefix is split into key ranges instead.
     ///   2. Scan with delimiter and a max-prefixes cap. Truncated
-    ///      scans (cap hit or flat-dense parent) settle the parent
-    ///      for `get_all_children`. Leaf scans (truncated=false, no
+    ///      scans settle the parent for `get_all_children`, unless the
+    ///      parent is flat-dense (no sub-prefixes), in which case it is
+    ///      split into key ranges (see [`key_ranges::partition`]). Leaf scans (truncated=false, no
     ///      sub-prefixes) forward their complete content like phase 1
     ///      and drop the prefix. Branch scans replace the parent
     ///      with sub-prefixes and carry direct objects forward.
+    #[allow(clippy::too_many_arguments)]
     async fn expand_recursive_frontier<E: Engine + Clone>(
         &self,
         engine: &E,
         delimiter: &str,
         initial: BTreeSet<String>,
         objects: &mut Vec<Object>,
+        ranges: &mut Vec<KeyRange>,
         max_candidate_prefixes: &mut usize,
+        estimated_list_calls: &mut usize,
     ) -> Result<BTreeSet<String>> {
+        let mut engine = engine.clone();
+        engine.set_phase(Phase::Expansion);
+        let engine = &engine;
         let probe_max_keys = self.probe_max_keys;
         let mut settled: BTreeSet<String> = BTreeSet::new();
         let mut frontier = initial;
-
-        while settled.len() + frontier.len() < self.min_prefixes && !frontier.is_empty() {
+        // LIST requests made or expected so far, and what listing each
+        // prefix in the frontier is expected to take
+        let mut estimated = 0.0;
+        let mut frontier_lists: BTreeMap<String, f64> = BTreeMap::new();
+        // the average number of sub-prefixes under the prefixes of the
+        // previous level
+        let mut fan_out = None;
+
+        while settled.len() + ranges.len() + frontier.len() < self.min_prefixes
+            && !frontier.is_empty()
+        {
             debug!(
                 settled_count = settled.len(),
                 frontier_count = frontier.len(),
@@ -704,19 +1142,49 @@ impl S3GlobMatcher {
                 })
                 .await
             };
+            estimated += frontier.len() as f64;
+            frontier_lists.clear();
 
             let mut to_scan = BTreeSet::new();
+            // the start of each dense prefix, in case it needs partitioning
+            let mut first_keys = BTreeMap::new();
+            let mut estimates = BTreeMap::new();
+            let mut flat_dense = Vec::new();
             for (prefix, result) in probe_results {
                 let probe = result.context("probing prefix at **")?;
                 if probe.truncated {
-                    to_scan.insert(prefix);
+                    let density = cost::Density::of_probe(&prefix, delimiter, &probe);
+                    let estimate = density.estimate(probe_max_keys as usize, fan_out);
+                    if let Some(first) = probe.objects.into_iter().find_map(|o| o.key) {
+                        first_keys.insert(prefix.clone(), first);
+                    }
+                    match estimate {
+                        Some(estimate) if !estimate.pays_off() => {
+                            debug!(
+                                %prefix,
+                                ?density,
+                                serial_lists = estimate.serial,
+                                expanded_lists = estimate.expanded(),
+                                "sub-prefixes are too small to list one by one, splitting into key ranges"
+                            );
+                            flat_dense.push(prefix.clone());
+                        }
+                        // with no sub-prefixes in the first page, only a
+                        // scan can tell if there are any
+                        _ => {
+                            to_scan.insert(prefix.clone());
+                        }
+                    }
+                    if let Some(estimate) = estimate {
+                        estimates.insert(prefix, estimate);
+                    }
                 } else {
                     // Sparse prefix: probe returned complete content.
                     objects.extend(probe.objects.into_iter().filter(|o| self.match_obj(o)));
                 }
             }
 
-            if to_scan.is_empty() {
+            if to_scan.is_empty() && flat_dense.is_empty() {
                 debug!("all frontier prefixes resolved by probe, stopping");
                 frontier.clear();
                 break;
@@ -741,9 +1209,16 @@ impl S3GlobMatcher {
 
             let mut new_frontier = BTreeSet::new();
             let mut made_progress = false;
+            let mut branch_count = 0;
             for (prefix, result) in scan_results {
                 let scan_result = result.context("expanding prefixes at **")?;
-                if scan_result.truncated {
+                let estimate = estimates.get(&prefix);
+                estimated += estimate.map_or(1.0, |e| e.scan);
+                if scan_result.truncated && scan_result.prefixes.is_empty() {
+                    // Lots of keys and no sub-directories to spread the
+                    // listing over, split the keys themselves instead.
+                    flat_dense.push(prefix);
+                } else if scan_result.truncated {
                     // Cap hit (too wide) or page-budget exhausted
                     // looking for non-existent sub-prefixes. Either
                     // way, parallel expansion isn't worth it; hand the
@@ -753,6 +1228,7 @@ impl S3GlobMatcher {
                         sub_prefix_count = scan_result.prefixes.len(),
                         "scan truncated, falling back to parent listing"
                     );
+                    estimated += estimate.map_or(1.0, |e| e.serial);
                     settled.insert(prefix);
                 } else if scan_result.prefixes.is_empty() {
                     // True leaf with complete content: forward objects
@@ -766,6 +1242,11 @@ impl S3GlobMatcher {
                     );
                 } else {
                     made_progress = true;
+                    branch_count += 1;
+                    let per_child = estimate.map_or(1.0, |e| e.per_child);
+                    for child in &scan_result.prefixes {
+                        frontier_lists.insert(child.clone(), per_child);
+                    }
                     new_frontier.extend(scan_result.prefixes);
                     // Direct objects at a branch prefix would be lost
                     // when we drop the parent in favor of its children.
@@ -778,6 +1259,64 @@ impl S3GlobMatcher {
                     );
                 }
             }
+            fan_out = (branch_count > 0).then(|| new_frontier.len() as f64 / branch_count as f64);
+
+            let mut to_partition = BTreeSet::new();
+            for prefix in flat_dense {
+                if first_keys.contains_key(&prefix) {
+                    to_partition.insert(prefix);
+                } else {
+                    estimated += estimates.get(&prefix).map_or(1.0, |e| e.serial);
+                    settled.insert(prefix);
+                }
+            }
+            // each partition probes in parallel too, so they share the limit
+            let partition_parallelism = (self.max_parallelism / to_partition.len().max(1)).max(1);
+            let partitions = {
+                let engine = engine.clone();
+                let target = self.min_prefixes;
+                fan_out_per_prefix(&to_partition, self.max_parallelism, |prefix| {
+                    let engine = engine.clone();
+                    let first = first_keys
+                        .remove(&prefix)
+                        .expect("only prefixes with a first key are partitioned");
+                    async move {
+                        key_ranges::partition(
+                            &engine,
+                            &prefix,
+                            first,
+                            target,
+                            partition_parallelism,
+                        )
+                        .await
+                    }
+                })
+                .await
+            };
+            for (prefix, parts) in partitions {
+                let parts = parts?;
+                let serial = estimates.get(&prefix).map_or(1.0, |e| e.serial);
+                if parts.len() > 1 {
+                    debug!(
+                        %prefix,
+                        range_count = parts.len(),
+                        "flat-dense prefix, listing key ranges in parallel"
+                    );
+                    // about a probe per split, and the same pages as a
+                    // serial listing but at least one per range
+                    estimated += (parts.len() - 1) as f64 + serial.max(parts.len() as f64);
+                    ranges.extend(parts);
+                } else {
+                    debug!(%prefix, "flat-dense prefix could not be split, falling back to parent listing");
+                    estimated += serial;
+                    settled.insert(prefix);
+                }
+            }
+            // listed in order of where they start, whichever partition
+            // finished first
+            ranges.sort_by(|a, b| {
+                (&a.prefix, &a.bounds.start_after).cmp(&(&b.prefix, &b.bounds.start_after))
+            });
 
             if !made_progress {
                 debug!("no sub-directories found during ** expansion, stopping");
@@ -785,7 +1324,7 @@ impl S3GlobMatcher {
                 break;
             }
 
-            let total_after = settled.len() + new_frontier.len();
+            let total_after = settled.len() + ranges.len() + new_frontier.len();
             *max_candidate_prefixes = (*max_candidate_prefixes).max(total_after);
 
             if total_after >= self.max_prefixes {
@@ -801,6 +1340,15 @@ impl S3GlobMatcher {
             frontier = new_frontier;
         }
 
+        estimated += frontier
+            .iter()
+            .map(|p| frontier_lists.get(p).copied().unwrap_or(1.0))
+            .sum::<f64>();
+        *estimated_list_calls = estimated.round() as usize;
+        debug!(
+            estimated_list_calls = *estimated_list_calls,
+            "estimated LIST requests for ** expansion and listing"
+        );
         Ok(settled.into_iter().chain(frontier).collect())
     }
 
@@ -820,10 +1368,50 @@ impl S3GlobMatcher {
         self.probe_max_keys = probe_max_keys;
     }
 
-    #[cfg(test)]
-    fn set_max_prefixes(&mut self, max_prefixes: usize) {
+    /// Set the most candidate prefixes a pattern may generate
+    ///
+    /// Past this the search stops and the prefixes found so far are listed
+    /// in full, see [`PrefixSearchResult::cut_off`].
+    pub fn set_max_prefixes(&mut self, max_prefixes: usize) {
         self.max_prefixes = max_prefixes;
     }
+
+    /// Send the keys `get_objects` lists in key order, see [`ordered`]
+    ///
+    /// Doesn't apply to patterns without a `**`, whose results are few and
+    /// arrive in any order, see [`ListResult::sorted`].
+    pub fn set_sorted(&mut self, sorted: bool) {
+        self.sorted = sorted;
+    }
+}
+
+/// Check that every `**` in `parts` is a whole path component
+///
+/// That is, `**` is at the start of the pattern or directly after a
+/// delimiter, and at the end of the pattern or directly before one.
+fn check_recursive_components(
+    parts: &[glob::Glob],
+    delimiter: &str,
+    dialect: GlobDialect,
+) -> Result<()> {
+    for (i, part) in parts.iter().enumerate() {
+        if !part.is_recursive() {
+            continue;
+        }
+        let after_delim = i == 0 || parts[i - 1].all_end_with(delimiter);
+        let before_delim = match parts.get(i + 1) {
+            None => true,
+            Some(next) => next.all_start_with(delimiter),
+        };
+        if !after_delim || !before_delim {
+            let dialect = format!("{dialect:?}").to_lowercase();
+            anyhow::bail!(
+                "`**` must be a whole path component in the {dialect} dialect \
+                 (e.g. `a/**/b`), use `*` to match within a single component"
+            );
+        }
+    }
+    Ok(())
 }
 
 /// Run `make` over each prefix in parallel, bounded by
@@ -866,19 +1454,34 @@ where
     out
 }
 
+/// Keep the `new_prefixes` that some key in the bucket starts with
+///
+/// Parents with many candidates are listed once instead of checking each
+/// candidate, see [`verify`].
 async fn check_prefixes(
     engine: &mut (impl Engine + Clone),
     prefixes: &BTreeSet<String>,
     new_prefixes: BTreeSet<String>,
+    delimiter: &str,
+    bounds: &KeyBounds,
     max_parallelism: usize,
 ) -> Result<BTreeSet<String>, anyhow::Error> {
     if prefixes.is_empty() || new_prefixes.is_empty() {
         debug!("check_prefixes called with no prefixes to build off of or none to check");
         return Ok(BTreeSet::new());
     }
-    let checked_prefixes = engine
-        .check_prefixes(new_prefixes.clone(), max_parallelism)
-        .await?;
+    let plan = verify::Plan::new(new_prefixes.iter().cloned(), delimiter);
+    let scanned =
+        verify::scan_parents(engine, bounds, plan.scans, delimiter, max_parallelism).await?;
+    let mut checked_prefixes = scanned.existing;
+    let points = plan
+        .points
+        .into_iter()
+        .chain(scanned.unresolved)
+        .collect::<BTreeSet<_>>();
+    if !points.is_empty() {
+        checked_prefixes.extend(engine.check_prefixes(points, max_parallelism).await?);
+    }
     if checked_prefixes.is_empty() {
         let mut message = vec!["Searched for prefixes do not exist in the bucket:".to_string()];
         let new_prefixes = new_prefixes.iter().collect::<Vec<_>>();
@@ -904,6 +1507,114 @@ async fn check_prefixes(
     Ok(checked_prefixes)
 }
 
+/// Find the spellings of `prefix + alt` that exist in the bucket, comparing
+/// each alternative case-insensitively.
+///
+/// Works down one delimiter level per round: every pending prefix is
+/// scanned with the delimiter, and each child that is consistent with one of
+/// its remaining alternatives either completes the alternative (and the
+/// matching part of the child becomes a result) or, if the child is a
+/// "directory" covering only part of the alternative, is scanned in the
+/// next round for the rest of it.
+async fn resolve_folded<E: Engine + Clone>(
+    engine: &E,
+    prefixes: &BTreeSet<String>,
+    allowed: &[String],
+    delimiter: &str,
+    max_parallelism: usize,
+) -> Result<BTreeSet<String>> {
+    let mut found = BTreeSet::new();
+    let mut pending: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for prefix in prefixes {
+        for alt in allowed {
+            if alt.is_empty() {
+                found.insert(prefix.clone());
+            } else {
+                pending
+                    .entry(prefix.clone())
+                    .or_default()
+                    .insert(alt.clone());
+            }
+        }
+    }
+
+    let mut rounds = 0;
+    while !pending.is_empty() {
+        rounds += 1;
+        let to_scan: BTreeSet<String> = pending.keys().cloned().collect();
+        let scan_results = {
+            let engine = engine.clone();
+            let delimiter = delimiter.to_string();
+            fan_out_per_prefix(&to_scan, max_parallelism, move |prefix| {
+                let mut engine = engine.clone();
+                let delimiter = delimiter.clone();
+                async move { engine.scan_prefixes(&prefix, &delimiter, None).await }
+            })
+            .await
+        };
+
+        let mut next: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (prefix, result) in scan_results {
+            let scan = result.context("scanning for case-insensitive match")?;
+            let alts = &pending[&prefix];
+            let children = scan.prefixes.iter().map(|p| (p.as_str(), true)).chain(
+                scan.objects
+                    .iter()
+                    .filter_map(|o| o.key())
+                    .map(|k| (k, false)),
+            );
+            for (child, is_dir) in children {
+                let Some(tail) = child.strip_prefix(prefix.as_str()) else {
+                    continue;
+                };
+                for alt in alts {
+                    match fold_prefix(tail, alt) {
+                        FoldMatch::Full(len) => {
+                            found.insert(prefix_join(&prefix, &tail[..len]));
+                        }
+                        FoldMatch::Partial(rest) if is_dir => {
+                            next.entry(child.to_string())
+                                .or_default()
+                                .insert(rest.to_string());
+                        }
+                        FoldMatch::Partial(_) | FoldMatch::Mismatch => {}
+                    }
+                }
+            }
+        }
+        pending = next;
+    }
+    debug!(
+        rounds,
+        found_count = found.len(),
+        "resolved case-insensitive alternatives"
+    );
+    Ok(found)
+}
+
+enum FoldMatch<'a> {
+    /// The whole alternative matched the first `.0` bytes of the key
+    Full(usize),
+    /// The key is a case-insensitive prefix of the alternative, `.0` is
+    /// the unmatched rest of the alternative
+    Partial(&'a str),
+    Mismatch,
+}
+
+/// Compare the start of `key` with `alt`, ignoring case
+fn fold_prefix<'a>(key: &str, alt: &'a str) -> FoldMatch<'a> {
+    let mut key_chars = key.char_indices();
+    for (alt_idx, alt_char) in alt.char_indices() {
+        match key_chars.next() {
+            Some((_, key_char))
+                if key_char == alt_char || key_char.to_lowercase().eq(alt_char.to_lowercase()) => {}
+            Some(_) => return FoldMatch::Mismatch,
+            None => return FoldMatch::Partial(&alt[alt_idx..]),
+        }
+    }
+    FoldMatch::Full(key_chars.next().map_or(key.len(), |(idx, _)| idx))
+}
+
 fn prefix_join(prefix: &str, alt: &str) -> String {
     format!("{prefix}{alt}")
 }
@@ -913,19 +1624,38 @@ fn prefix_join(prefix: &str, alt: &str) -> String {
 pub(crate) enum PrefixResult {
     Object(S3Object),
     Prefix(String),
+    /// A prefix that could not be (fully) listed, so matches under it may
+    /// be missing from the results
+    Failed(ListFailure),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ListFailure {
+    pub(crate) prefix: String,
+    pub(crate) error: String,
 }
 
 impl PrefixResult {
+    pub(crate) fn failed(prefix: &str, error: impl Into<anyhow::Error>) -> Self {
+        Self::Failed(ListFailure {
+            prefix: prefix.to_owned(),
+            // include the source chain, the SDK's top level message is
+            // usually just "service error"
+            error: format!("{:#}", error.into()),
+        })
+    }
     pub(crate) fn kind(&self) -> String {
         match self {
             Self::Object(_) => "OBJ".to_owned(),
             Self::Prefix(_) => "PRE".to_owned(),
+            Self::Failed(_) => "ERR".to_owned(),
         }
     }
     pub(crate) fn key(&self) -> String {
         match self {
             Self::Object(obj) => obj.key.clone(),
             Self::Prefix(prefix) => prefix.clone(),
+            Self::Failed(failure) => failure.prefix.clone(),
         }
     }
 }
@@ -934,14 +1664,19 @@ pub(crate) struct ListResult {
     pub(crate) status: LiveStatus,
     pub(crate) totals: Totals,
     pub(crate) rx: UnboundedReceiver<Vec<PrefixResult>>,
+    /// True if `rx` yields results in key order
+    pub(crate) sorted: bool,
 }
 
 pub(crate) struct Totals {
     pub(crate) total_prefixes: usize,
     /// Carried through from [`PrefixSearchResult::max_candidate_prefixes`].
     pub(crate) max_candidate_prefixes: usize,
+    /// Carried through from [`PrefixSearchResult::estimated_list_calls`].
+    pub(crate) estimated_list_calls: Option<usize>,
 }
 
+#[derive(Clone)]
 pub(crate) struct LiveStatus {
     pub(crate) total_objects: Arc<AtomicUsize>,
     pub(crate) seen_prefixes: Arc<AtomicUsize>,
@@ -950,6 +1685,7 @@ pub(crate) struct LiveStatus {
 #[cfg(test)]
 mod tests {
     use assert2::{assert, check};
+    use rstest::rstest;
 
     use super::*;
     use crate::glob_matcher::engine::MockS3Engine;
@@ -1263,40 +1999,421 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_objects_recursive_no_duplicate_emissions_with_mixed_outcomes() -> Result<()> {
+    async fn test_get_objects_backs_off_when_throttled() -> Result<()> {
         setup_logging(Some("s3glob=trace"));
-        // Regression guard: across rounds of `**` expansion with mixed
-        // leaf/branch/truncated outcomes, the final prefix set must
-        // not contain both an ancestor and one of its descendants —
-        // otherwise `get_all_children` would double-list everything
-        // under the descendant.
-        //
-        // Scenario: `{a,b,c}/**/*.rs` pre-populates the initial frontier
-        // with three sibling prefixes so we exercise the BFS without
-        // depending on round-1 src/ scan behavior. With probe_max_keys=1
-        // and ≥2 keys per prefix every probe truncates; cap=3 so:
-        //   Round 1: a/ → leaf (forward 2 objects);
-        //            b/ → 1 sub-dir (branch, new_frontier += b/x/);
-        //            c/ → 4 sub-dirs (> cap) → truncated (settled).
-        //   Round 2: b/x/ → leaf (forward 2 objects); break.
-        //   Final: settled={c/}, objects=[a/*, b/x/*]. Only c/ remains
-        //   in prefixes — neither it nor its descendants overlap with
-        //   anything else in the result.
-        let mut scanner = S3GlobMatcher::parse("{a,b,c}/**/*.rs".to_string(), "/", false)?;
-        scanner.set_min_prefixes(10);
-        scanner.set_probe_max_keys(1);
-        scanner.set_max_parallelism(3);
-
-        let paths = vec![
-            "a/file1.rs".to_string(),
-            "a/file2.rs".to_string(),
-            "b/x/file1.rs".to_string(),
-            "b/x/file2.rs".to_string(),
-            "c/p/f.rs".to_string(),
-            "c/q/f.rs".to_string(),
-            "c/r/f.rs".to_string(),
-            "c/s/f.rs".to_string(),
-        ];
+        let scanner = S3GlobMatcher::parse("src/*/*.rs".to_string(), "/", false)?;
+        let engine = MockS3Engine::new(vec![
+            "src/bar/test.rs".to_string(),
+            "src/foo/test.rs".to_string(),
+            "src/foo/skip.txt".to_string(),
+        ])
+        .with_throttling(3);
+        let limiter = engine.limiter.clone();
+
+        let mut result = scanner.get_objects(engine).await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+
+        // throttled requests are retried, so nothing goes missing
+        assert!(keys == ["src/bar/test.rs", "src/foo/test.rs"]);
+        assert!(limiter.throttles() == 3);
+        assert!(limiter.limit() < 64, "limit is {}", limiter.limit());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_counts_calls_by_phase() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner = S3GlobMatcher::parse("src/**/*.rs".to_string(), "/", false)?;
+        scanner.set_min_prefixes(3);
+        scanner.set_probe_max_keys(1);
+        let engine = MockS3Engine::new(vec![
+            "src/bar/test.rs".to_string(),
+            "src/baz/test.rs".to_string(),
+            "src/foo/test.rs".to_string(),
+        ]);
+        let calls = engine.api_calls.clone();
+
+        let mut result = scanner.get_objects(engine).await?;
+        while result.rx.recv().await.is_some() {}
+
+        let stats = calls.stats();
+        // checking that src/ exists, one probe and one scan of it, then one
+        // listing per sub-directory
+        assert!(stats.discovery.list_objects_v2 == 1, "{stats:?}");
+        assert!(stats.expansion.list_objects_v2 == 2, "{stats:?}");
+        assert!(stats.listing.list_objects_v2 == 3, "{stats:?}");
+        assert!(stats.total().list_objects_v2 == 6, "{stats:?}");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::character_class(
+        "logs/2024-0[6-9]*",
+        Some("logs/2024-05\u{10ffff}"),
+        Some("logs/2024-0:")
+    )]
+    #[case::alternation("{foo,bar}/**", Some("bar.\u{10ffff}"), Some("foo0"))]
+    #[case::leading_any("*/2024", None, None)]
+    fn test_key_bounds_from_pattern(
+        #[case] pattern: &str,
+        #[case] start_after: Option<&str>,
+        #[case] end_before: Option<&str>,
+    ) -> Result<()> {
+        let scanner = S3GlobMatcher::parse(pattern.to_string(), "/", false)?;
+        let bounds = scanner.key_bounds();
+        check!(bounds.start_after.as_deref() == start_after);
+        check!(bounds.end_before.as_deref() == end_before);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_within_user_key_bounds() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner = S3GlobMatcher::parse("logs/**".to_string(), "/", false)?;
+        scanner.set_min_prefixes(0);
+        scanner.set_key_bounds(
+            Some("logs/2024-06".to_string()),
+            Some("logs/2024-08".to_string()),
+        );
+        let engine = MockS3Engine::new(vec![
+            "logs/2024-05-31.json".to_string(),
+            "logs/2024-06-01.json".to_string(),
+            "logs/2024-07-15.json".to_string(),
+            "logs/2024-08-01.json".to_string(),
+            "logs/2024-09-30.json".to_string(),
+        ]);
+
+        let mut result = scanner.get_objects(engine).await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+        assert!(keys == ["logs/2024-06-01.json", "logs/2024-07-15.json"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_scans_within_key_bounds() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        // The bounds keep the scan for `*` from returning "directories"
+        // that can't contain a match
+        let mut scanner = S3GlobMatcher::parse("logs/*/*.json".to_string(), "/", false)?;
+        scanner.set_key_bounds(Some("logs/b".to_string()), Some("logs/d".to_string()));
+        let engine = MockS3Engine::new(vec![
+            "logs/a/1.json".to_string(),
+            "logs/b/1.json".to_string(),
+            "logs/c/1.json".to_string(),
+            "logs/d/1.json".to_string(),
+        ]);
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        let mut keys: Vec<&str> = presult.objects.iter().filter_map(|o| o.key()).collect();
+        keys.sort();
+        assert!(keys == ["logs/b/1.json", "logs/c/1.json"]);
+        engine.assert_call_set(&[("logs/", "/"), ("logs/b/", "/"), ("logs/c/", "/")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_verifies_many_candidates_with_one_scan() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let alternatives = (0..20).map(|i| format!("d{i:02}")).join(",");
+        let scanner = S3GlobMatcher::parse(format!("data/{{{alternatives}}}/*.txt"), "/", false)?;
+        let engine = MockS3Engine::new(vec![
+            "data/d03/a.txt".to_string(),
+            "data/d07/b.txt".to_string(),
+            "data/d25/c.txt".to_string(),
+        ]);
+        let calls = engine.api_calls.clone();
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        let mut keys: Vec<&str> = presult.objects.iter().filter_map(|o| o.key()).collect();
+        keys.sort();
+        assert!(keys == ["data/d03/a.txt", "data/d07/b.txt"]);
+        // one scan of data/ instead of a check per candidate, then one scan
+        // per directory that exists for the `*`
+        engine.assert_call_set(&[("data/", "/"), ("data/d03/", "/"), ("data/d07/", "/")]);
+        assert!(calls.stats().discovery.list_objects_v2 == 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_verifies_many_exact_keys_with_one_scan() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let alternatives = (0..20).map(|i| format!("k{i:02}")).join(",");
+        let scanner = S3GlobMatcher::parse(format!("data/{{{alternatives}}}"), "/", false)?;
+        // enough of the candidates exist that they are still worth a scan
+        // of their parent after discovery
+        let mut paths = (0..18).map(|i| format!("data/k{i:02}")).collect::<Vec<_>>();
+        paths.push("data/k18/inner.txt".to_string());
+        paths.push("data/k99".to_string());
+        let engine = MockS3Engine::new(paths);
+        let calls = engine.api_calls.clone();
+
+        let mut result = scanner.get_objects(engine).await?;
+        let mut found: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            found.extend(
+                batch
+                    .into_iter()
+                    .map(|r| format!("{} {}", r.kind(), r.key())),
+            );
+        }
+        found.sort();
+        let mut expected = (0..18)
+            .map(|i| format!("OBJ data/k{i:02}"))
+            .collect::<Vec<_>>();
+        expected.push("PRE data/k18/".to_string());
+        assert!(found == expected);
+        // the scan of data/ found the keys and the directory, so nothing is
+        // left to HEAD
+        let stats = calls.stats();
+        assert!(stats.listing.list_objects_v2 == 1, "{stats:?}");
+        assert!(stats.listing.head_object == 0, "{stats:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_keeps_large_leading_run_of_literals_apart() -> Result<()> {
+        let scanner = S3GlobMatcher::parse("[a-z][a-z][a-z][0-9]/x/*".to_string(), "/", false)?;
+        let lens = scanner
+            .parts
+            .iter()
+            .map(|p| match p {
+                Glob::Choice { allowed, .. } => allowed.len(),
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        // [a-z][a-z] is combined, the next [a-z] would take it over
+        // MAX_CHECK_PREFIXES so it starts a new level with the rest
+        assert!(lens == [676, 260, 0]);
+        assert!(scanner.matches_key("abc1/x/data.txt"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_expands_large_leading_run_level_by_level() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let scanner = S3GlobMatcher::parse("[a-z][a-z][a-z][0-9]/x/*.txt".to_string(), "/", false)?;
+        let engine = MockS3Engine::new(vec![
+            "abc1/x/data.txt".to_string(),
+            "abd1/y/skip.txt".to_string(),
+            "zzz9/x/other.txt".to_string(),
+        ]);
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        let mut keys: Vec<&str> = presult.objects.iter().filter_map(|o| o.key()).collect();
+        keys.sort();
+        assert!(keys == ["abc1/x/data.txt", "zzz9/x/other.txt"]);
+        assert!(!presult.cut_off);
+        // only the two-letter prefixes that exist were expanded, never all
+        // 175,760 combinations
+        assert!(presult.max_candidate_prefixes == 676);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_expands_large_run_after_a_wildcard_level_by_level() -> Result<()> {
+        setup_logging(Some("s3glob=debug"));
+        let scanner = S3GlobMatcher::parse(
+            "logs/*/[a-z][a-z][a-z][0-9]/x/*.txt".to_string(),
+            "/",
+            false,
+        )?;
+        // combined, the run would be 175,760 alternatives for each directory
+        let lens = scanner.parts[2..4]
+            .iter()
+            .map(|p| match p {
+                Glob::Choice { allowed, .. } => allowed.len(),
+                other => panic!("expected a Choice, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert!(lens == [676, 260]);
+        let engine = MockS3Engine::new(vec![
+            "logs/p/abc1/x/data.txt".to_string(),
+            "logs/p/abd1/y/skip.txt".to_string(),
+            "logs/q/zzz9/x/other.txt".to_string(),
+        ]);
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        assert!(!presult.cut_off);
+        let mut keys: Vec<&str> = presult.objects.iter().filter_map(|o| o.key()).collect();
+        keys.sort();
+        assert!(keys == ["logs/p/abc1/x/data.txt", "logs/q/zzz9/x/other.txt"]);
+        // the two-letter level for both directories, never the whole product
+        assert!(presult.max_candidate_prefixes == 2 * 676);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_lists_everything_past_max_prefixes() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner =
+            S3GlobMatcher::parse("[a-z][a-z][a-z][0-9]/x/data.txt".to_string(), "/", false)?;
+        scanner.set_max_prefixes(100);
+        let engine = MockS3Engine::new(vec![
+            "abc1/x/data.txt".to_string(),
+            "abc1/x/other.txt".to_string(),
+            "zzz9/y/data.txt".to_string(),
+        ]);
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        assert!(presult.cut_off);
+        assert!(presult.prefixes == [""]);
+
+        let mut result = scanner.get_objects(engine).await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        assert!(keys == ["abc1/x/data.txt"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_all_under_matched_prefixes() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let paths = vec![
+            "data/2023-12/z.csv".to_string(),
+            "data/2024-01/a.csv".to_string(),
+            "data/2024-01/nested/b.csv".to_string(),
+            "data/2024-02/c.csv".to_string(),
+            "data/2024-notes.txt".to_string(),
+        ];
+        let scanner = S3GlobMatcher::parse("data/2024-*".to_string(), "/", false)?;
+        assert!(scanner.is_complete());
+        let mut result = scanner
+            .get_objects(MockS3Engine::new(paths.clone()))
+            .await?;
+        let mut prefixes = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            for r in batch {
+                if let PrefixResult::Prefix(prefix) = r {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+        prefixes.sort();
+        assert!(prefixes == ["data/2024-01/", "data/2024-02/"]);
+
+        let mut result = scanner
+            .get_all_under(MockS3Engine::new(paths), prefixes)
+            .await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+        assert!(
+            keys == [
+                "data/2024-01/a.csv",
+                "data/2024-01/nested/b.csv",
+                "data/2024-02/c.csv"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_regex() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let scanner = S3GlobMatcher::from_regex(
+            r"events/(2023|2024)-0[12]/.*\.parquet".to_string(),
+            "/",
+            false,
+        )?;
+        assert_scanner_part!(
+            &scanner.parts[0],
+            Choice(vec![
+                "events/2023-01/",
+                "events/2023-02/",
+                "events/2024-01/",
+                "events/2024-02/"
+            ])
+        );
+        assert!(scanner.parts[1].is_recursive());
+        assert!(scanner.literal_prefix() == "events/202");
+
+        let engine = MockS3Engine::new(vec![
+            "events/2022-01/d.parquet".to_string(),
+            "events/2023-01/a.parquet".to_string(),
+            "events/2023-03/b.parquet".to_string(),
+            "events/2024-02/c.csv".to_string(),
+            "events/2024-02/x/c.parquet".to_string(),
+            "other/2023-01/e.parquet".to_string(),
+        ]);
+        let mut result = scanner.get_objects(engine).await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+        assert!(keys == ["events/2023-01/a.parquet", "events/2024-02/x/c.parquet"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_regex_without_literal_prefix() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let scanner = S3GlobMatcher::from_regex(r".*/[ab]\.txt".to_string(), "/", true)?;
+        assert!(scanner.parts.len() == 1 && scanner.parts[0].is_recursive());
+        assert!(scanner.literal_prefix().is_empty());
+
+        let engine = MockS3Engine::new(vec![
+            "x/A.txt".to_string(),
+            "y/z/b.txt".to_string(),
+            "c.txt".to_string(),
+        ]);
+        let mut result = scanner.get_objects(engine).await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+        assert!(keys == ["x/A.txt", "y/z/b.txt"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_recursive_no_duplicate_emissions_with_mixed_outcomes() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        // Regression guard: across rounds of `**` expansion with mixed
+        // leaf/branch/truncated outcomes, the final prefix set must
+        // not contain both an ancestor and one of its descendants —
+        // otherwise `get_all_children` would double-list everything
+        // under the descendant.
+        //
+        // Scenario: `{a,b,c}/**/*.rs` pre-populates the initial frontier
+        // with three sibling prefixes so we exercise the BFS without
+        // depending on round-1 src/ scan behavior. With probe_max_keys=1
+        // and ≥2 keys per prefix every probe truncates; cap=3 so:
+        //   Round 1: a/ → leaf (forward 2 objects);
+        //            b/ → 1 sub-dir (branch, new_frontier += b/x/);
+        //            c/ → 4 sub-dirs (> cap) → truncated (settled).
+        //   Round 2: b/x/ → leaf (forward 2 objects); break.
+        //   Final: settled={c/}, objects=[a/*, b/x/*]. Only c/ remains
+        //   in prefixes — neither it nor its descendants overlap with
+        //   anything else in the result.
+        let mut scanner = S3GlobMatcher::parse("{a,b,c}/**/*.rs".to_string(), "/", false)?;
+        scanner.set_min_prefixes(10);
+        scanner.set_probe_max_keys(1);
+        scanner.set_max_parallelism(3);
+
+        let paths = vec![
+            "a/file1.rs".to_string(),
+            "a/file2.rs".to_string(),
+            "b/x/file1.rs".to_string(),
+            "b/x/file2.rs".to_string(),
+            "c/p/f.rs".to_string(),
+            "c/q/f.rs".to_string(),
+            "c/r/f.rs".to_string(),
+            "c/s/f.rs".to_string(),
+        ];
 
         // Sanity: the scenario actually exercised mixed BFS outcomes
         // — the truncated parent c/ stays in prefixes, the leaves go
@@ -1507,19 +2624,19 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_find_prefixes_recursive_settles_flat_dense_prefix() -> Result<()> {
+    async fn test_find_prefixes_recursive_partitions_flat_dense_prefix() -> Result<()> {
         setup_logging(Some("s3glob=trace"));
         // The real `S3Engine::scan_prefixes` fires a page-budget guard
         // (`prefixes.is_empty() && pages_seen >= ceil(max/1000)`) that
         // returns `truncated=true` with no sub-prefixes for flat-dense
-        // parents. The BFS treats that the same as cap-hit truncation
-        // — settle the parent and let `get_all_children` re-list it.
+        // parents. There are no sub-directories to spread the listing
+        // over, so the BFS splits the parent's keys into ranges for
+        // `get_all_children` to list in parallel instead.
         // The mock simulates this via `with_forced_truncation`.
         //
         // Scenario: src/dense/ pretends to be flat-dense (forced
         // truncate). src/sparse/ has one file and is probe-resolvable.
-        // The two prefixes exercise different settle paths in the same
-        // run.
+        // The two prefixes exercise different paths in the same run.
         let mut scanner = S3GlobMatcher::parse("src/**/*.rs".to_string(), "/", false)?;
         scanner.set_min_prefixes(10);
         scanner.set_probe_max_keys(1);
@@ -1534,24 +2651,169 @@ mod tests {
 
         let presult = scanner.find_prefixes(engine).await?;
 
-        // src/dense/ must end up settled (not expanded into children
-        // and not dropped); src/sparse/ probe-resolves to objects.
+        // src/dense/ must end up as key ranges (not expanded into
+        // children and not dropped); src/sparse/ probe-resolves to objects.
         assert!(
-            presult.prefixes.contains(&"src/dense/".to_string()),
-            "expected src/dense/ settled via flat-dense fallback, got: {:?}",
-            presult.prefixes,
+            presult.ranges.len() == 3 && presult.ranges.iter().all(|r| r.prefix == "src/dense/"),
+            "expected src/dense/ split into one range per key, got: {:?}",
+            presult.ranges,
         );
         assert!(
-            !presult
-                .prefixes
-                .iter()
-                .any(|p| p.starts_with("src/dense/f")),
-            "flat-dense prefix must not be expanded into per-file children, got: {:?}",
+            !presult.prefixes.iter().any(|p| p.starts_with("src/dense/")),
+            "flat-dense prefix must not be listed whole or expanded into per-file children, got: {:?}",
             presult.prefixes,
         );
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_find_prefixes_partitions_flat_dense_prefixes_together() -> Result<()> {
+        setup_logging(Some("s3glob=debug"));
+        let mut scanner = S3GlobMatcher::parse("{a,b}/**.json".to_string(), "/", false)?;
+        scanner.set_min_prefixes(8);
+        scanner.set_probe_max_keys(10);
+        let paths: Vec<String> = ["a", "b"]
+            .iter()
+            .flat_map(|dir| (0..200).map(move |i| format!("{dir}/{i:05}.json")))
+            .collect();
+        let engine = MockS3Engine::new(paths.clone()).with_forced_truncation(["a/", "b/"]);
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        assert!(presult.prefixes.is_empty(), "{:?}", presult.prefixes);
+        for dir in ["a/", "b/"] {
+            let ranges = presult.ranges.iter().filter(|r| r.prefix == dir).count();
+            assert!(ranges > 1, "{dir} was not split: {:?}", presult.ranges);
+        }
+        let starts = presult
+            .ranges
+            .iter()
+            .map(|r| (r.prefix.clone(), r.bounds.start_after.clone()))
+            .collect::<Vec<_>>();
+        assert!(starts.is_sorted());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_partitions_flat_dense_prefix() -> Result<()> {
+        setup_logging(Some("s3glob=debug"));
+        let mut scanner = S3GlobMatcher::parse("logs/**.json".to_string(), "/", false)?;
+        scanner.set_min_prefixes(8);
+        scanner.set_probe_max_keys(10);
+        let mut paths: Vec<String> = (0..500).map(|i| format!("logs/{i:07}.json")).collect();
+        paths.push("logs/README.txt".to_string());
+        let engine = MockS3Engine::new(paths.clone()).with_forced_truncation(["logs/"]);
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        assert!(presult.prefixes.is_empty(), "{:?}", presult.prefixes);
+        assert!(presult.ranges.len() == 8, "{:?}", presult.ranges);
+        assert!(!engine.sample_calls.lock().unwrap().is_empty());
+        // every range gets a share of the keys, not just the first or last
+        for range in &presult.ranges {
+            let count = paths.iter().filter(|k| range.contains(k)).count();
+            assert!(count > 0, "empty range {range:?}");
+        }
+
+        // Listing the ranges emits every matching key exactly once
+        let mut result = scanner
+            .get_objects(MockS3Engine::new(paths.clone()).with_forced_truncation(["logs/"]))
+            .await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+        let mut expected: Vec<String> =
+            paths.into_iter().filter(|k| k.ends_with(".json")).collect();
+        expected.sort();
+        assert!(keys == expected);
+        assert!(result.totals.total_prefixes == 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_sorted_merges_listings_in_key_order() -> Result<()> {
+        setup_logging(Some("s3glob=debug"));
+        // Expanding `**` leaves whole prefixes, key ranges of the flat dense
+        // src/dense/, and objects found along the way, which all have to be
+        // put in order.
+        let mut scanner = S3GlobMatcher::parse("src/**/*.rs".to_string(), "/", false)?;
+        scanner.set_min_prefixes(10);
+        scanner.set_probe_max_keys(1);
+        scanner.set_sorted(true);
+        let mut paths = vec![
+            "src/z/b.rs".to_string(),
+            "src/a.rs".to_string(),
+            "src/y.rs".to_string(),
+            "src/b/x.rs".to_string(),
+            "src/b/c/y.rs".to_string(),
+            "src/b/c/z.rs".to_string(),
+            "src/m/only.rs".to_string(),
+            "src/z/a.rs".to_string(),
+            "src/z/README".to_string(),
+        ];
+        paths.extend((0..5).map(|i| format!("src/dense/f{i}.rs")));
+        let engine = || MockS3Engine::new(paths.clone()).with_forced_truncation(["src/dense/"]);
+
+        let presult = scanner.find_prefixes(engine()).await?;
+        assert!(!presult.ranges.is_empty());
+        assert!(!presult.objects.is_empty());
+
+        let mut result = scanner.get_objects(engine()).await?;
+        assert!(result.sorted);
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        let mut expected: Vec<String> = paths.into_iter().filter(|k| k.ends_with(".rs")).collect();
+        expected.sort();
+        assert!(keys == expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_objects_sorted_does_not_apply_to_exact_keys() -> Result<()> {
+        setup_logging(Some("s3glob=debug"));
+        let mut scanner = S3GlobMatcher::parse("src/{b,a}.rs".to_string(), "/", false)?;
+        scanner.set_sorted(true);
+        let engine = MockS3Engine::new(vec!["src/a.rs".to_string(), "src/b.rs".to_string()]);
+        let result = scanner.get_objects(engine).await?;
+        assert!(!result.sorted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_partitions_prefix_with_tiny_sub_prefixes() -> Result<()> {
+        setup_logging(Some("s3glob=debug"));
+        // Two keys in each of 100 sub-directories: the probe's first page
+        // shows that listing them one by one would take far more requests
+        // than listing the prefix, so it is split into key ranges without
+        // scanning it for its sub-directories.
+        let mut scanner = S3GlobMatcher::parse("logs/**.json".to_string(), "/", false)?;
+        scanner.set_min_prefixes(8);
+        scanner.set_probe_max_keys(20);
+        let paths: Vec<String> = (0..200)
+            .map(|i| format!("logs/{:03}/{i:03}.json", i / 2))
+            .collect();
+        let engine = MockS3Engine::new(paths.clone());
+
+        let presult = scanner.find_prefixes(engine.clone()).await?;
+        assert!(presult.prefixes.is_empty(), "{:?}", presult.prefixes);
+        assert!(presult.ranges.len() == 8, "{:?}", presult.ranges);
+        assert!(engine.calls.lock().unwrap().is_empty());
+        assert!(presult.estimated_list_calls.is_some());
+
+        let mut result = scanner
+            .get_objects(MockS3Engine::new(paths.clone()))
+            .await?;
+        let mut keys: Vec<String> = Vec::new();
+        while let Some(batch) = result.rx.recv().await {
+            keys.extend(batch.into_iter().map(|r| r.key()));
+        }
+        keys.sort();
+        assert!(keys == paths);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_find_prefixes_recursive_mixed_probe_outcomes() -> Result<()> {
         setup_logging(Some("s3glob=trace"));
@@ -1994,6 +3256,25 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_find_prefixes_multi_char_delimiter() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner = S3GlobMatcher::parse("foo::*::bar".to_string(), "::", false)?;
+        scanner.set_min_prefixes(0);
+
+        let engine = MockS3Engine::new(vec![
+            "foo::a::bar".to_string(),
+            "foo::b:c::bar".to_string(),
+            "foo::a::b::bar".to_string(), // Should be filtered out (too many segments)
+            "foo::x::baz".to_string(),    // Should be filtered out
+        ]);
+
+        let prefixes = scanner.find_prefixes(engine.clone()).await?.prefixes;
+        assert!(prefixes == vec!["foo::a::bar", "foo::b:c::bar"]);
+        engine.assert_calls(&[("foo::", "::")]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_find_prefixes_complex_negative_pattern() -> Result<()> {
         setup_logging(Some("s3glob=trace"));
@@ -2063,6 +3344,91 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_find_prefixes_ignore_case_variants() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner = S3GlobMatcher::parse("ab/*".to_string(), "/", false)?;
+        scanner.set_min_prefixes(0);
+        scanner.set_ignore_case(DEFAULT_MAX_CASE_VARIANTS);
+        let engine = MockS3Engine::new(vec![
+            "AB/1".to_string(),
+            "Ab/2".to_string(),
+            "abc/3".to_string(), // Should be filtered out
+        ]);
+
+        let objects = scanner
+            .find_prefixes(engine.clone())
+            .await?
+            .objects
+            .into_iter()
+            .map(|o| o.key.unwrap())
+            .collect::<Vec<_>>();
+        assert!(objects == vec!["AB/1", "Ab/2"]);
+        // only the spellings that exist are listed
+        engine.assert_call_set(&[("AB/", "/"), ("Ab/", "/")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_ignore_case_scans_over_limit() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner = S3GlobMatcher::parse("partner/invoice*".to_string(), "/", false)?;
+        scanner.set_min_prefixes(0);
+        scanner.set_ignore_case(4);
+        let engine = MockS3Engine::new(vec![
+            "PARTNER/INVOICE-1".to_string(),
+            "Partner/Invoice-2".to_string(),
+            "partner/invoice-3".to_string(),
+            "partner/receipt-4".to_string(), // Should be filtered out
+            "partners/invoice-5".to_string(), // Should be filtered out
+        ]);
+
+        let objects = scanner
+            .find_prefixes(engine.clone())
+            .await?
+            .objects
+            .into_iter()
+            .map(|o| o.key.unwrap())
+            .collect::<Vec<_>>();
+        assert!(
+            objects
+                == vec![
+                    "PARTNER/INVOICE-1",
+                    "Partner/Invoice-2",
+                    "partner/invoice-3"
+                ]
+        );
+        // one scan for the first segment, one per spelling of it for the
+        // second, and then the `*` scans below each spelling of the literal
+        engine.assert_call_set(&[
+            ("", "/"),
+            ("PARTNER/", "/"),
+            ("Partner/", "/"),
+            ("partner/", "/"),
+            ("PARTNER/INVOICE", "/"),
+            ("Partner/Invoice", "/"),
+            ("partner/invoice", "/"),
+        ]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_prefixes_ignore_case_after_any() -> Result<()> {
+        setup_logging(Some("s3glob=trace"));
+        let mut scanner = S3GlobMatcher::parse("*/data/report".to_string(), "/", false)?;
+        scanner.set_min_prefixes(0);
+        scanner.set_ignore_case(4);
+        let engine = MockS3Engine::new(vec![
+            "a/DATA/Report".to_string(),
+            "b/data/report".to_string(),
+            "c/data/other".to_string(), // Should be filtered out
+        ]);
+
+        let prefixes = scanner.find_prefixes(engine.clone()).await?.prefixes;
+        assert!(prefixes == vec!["a/DATA/Report", "b/data/report"]);
+        Ok(())
+    }
+
     //
     // Helpers
     //
@@ -0,0 +1,162 @@
+//! Deciding from a probe whether scanning a prefix at `**` for its
+//! sub-prefixes pays off
+//!
+//! A dense prefix can be listed serially, one page after the other, or
+//! scanned with the delimiter so that its sub-prefixes can be listed in
+//! parallel. Expanding costs the scan plus at least one request per
+//! sub-prefix, which is only worth it if the sub-prefixes hold enough keys
+//! each. When they are tiny, the listing turns into a request per handful of
+//! keys, and splitting the prefix into key ranges is the cheaper way to list
+//! it in parallel.
+
+use std::collections::BTreeSet;
+
+use super::engine::ScanResult;
+
+/// The most LIST requests expanding a prefix may take, relative to listing
+/// it serially
+const MAX_OVERHEAD: f64 = 2.0;
+
+/// The most keys or prefixes S3 returns in a page of a delimiter listing
+const KEYS_PER_PAGE: f64 = 1000.0;
+
+/// What the first page of a prefix's keys says about the subtree under it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Density {
+    /// Keys in the page that are under a sub-prefix
+    nested_keys: usize,
+    /// How many sub-prefixes those keys are under
+    sub_prefixes: usize,
+}
+
+/// The LIST requests it should take to list a subtree, serially or after
+/// expanding it by one level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Estimate {
+    /// Listing the prefix page by page
+    pub(super) serial: f64,
+    /// Scanning the prefix for its sub-prefixes
+    pub(super) scan: f64,
+    /// Listing each of the sub-prefixes the scan finds
+    pub(super) per_child: f64,
+    /// How many sub-prefixes the scan should find
+    pub(super) children: f64,
+}
+
+impl Density {
+    /// Count the keys under each sub-prefix of `prefix` in a probe of it
+    pub(super) fn of_probe(prefix: &str, delimiter: &str, probe: &ScanResult) -> Self {
+        let mut sub_prefixes = BTreeSet::new();
+        let mut nested_keys = 0;
+        for key in probe.objects.iter().filter_map(|o| o.key()) {
+            let rest = key.strip_prefix(prefix).unwrap_or(key);
+            if let Some(idx) = rest.find(delimiter) {
+                sub_prefixes.insert(&rest[..idx]);
+                nested_keys += 1;
+            }
+        }
+        Self {
+            nested_keys,
+            sub_prefixes: sub_prefixes.len(),
+        }
+    }
+
+    /// Estimate the cost of a subtree with `page_keys` keys per listing page
+    ///
+    /// The probe only sees the first page, so the number of sub-prefixes
+    /// is at least what it saw, and at least `fan_out`, the average number
+    /// found under each prefix of the level above. The probe was truncated,
+    /// so listing the prefix takes at least two pages.
+    pub(super) fn estimate(&self, page_keys: usize, fan_out: Option<f64>) -> Option<Estimate> {
+        if self.sub_prefixes == 0 {
+            return None;
+        }
+        let page_keys = page_keys.max(1) as f64;
+        let keys_per_child = self.nested_keys as f64 / self.sub_prefixes as f64;
+        let children = (self.sub_prefixes as f64).max(fan_out.unwrap_or(0.0));
+        Some(Estimate {
+            serial: (children * keys_per_child / page_keys).ceil().max(2.0),
+            scan: (children / KEYS_PER_PAGE).ceil().max(1.0),
+            per_child: (keys_per_child / page_keys).ceil().max(1.0),
+            children,
+        })
+    }
+}
+
+impl Estimate {
+    pub(super) fn expanded(&self) -> f64 {
+        self.scan + self.children * self.per_child
+    }
+
+    /// True if listing the sub-prefixes in parallel doesn't take too many
+    /// more requests than listing the prefix serially
+    pub(super) fn pays_off(&self) -> bool {
+        self.expanded() <= self.serial * MAX_OVERHEAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::{assert, check};
+    use aws_sdk_s3::types::Object;
+
+    use super::*;
+
+    fn probe(keys: impl IntoIterator<Item = String>) -> ScanResult {
+        ScanResult {
+            prefixes: Vec::new(),
+            objects: keys
+                .into_iter()
+                .map(|k| Object::builder().key(k).build())
+                .collect(),
+            truncated: true,
+        }
+    }
+
+    #[test]
+    fn test_density_ignores_direct_objects() {
+        let probe = probe([
+            "p/README".to_string(),
+            "p/a/1".to_string(),
+            "p/a/2".to_string(),
+            "p/b/1".to_string(),
+        ]);
+        let density = Density::of_probe("p/", "/", &probe);
+        assert!(
+            density
+                == Density {
+                    nested_keys: 3,
+                    sub_prefixes: 2
+                }
+        );
+        assert!(
+            Density::of_probe("p/", "/", &self::probe(["p/README".to_string()]))
+                .estimate(1000, None)
+                == None
+        );
+    }
+
+    #[test]
+    fn test_expanding_into_big_sub_prefixes_pays_off() {
+        // a whole page under one sub-prefix, and the level above had 30
+        let probe = probe((0..1000).map(|i| format!("p/a/{i:04}")));
+        let estimate = Density::of_probe("p/", "/", &probe)
+            .estimate(1000, Some(30.0))
+            .unwrap();
+        check!(estimate.serial == 30.0);
+        check!(estimate.expanded() == 31.0);
+        assert!(estimate.pays_off());
+    }
+
+    #[test]
+    fn test_expanding_into_tiny_sub_prefixes_does_not_pay_off() {
+        // two keys in each of 500 sub-prefixes
+        let probe = probe((0..1000).map(|i| format!("p/{:03}/{i}", i / 2)));
+        let estimate = Density::of_probe("p/", "/", &probe)
+            .estimate(1000, None)
+            .unwrap();
+        check!(estimate.serial == 2.0);
+        check!(estimate.expanded() == 501.0);
+        assert!(!estimate.pays_off());
+    }
+}
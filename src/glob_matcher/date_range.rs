@@ -0,0 +1,158 @@
+//! Date-range tokens: `{date:2024-05-28..2024-06-03:%Y-%m-%d}`
+//!
+//! A date range expands into the formatted date of every day in the range,
+//! so it ends up as an ordinary [`Glob::Choice`](super::glob::Glob::Choice)
+//! and narrows prefixes just like a hand-written `{...}` alternation.
+
+use std::fmt::Write as _;
+
+use anyhow::{Context as _, Result, bail};
+use chrono::{Days, NaiveDate};
+use itertools::Itertools as _;
+
+/// The format used when a date token doesn't specify one
+const DEFAULT_FORMAT: &str = "%Y-%m-%d";
+
+/// The most days a single date token may cover
+const MAX_DAYS: u64 = 10_000;
+
+/// Expand the body of a date token (everything after `date:`)
+///
+/// The body is `START..END` optionally followed by `:FORMAT` (a strftime
+/// format, `%Y-%m-%d` by default). Both ends are inclusive and are either an
+/// ISO date (`2024-06-01`), `today`, `yesterday`, or an offset from today
+/// like `-7d` or `+2w`. Formats that don't include the day (e.g. `%Y/%m`)
+/// produce each distinct value once.
+pub(super) fn expand(spec: &str, today: NaiveDate) -> Result<Vec<String>> {
+    let (range, format) = spec.split_once(':').unwrap_or((spec, DEFAULT_FORMAT));
+    let Some((start, end)) = range.split_once("..") else {
+        bail!("Date range must look like START..END, got: {range}");
+    };
+    let start = parse_bound(start, today)?;
+    let end = parse_bound(end, today)?;
+    if start > end {
+        bail!("Date range starts after it ends: {range}");
+    }
+    let days = (end - start).num_days() as u64 + 1;
+    if days > MAX_DAYS {
+        bail!("Date range covers {days} days, the maximum is {MAX_DAYS}: {range}");
+    }
+
+    let mut dates = Vec::new();
+    for offset in 0..days {
+        let date = start + Days::new(offset);
+        let mut formatted = String::new();
+        write!(formatted, "{}", date.format(format))
+            .map_err(|_| anyhow::anyhow!("Invalid date format: {format}"))?;
+        dates.push(formatted);
+    }
+    Ok(dates.into_iter().unique().collect())
+}
+
+/// Parse one end of a date range
+fn parse_bound(raw: &str, today: NaiveDate) -> Result<NaiveDate> {
+    match raw {
+        "today" => return Ok(today),
+        "yesterday" => return Ok(today - Days::new(1)),
+        _ => {}
+    }
+    if let Some(sign @ ('-' | '+')) = raw.chars().next() {
+        let body = &raw[1..];
+        if body.is_empty() {
+            bail!("Invalid relative date: {raw}");
+        }
+        let unit = body.chars().last().unwrap_or_default();
+        let count: u64 = body[..body.len() - unit.len_utf8()]
+            .parse()
+            .with_context(|| format!("Invalid relative date: {raw}"))?;
+        let days_per_unit = match unit {
+            'd' => 1,
+            'w' => 7,
+            _ => bail!("Relative dates must end in `d` or `w`: {raw}"),
+        };
+        let days = count
+            .checked_mul(days_per_unit)
+            .with_context(|| format!("Relative date is out of range: {raw}"))?;
+        let date = if sign == '-' {
+            today.checked_sub_days(Days::new(days))
+        } else {
+            today.checked_add_days(Days::new(days))
+        };
+        return date.with_context(|| format!("Relative date is out of range: {raw}"));
+    }
+    NaiveDate::parse_from_str(raw, "%Y-%m-%d").with_context(|| {
+        format!("Invalid date (expected YYYY-MM-DD, today, yesterday, or -Nd): {raw}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+    use rstest::rstest;
+
+    use super::*;
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 2).unwrap()
+    }
+
+    #[test]
+    fn test_expand_crosses_month_boundary() -> Result<()> {
+        let dates = expand("2024-05-30..2024-06-02", today())?;
+        check!(dates == ["2024-05-30", "2024-05-31", "2024-06-01", "2024-06-02"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_crosses_year_boundary_with_format() -> Result<()> {
+        let dates = expand("2023-12-31..2024-01-01:%Y/%m/%d", today())?;
+        check!(dates == ["2023/12/31", "2024/01/01"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_leap_day() -> Result<()> {
+        let dates = expand("2024-02-28..2024-03-01", today())?;
+        check!(dates == ["2024-02-28", "2024-02-29", "2024-03-01"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_dedups_coarse_formats() -> Result<()> {
+        let dates = expand("2024-01-30..2024-03-01:%Y-%m", today())?;
+        check!(dates == ["2024-01", "2024-02", "2024-03"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_relative() -> Result<()> {
+        let dates = expand("-3d..today", today())?;
+        check!(dates == ["2024-02-28", "2024-02-29", "2024-03-01", "2024-03-02"]);
+        let dates = expand("-1w..yesterday:%d", today())?;
+        check!(dates == ["24", "25", "26", "27", "28", "29", "01"]);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("-3000000000000000000w..today")]
+    #[case("today..+3000000000000000000w")]
+    #[case("-18446744073709551615d..today")]
+    fn test_expand_out_of_range(#[case] spec: &str) {
+        let err = expand(spec, today()).unwrap_err();
+        check!(err.to_string().contains("out of range"), "spec: {spec}");
+    }
+
+    #[rstest]
+    #[case("2024-06-01")]
+    #[case("2024-06-03..2024-06-01")]
+    #[case("2024-06-01..tomorrow")]
+    #[case("2024-13-01..2024-13-02")]
+    #[case("-7m..today")]
+    #[case("-..today")]
+    #[case("+..today")]
+    #[case("2024-06-01..2024-06-02:%Q")]
+    #[case("1990-01-01..2024-01-01")]
+    fn test_expand_invalid(#[case] spec: &str) {
+        check!(expand(spec, today()).is_err(), "spec: {spec}");
+    }
+}
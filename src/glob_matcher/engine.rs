@@ -1,26 +1,45 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::{Context as _, Result};
 use aws_sdk_s3::Client;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::list_objects_v2::builders::ListObjectsV2FluentBuilder;
+use aws_sdk_s3::operation::list_objects_v2::{ListObjectsV2Error, ListObjectsV2Output};
 use aws_sdk_s3::types::Object;
 use num_format::{Locale, ToFormattedString as _};
 use tokio::sync::Semaphore;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinSet;
 use tracing::{debug, trace};
 
+#[cfg(test)]
+use itertools::Itertools as _;
 #[cfg(test)]
 use std::sync::Mutex;
 #[cfg(test)]
 use tracing::info;
 
-use crate::{S3Object, add_atomic, progressln};
+use crate::limiter::Limiter;
+use crate::stats::{Api, ApiCalls, Phase};
+use crate::{S3Object, add_atomic, progressln, retry};
 
+use super::key_ranges::{KeyBounds, KeyRange};
+use super::key_regex::KeyRegex;
 use super::{LiveStatus, PrefixResult, PrefixSearchResult};
 
 #[async_trait::async_trait]
 pub trait Engine: Send + Sync + 'static {
+    /// Count the requests made from now on towards `phase`
+    fn set_phase(&mut self, phase: Phase);
+
+    /// Skip keys outside of `bounds` in every scan, probe and listing from
+    /// now on
+    fn set_bounds(&mut self, bounds: KeyBounds);
+
     /// List the immediate children of `prefix` using `delimiter`.
     ///
     /// If `max_prefixes` is `Some(n)`, pagination may stop early —
@@ -37,6 +56,18 @@ pub trait Engine: Send + Sync + 'static {
         max_prefixes: Option<usize>,
     ) -> Result<ScanResult>;
 
+    /// List the immediate children of `prefix` using `delimiter`, fetching
+    /// at most `max_pages` pages
+    ///
+    /// Sets `ScanResult::truncated` if there were more pages, whatever was
+    /// found on the ones that were fetched.
+    async fn scan_pages(
+        &mut self,
+        prefix: &str,
+        delimiter: &str,
+        max_pages: usize,
+    ) -> Result<ScanResult>;
+
     /// Single-page delimiter-less list of `prefix`, capped at `max_keys`.
     ///
     /// Returns up to `max_keys` objects under `prefix`. `truncated` is true
@@ -44,6 +75,9 @@ pub trait Engine: Send + Sync + 'static {
     /// returned objects are the complete content under `prefix`.
     async fn probe_prefix(&mut self, prefix: &str, max_keys: i32) -> Result<ScanResult>;
 
+    /// The first key under `prefix` that sorts after `start_after`, if any
+    async fn first_key_after(&mut self, prefix: &str, start_after: &str) -> Result<Option<String>>;
+
     async fn check_prefixes<P>(
         &mut self,
         prefixes: P,
@@ -64,20 +98,21 @@ pub trait Engine: Send + Sync + 'static {
     async fn get_exact(
         &self,
         presult: PrefixSearchResult,
-        delimiter: char,
+        delimiter: &str,
         status: &LiveStatus,
-        matcher: &regex::Regex,
+        matcher: &KeyRegex,
         tx: &UnboundedSender<Vec<PrefixResult>>,
         permit: Arc<Semaphore>,
     ) -> Result<()>;
 
-    /// List all objects under each `presult` prefix which match matcher.
+    /// List all objects under each `presult` prefix and in each of its key
+    /// ranges which match matcher.
     ///
     ///  Used when the pattern contains `**`.
     async fn get_all_children(
         &self,
         presult: PrefixSearchResult,
-        matcher: Arc<regex::Regex>,
+        matcher: Arc<KeyRegex>,
         status: &LiveStatus,
         tx: &UnboundedSender<Vec<PrefixResult>>,
         permit: Arc<Semaphore>,
@@ -88,41 +123,183 @@ pub trait Engine: Send + Sync + 'static {
 pub struct S3Engine {
     client: Client,
     bucket: String,
+    /// Shared by every request, so throttling anywhere slows down everything
+    limiter: Arc<Limiter>,
+    calls: Arc<ApiCalls>,
+    phase: Phase,
+    bounds: KeyBounds,
 }
 
 impl S3Engine {
-    pub fn new(client: Client, bucket: String) -> Self {
-        Self { client, bucket }
+    pub fn new(
+        client: Client,
+        bucket: String,
+        limiter: Arc<Limiter>,
+        calls: Arc<ApiCalls>,
+    ) -> Self {
+        Self {
+            client,
+            bucket,
+            limiter,
+            calls,
+            phase: Phase::Discovery,
+            bounds: KeyBounds::default(),
+        }
+    }
+
+    /// Send a request through the limiter and retries, counting every attempt
+    async fn send<T, E, F, Fut>(
+        &self,
+        api: Api,
+        key: &str,
+        what: &str,
+        mut op: F,
+    ) -> Result<T, SdkError<E, HttpResponse>>
+    where
+        E: aws_sdk_s3::error::ProvideErrorMetadata,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+    {
+        retry::retry_limited(&self.limiter, key, what, || {
+            self.calls.record(self.phase, api);
+            op()
+        })
+        .await
+    }
+
+    /// List the immediate children of `prefix`, see
+    /// [`Engine::scan_prefixes`] and [`Engine::scan_pages`]
+    async fn scan(
+        &self,
+        prefix: &str,
+        delimiter: &str,
+        max_prefixes: Option<usize>,
+        max_pages: Option<usize>,
+    ) -> Result<ScanResult> {
+        trace!(
+            prefix,
+            ?max_prefixes,
+            ?max_pages,
+            "scanning for prefixes within"
+        );
+        let mut result = ScanResult::default();
+        let mut paginator = ListPages::new(
+            self.client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .delimiter(delimiter)
+                .set_start_after(self.bounds.start_after.clone()),
+            self.clone(),
+        );
+
+        let mut warning_count = 0;
+        let mut warning_inc = 50_000;
+        let mut pages_seen = 0usize;
+        while let Some(page) = paginator.next().await {
+            let page = page?;
+            pages_seen += 1;
+            let page_is_truncated = page.is_truncated.unwrap_or(false);
+            if result.len() >= warning_count + warning_inc {
+                // Routed through progressln! (not tracing::warn!) so the
+                // prefix-discovery spinner is suspended while we print, and
+                // the line doesn't get clobbered by the next bar redraw.
+                progressln!(
+                    "found {} objects and {} prefixes in {prefix} and still discovering more",
+                    result.objects.len().to_formatted_string(&Locale::en),
+                    result.prefixes.len().to_formatted_string(&Locale::en),
+                );
+                warning_count += warning_inc;
+                if warning_count >= 100_000 {
+                    warning_inc = 100_000;
+                }
+            }
+            // S3 returns prefixes and keys in one sorted listing, so
+            // everything after the end of the bounds is on this page or later
+            let mut reached_end = false;
+            if let Some(common_prefixes) = page.common_prefixes {
+                for prefix in common_prefixes.into_iter().filter_map(|p| p.prefix) {
+                    if self.bounds.is_past_end(&prefix) {
+                        reached_end = true;
+                    } else {
+                        result.prefixes.push(prefix);
+                    }
+                }
+            }
+            if let Some(contents) = page.contents {
+                for obj in contents {
+                    if obj.key().is_some_and(|key| self.bounds.is_past_end(key)) {
+                        reached_end = true;
+                    } else {
+                        result.objects.push(obj);
+                    }
+                }
+            }
+            if reached_end {
+                break;
+            }
+            if page_is_truncated && max_pages.is_some_and(|max| pages_seen >= max) {
+                result.truncated = true;
+                break;
+            }
+            if let Some(max) = max_prefixes {
+                if result.prefixes.len() >= max {
+                    result.prefixes.truncate(max);
+                    result.truncated = true;
+                    break;
+                }
+                // Bail out of flat-dense parents: if we've paged through
+                // enough entries to plausibly surface `max` prefixes and
+                // none have appeared, further pagination won't help.
+                // Only fire when S3 still has more pages — if `page` was
+                // the last page, this is the complete listing and the
+                // caller can use `result.objects` directly.
+                let plausible_pages = max.div_ceil(1000);
+                if page_is_truncated && result.prefixes.is_empty() && pages_seen >= plausible_pages
+                {
+                    result.truncated = true;
+                    break;
+                }
+            }
+        }
+        Ok(result)
     }
 }
 
 async fn list_matching_objects(
-    client: Client,
-    bucket: String,
-    prefix: String,
-    matcher: Arc<regex::Regex>,
+    engine: S3Engine,
+    range: KeyRange,
+    matcher: Arc<KeyRegex>,
     total_objects: Arc<AtomicUsize>,
     tx: UnboundedSender<Vec<PrefixResult>>,
 ) -> Result<()> {
-    let mut paginator = client
+    let request = engine
+        .client
         .list_objects_v2()
-        .bucket(bucket.clone())
-        .prefix(prefix)
-        .into_paginator()
-        .send();
+        .bucket(&engine.bucket)
+        .prefix(&range.prefix)
+        .set_start_after(range.bounds.start_after.clone());
+    let mut paginator = ListPages::new(request, engine);
 
     while let Some(page) = paginator.next().await {
         let page = page?;
+        let mut reached_end = false;
         if let Some(contents) = page.contents {
             let mut matching_objects = Vec::new();
-            total_objects.fetch_add(contents.len(), Ordering::Relaxed);
+            let mut in_range = 0;
             for obj in contents {
-                if let Some(key) = &obj.key
-                    && matcher.is_match(key)
-                {
-                    matching_objects.push(obj);
+                if let Some(key) = &obj.key {
+                    if range.bounds.is_past_end(key) {
+                        reached_end = true;
+                        break;
+                    }
+                    in_range += 1;
+                    if matcher.is_match(key) {
+                        matching_objects.push(obj);
+                    }
                 }
             }
+            total_objects.fetch_add(in_range, Ordering::Relaxed);
             tx.send(
                 matching_objects
                     .into_iter()
@@ -130,10 +307,67 @@ async fn list_matching_objects(
                     .collect::<Vec<_>>(),
             )?;
         }
+        if reached_end {
+            break;
+        }
     }
     Ok(())
 }
 
+/// The pages of a `ListObjectsV2` request
+///
+/// Like the SDK's paginator, but each page is retried on transient errors,
+/// so a throttled request halfway through a large listing picks up from the
+/// same continuation token instead of failing the whole prefix.
+struct ListPages {
+    request: ListObjectsV2FluentBuilder,
+    continuation_token: Option<String>,
+    done: bool,
+    engine: S3Engine,
+}
+
+impl ListPages {
+    fn new(request: ListObjectsV2FluentBuilder, engine: S3Engine) -> Self {
+        Self {
+            request,
+            continuation_token: None,
+            done: false,
+            engine,
+        }
+    }
+
+    async fn next(
+        &mut self,
+    ) -> Option<Result<ListObjectsV2Output, SdkError<ListObjectsV2Error, HttpResponse>>> {
+        if self.done {
+            return None;
+        }
+        let request = self
+            .request
+            .clone()
+            .set_continuation_token(self.continuation_token.clone());
+        let prefix = request.get_prefix().clone().unwrap_or_default();
+        let page = self
+            .engine
+            .send(
+                Api::ListObjectsV2,
+                &prefix,
+                &format!("listing {prefix}"),
+                || request.clone().send(),
+            )
+            .await;
+        match &page {
+            Ok(output) => {
+                self.continuation_token = output.next_continuation_token.clone();
+                self.done =
+                    !output.is_truncated.unwrap_or(false) || self.continuation_token.is_none();
+            }
+            Err(_) => self.done = true,
+        }
+        Some(page)
+    }
+}
+
 #[derive(Default)]
 pub struct ScanResult {
     pub prefixes: Vec<String>,
@@ -177,92 +411,88 @@ impl ScanResult {
 
 #[async_trait::async_trait]
 impl Engine for S3Engine {
+    fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+    }
+
+    fn set_bounds(&mut self, bounds: KeyBounds) {
+        self.bounds = bounds;
+    }
+
     async fn scan_prefixes(
         &mut self,
         prefix: &str,
         delimiter: &str,
         max_prefixes: Option<usize>,
     ) -> Result<ScanResult> {
-        trace!(prefix, ?max_prefixes, "scanning for prefixes within");
-        let mut result = ScanResult::default();
-        let mut paginator = self
-            .client
-            .list_objects_v2()
-            .bucket(&self.bucket)
-            .prefix(prefix)
-            .delimiter(delimiter)
-            .into_paginator()
-            .send();
+        self.scan(prefix, delimiter, max_prefixes, None).await
+    }
 
-        let mut warning_count = 0;
-        let mut warning_inc = 50_000;
-        let mut pages_seen = 0usize;
-        while let Some(page) = paginator.next().await {
-            let page = page?;
-            pages_seen += 1;
-            let page_is_truncated = page.is_truncated.unwrap_or(false);
-            if result.len() >= warning_count + warning_inc {
-                // Routed through progressln! (not tracing::warn!) so the
-                // prefix-discovery spinner is suspended while we print, and
-                // the line doesn't get clobbered by the next bar redraw.
-                progressln!(
-                    "found {} objects and {} prefixes in {prefix} and still discovering more",
-                    result.objects.len().to_formatted_string(&Locale::en),
-                    result.prefixes.len().to_formatted_string(&Locale::en),
-                );
-                warning_count += warning_inc;
-                if warning_count >= 100_000 {
-                    warning_inc = 100_000;
-                }
-            }
-            if let Some(common_prefixes) = page.common_prefixes {
-                result
-                    .prefixes
-                    .extend(common_prefixes.into_iter().filter_map(|p| p.prefix));
-            }
-            if let Some(contents) = page.contents {
-                result.objects.extend(contents);
-            }
-            if let Some(max) = max_prefixes {
-                if result.prefixes.len() >= max {
-                    result.prefixes.truncate(max);
-                    result.truncated = true;
-                    break;
-                }
-                // Bail out of flat-dense parents: if we've paged through
-                // enough entries to plausibly surface `max` prefixes and
-                // none have appeared, further pagination won't help.
-                // Only fire when S3 still has more pages — if `page` was
-                // the last page, this is the complete listing and the
-                // caller can use `result.objects` directly.
-                let plausible_pages = max.div_ceil(1000);
-                if page_is_truncated && result.prefixes.is_empty() && pages_seen >= plausible_pages
-                {
-                    result.truncated = true;
-                    break;
-                }
-            }
-        }
-        Ok(result)
+    async fn scan_pages(
+        &mut self,
+        prefix: &str,
+        delimiter: &str,
+        max_pages: usize,
+    ) -> Result<ScanResult> {
+        self.scan(prefix, delimiter, None, Some(max_pages)).await
     }
 
     async fn probe_prefix(&mut self, prefix: &str, max_keys: i32) -> Result<ScanResult> {
         trace!(prefix, max_keys, "probing prefix for direct content");
-        let response = self
+        let request = self
             .client
             .list_objects_v2()
             .bucket(&self.bucket)
             .prefix(prefix)
-            .max_keys(max_keys)
-            .send()
+            .set_start_after(self.bounds.start_after.clone())
+            .max_keys(max_keys);
+        let response = self
+            .send(
+                Api::ListObjectsV2,
+                prefix,
+                &format!("probing {prefix}"),
+                || request.clone().send(),
+            )
             .await?;
+        let mut objects = response.contents.unwrap_or_default();
+        let in_bounds = objects
+            .iter()
+            .take_while(|o| o.key().is_none_or(|key| !self.bounds.is_past_end(key)))
+            .count();
+        // everything within the bounds is here if the page got past them
+        let reached_end = in_bounds < objects.len();
+        objects.truncate(in_bounds);
         Ok(ScanResult {
             prefixes: Vec::new(),
-            objects: response.contents.unwrap_or_default(),
-            truncated: response.is_truncated.unwrap_or(false),
+            objects,
+            truncated: response.is_truncated.unwrap_or(false) && !reached_end,
         })
     }
 
+    async fn first_key_after(&mut self, prefix: &str, start_after: &str) -> Result<Option<String>> {
+        trace!(prefix, start_after, "sampling first key");
+        let request = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .start_after(start_after)
+            .max_keys(1);
+        let response = self
+            .send(
+                Api::ListObjectsV2,
+                prefix,
+                &format!("sampling {prefix} after {start_after}"),
+                || request.clone().send(),
+            )
+            .await?;
+        Ok(response
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|o| o.key))
+    }
+
     // TODO: convert this to take &mut prefixes so that we don't have to
     // reallocate the vector on each call
     async fn check_prefixes<P>(
@@ -281,19 +511,25 @@ impl Engine for S3Engine {
         let permit = Arc::new(tokio::sync::Semaphore::new(max_parallelism));
 
         for prefix in prefixes {
-            let client = self.client.clone();
-            let bucket = self.bucket.clone();
+            let engine = self.clone();
             let tx = tx.clone();
             let prefix = prefix.clone();
             let permit = permit.clone().acquire_owned().await;
 
             tokio::spawn(async move {
-                let result = client
+                let request = engine
+                    .client
                     .list_objects_v2()
-                    .bucket(bucket)
+                    .bucket(&engine.bucket)
                     .prefix(prefix.clone())
-                    .max_keys(1)
-                    .send()
+                    .max_keys(1);
+                let result = engine
+                    .send(
+                        Api::ListObjectsV2,
+                        &prefix,
+                        &format!("checking {prefix}"),
+                        || request.clone().send(),
+                    )
                     .await;
                 drop(permit);
 
@@ -324,9 +560,9 @@ impl Engine for S3Engine {
     async fn get_exact(
         &self,
         presult: PrefixSearchResult,
-        delimiter: char,
+        delimiter: &str,
         status: &LiveStatus,
-        matcher: &regex::Regex,
+        matcher: &KeyRegex,
         tx: &UnboundedSender<Vec<PrefixResult>>,
         permit: Arc<Semaphore>,
     ) -> Result<()> {
@@ -336,16 +572,17 @@ impl Engine for S3Engine {
             }
             // just get the object info for each prefix
             let permit = permit.clone().acquire_owned().await;
-            let client = self.client.clone();
-            let bucket = self.bucket.clone();
+            let engine = self.clone();
             let prefix = prefix.clone();
+            let delimiter = delimiter.to_string();
             let tx = tx.clone();
+            let bounds = self.bounds.clone();
 
             status.total_objects.fetch_add(1, Ordering::Relaxed);
             tokio::spawn(async move {
                 // Prefixes that already end with the delimiter came from
                 // Engine::scan_prefixes and are verified directories.
-                if prefix.ends_with(delimiter) {
+                if prefix.ends_with(&delimiter) {
                     drop(permit);
                     let _ = tx.send(vec![PrefixResult::Prefix(prefix)]);
                     return;
@@ -355,34 +592,56 @@ impl Engine for S3Engine {
                 // also a directory.
                 // simple_append's loose verification can produce phantom
                 // prefixes which are neither.
-                let head = client
+                let head_request = engine
+                    .client
                     .head_object()
-                    .bucket(bucket.clone())
-                    .key(prefix.clone())
-                    .send()
+                    .bucket(&engine.bucket)
+                    .key(prefix.clone());
+                let head = engine
+                    .send(
+                        Api::HeadObject,
+                        &prefix,
+                        &format!("checking key {prefix}"),
+                        || head_request.clone().send(),
+                    )
                     .await;
                 let directory_form = format!("{prefix}{delimiter}");
-                let dir_check = client
+                let dir_request = engine
+                    .client
                     .list_objects_v2()
-                    .bucket(bucket)
+                    .bucket(&engine.bucket)
                     .prefix(directory_form.clone())
-                    .max_keys(1)
-                    .send()
+                    .max_keys(1);
+                let dir_check = engine
+                    .send(
+                        Api::ListObjectsV2,
+                        &directory_form,
+                        &format!("checking {directory_form}"),
+                        || dir_request.clone().send(),
+                    )
                     .await;
                 drop(permit);
 
                 let mut out: Vec<PrefixResult> = Vec::new();
-                if let Ok(o) = head {
-                    trace!(prefix, "prefix is actually an object");
-                    out.push(PrefixResult::Object(S3Object::from_head_object(
-                        prefix.clone(),
-                        o,
-                    )));
+                match head {
+                    Ok(_) if !bounds.contains(&prefix) => {}
+                    Ok(o) => {
+                        trace!(prefix, "prefix is actually an object");
+                        out.push(PrefixResult::Object(S3Object::from_head_object(
+                            prefix.clone(),
+                            o,
+                        )));
+                    }
+                    // not being a key is the common case, not a failure
+                    Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => {}
+                    Err(e) => out.push(PrefixResult::failed(&prefix, e)),
                 }
-                if let Ok(resp) = dir_check
-                    && resp.key_count.unwrap_or(0) > 0
-                {
-                    out.push(PrefixResult::Prefix(directory_form));
+                match dir_check {
+                    Ok(resp) if resp.key_count.unwrap_or(0) > 0 => {
+                        out.push(PrefixResult::Prefix(directory_form));
+                    }
+                    Ok(_) => {}
+                    Err(e) => out.push(PrefixResult::failed(&directory_form, e)),
                 }
                 if !out.is_empty() {
                     let _ = tx.send(out);
@@ -407,29 +666,51 @@ impl Engine for S3Engine {
     async fn get_all_children(
         &self,
         presult: PrefixSearchResult,
-        matcher: Arc<regex::Regex>,
+        matcher: Arc<KeyRegex>,
         status: &LiveStatus,
         tx: &UnboundedSender<Vec<PrefixResult>>,
         permit: Arc<Semaphore>,
     ) -> Result<()> {
-        for prefix in presult.prefixes {
-            let client = self.client.clone();
+        let mut tasks = JoinSet::new();
+        let mut task_prefixes = HashMap::new();
+        let ranges = presult
+            .prefixes
+            .into_iter()
+            .map(KeyRange::whole)
+            .chain(presult.ranges);
+        for mut range in ranges {
+            range.bounds = range.bounds.intersect(&self.bounds);
+            let engine = self.clone();
             let total_objects = Arc::clone(&status.total_objects);
             let seen_prefixes = Arc::clone(&status.seen_prefixes);
             let matcher = matcher.clone();
-            let bucket = self.bucket.clone();
             let tx = tx.clone();
             let permit = permit.clone().acquire_owned().await;
 
-            tokio::spawn(async move {
-                list_matching_objects(client, bucket, prefix.clone(), matcher, total_objects, tx)
-                    .await?;
+            let prefix = range.prefix.clone();
+            let handle = tasks.spawn(async move {
+                let result = list_matching_objects(engine, range, matcher, total_objects, tx).await;
                 drop(permit);
 
                 add_atomic(&seen_prefixes, 1);
-                Ok::<_, anyhow::Error>(())
+                result
             });
+            task_prefixes.insert(handle.id(), prefix);
         }
+        // Collect the listing results in the background so that a prefix
+        // that fails (or panics) is reported instead of silently yielding
+        // fewer results.
+        let failure_tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(joined) = tasks.join_next_with_id().await {
+                let failure = match joined {
+                    Ok((_, Ok(()))) => continue,
+                    Ok((id, Err(e))) => PrefixResult::failed(&task_prefixes[&id], e),
+                    Err(e) => PrefixResult::failed(&task_prefixes[&e.id()], e),
+                };
+                let _ = failure_tx.send(vec![failure]);
+            }
+        });
         tx.send(
             presult
                 .objects
@@ -449,16 +730,31 @@ pub(super) struct MockS3Engine {
     pub paths: Arc<Vec<String>>,
     pub calls: Arc<Mutex<Vec<(String, String)>>>, // (prefix, delimiter) pairs
     pub probe_calls: Arc<Mutex<Vec<(String, i32)>>>, // (prefix, max_keys) pairs
+    pub sample_calls: Arc<Mutex<Vec<(String, String)>>>, // (prefix, start_after) pairs
     /// Prefixes for which `scan_prefixes` should simulate the real
     /// `S3Engine`'s page-budget guard firing — i.e. return
     /// `truncated=true` with no sub-prefixes, as if the engine had
     /// paged through enough flat content to give up.
     pub force_truncate_prefixes: Arc<BTreeSet<String>>,
+    /// How many more requests should be throttled with a `SlowDown`
+    pub throttle_next: Arc<AtomicUsize>,
+    pub limiter: Arc<Limiter>,
+    pub api_calls: Arc<ApiCalls>,
+    pub phase: Phase,
+    pub bounds: KeyBounds,
 }
 
 #[cfg(test)]
 #[async_trait::async_trait]
 impl Engine for MockS3Engine {
+    fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+    }
+
+    fn set_bounds(&mut self, bounds: KeyBounds) {
+        self.bounds = bounds;
+    }
+
     async fn scan_prefixes(
         &mut self,
         prefix: &str,
@@ -469,6 +765,8 @@ impl Engine for MockS3Engine {
             .lock()
             .unwrap()
             .push((prefix.to_string(), delimiter.to_string()));
+        self.request(Api::ListObjectsV2, prefix, &format!("scanning {prefix}"))
+            .await?;
         if self.force_truncate_prefixes.contains(prefix) {
             // Simulate the real engine's flat-dense page-budget guard:
             // empty sub-prefix list with `truncated=true`.
@@ -493,16 +791,63 @@ impl Engine for MockS3Engine {
         Ok(found)
     }
 
+    async fn scan_pages(
+        &mut self,
+        prefix: &str,
+        delimiter: &str,
+        max_pages: usize,
+    ) -> Result<ScanResult> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), delimiter.to_string()));
+        let found = self.scan_prefixes_inner(prefix, delimiter)?;
+        // like S3, a page holds up to 1000 keys and prefixes in key order
+        let mut entries = found
+            .prefixes
+            .into_iter()
+            .map(|p| (p, None))
+            .chain(
+                found
+                    .objects
+                    .into_iter()
+                    .map(|o| (o.key().unwrap().to_string(), Some(o))),
+            )
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let pages = entries.len().div_ceil(1000).max(1);
+        for _ in 0..pages.min(max_pages) {
+            self.request(Api::ListObjectsV2, prefix, &format!("scanning {prefix}"))
+                .await?;
+        }
+        let truncated = pages > max_pages;
+        entries.truncate(max_pages * 1000);
+        let mut result = ScanResult {
+            truncated,
+            ..ScanResult::default()
+        };
+        for (key, object) in entries {
+            match object {
+                Some(object) => result.objects.push(object),
+                None => result.prefixes.push(key),
+            }
+        }
+        info!(prefix, max_pages, ?result, "MockS3 scanned pages");
+        Ok(result)
+    }
+
     async fn probe_prefix(&mut self, prefix: &str, max_keys: i32) -> Result<ScanResult> {
         self.probe_calls
             .lock()
             .unwrap()
             .push((prefix.to_string(), max_keys));
+        self.request(Api::ListObjectsV2, prefix, &format!("probing {prefix}"))
+            .await?;
         let max = max_keys as usize;
         let mut matched: Vec<&String> = self
             .paths
             .iter()
-            .filter(|p| p.starts_with(prefix))
+            .filter(|p| p.starts_with(prefix) && self.bounds.contains(p))
             .collect();
         let truncated = matched.len() > max;
         matched.truncate(max);
@@ -519,6 +864,23 @@ impl Engine for MockS3Engine {
         Ok(result)
     }
 
+    async fn first_key_after(&mut self, prefix: &str, start_after: &str) -> Result<Option<String>> {
+        self.sample_calls
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), start_after.to_string()));
+        self.request(Api::ListObjectsV2, prefix, &format!("sampling {prefix}"))
+            .await?;
+        let first = self
+            .paths
+            .iter()
+            .filter(|p| p.starts_with(prefix) && p.as_str() > start_after)
+            .min()
+            .cloned();
+        info!(prefix, start_after, ?first, "MockS3 sampled first key");
+        Ok(first)
+    }
+
     async fn check_prefixes<P>(
         &mut self,
         prefixes: P,
@@ -535,6 +897,8 @@ impl Engine for MockS3Engine {
         // a prefix is "valid" if any key in the bucket starts with it.
         // Independent of delimiter.
         for prefix in &prefixes {
+            self.request(Api::ListObjectsV2, prefix, &format!("checking {prefix}"))
+                .await?;
             if self.paths.iter().any(|k| k.starts_with(prefix)) {
                 valid_prefixes.insert(prefix.to_string());
             }
@@ -548,9 +912,9 @@ impl Engine for MockS3Engine {
     async fn get_exact(
         &self,
         presult: PrefixSearchResult,
-        delimiter: char,
+        delimiter: &str,
         _status: &LiveStatus,
-        matcher: &regex::Regex,
+        matcher: &KeyRegex,
         tx: &UnboundedSender<Vec<PrefixResult>>,
         _permit: Arc<Semaphore>,
     ) -> Result<()> {
@@ -564,12 +928,14 @@ impl Engine for MockS3Engine {
                 tx.send(vec![PrefixResult::Prefix(prefix.clone())])?;
                 continue;
             }
+            self.request(Api::HeadObject, prefix, &format!("checking key {prefix}"))
+                .await?;
             // For non-delim-suffixed prefixes, emit Object if it's an
             // exact key, Prefix(directory_form) if any key starts with
             // prefix+delim. Both can apply (a key plus a "directory" at
             // the same name, S3 is not a filesystem).
             let mut out: Vec<PrefixResult> = Vec::new();
-            if self.paths.iter().any(|k| k == prefix) {
+            if self.paths.iter().any(|k| k == prefix) && self.bounds.contains(prefix) {
                 out.push(PrefixResult::Object(S3Object::from(
                     Object::builder().key(prefix).build(),
                 )));
@@ -596,16 +962,38 @@ impl Engine for MockS3Engine {
     async fn get_all_children(
         &self,
         presult: PrefixSearchResult,
-        matcher: Arc<regex::Regex>,
+        matcher: Arc<KeyRegex>,
         _status: &LiveStatus,
         tx: &UnboundedSender<Vec<PrefixResult>>,
         _permit: Arc<Semaphore>,
     ) -> Result<()> {
         for prefix in &presult.prefixes {
+            self.request(Api::ListObjectsV2, prefix, &format!("listing {prefix}"))
+                .await?;
+            let matching: Vec<PrefixResult> = self
+                .paths
+                .iter()
+                .filter(|k| {
+                    k.starts_with(prefix.as_str()) && self.bounds.contains(k) && matcher.is_match(k)
+                })
+                .sorted()
+                .map(|k| PrefixResult::Object(S3Object::from(Object::builder().key(k).build())))
+                .collect();
+            tx.send(matching)?;
+        }
+        for range in &presult.ranges {
+            self.request(
+                Api::ListObjectsV2,
+                &range.prefix,
+                &format!("listing {}", range.prefix),
+            )
+            .await?;
             let matching: Vec<PrefixResult> = self
                 .paths
                 .iter()
-                .filter(|k| k.starts_with(prefix.as_str()) && matcher.is_match(k))
+                .filter(|k| range.contains(k) && self.bounds.contains(k) && matcher.is_match(k))
+                // S3 lists keys in order
+                .sorted()
                 .map(|k| PrefixResult::Object(S3Object::from(Object::builder().key(k).build())))
                 .collect();
             tx.send(matching)?;
@@ -629,10 +1017,50 @@ impl MockS3Engine {
             paths: Arc::new(paths),
             calls: Arc::new(Mutex::new(Vec::new())),
             probe_calls: Arc::new(Mutex::new(Vec::new())),
+            sample_calls: Arc::new(Mutex::new(Vec::new())),
             force_truncate_prefixes: Arc::new(BTreeSet::new()),
+            throttle_next: Arc::new(AtomicUsize::new(0)),
+            limiter: Arc::new(Limiter::new(64)),
+            api_calls: Arc::default(),
+            phase: Phase::Discovery,
+            bounds: KeyBounds::default(),
         }
     }
 
+    /// Answer the next `count` requests with a `SlowDown`, like S3 does
+    /// while it scales up a prefix
+    pub fn with_throttling(self, count: usize) -> Self {
+        self.throttle_next.store(count, Ordering::Relaxed);
+        self
+    }
+
+    /// Go through the same limiter and retries as a real request would
+    async fn request(&self, api: Api, prefix: &str, what: &str) -> Result<()> {
+        retry::retry_limited(&self.limiter, prefix, what, || async {
+            self.api_calls.record(self.phase, api);
+            let throttled = self
+                .throttle_next
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            if throttled {
+                let err = ListObjectsV2Error::generic(
+                    aws_smithy_types::error::ErrorMetadata::builder()
+                        .code("SlowDown")
+                        .build(),
+                );
+                let raw = HttpResponse::new(
+                    503.try_into().expect("503 is a valid status"),
+                    aws_smithy_types::body::SdkBody::empty(),
+                );
+                Err(SdkError::service_error(err, raw))
+            } else {
+                Ok(())
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
     /// Make `scan_prefixes` return `truncated=true` with no sub-prefixes
     /// for any of `prefixes`, simulating the real `S3Engine`'s
     /// flat-dense page-budget early exit.
@@ -699,13 +1127,16 @@ impl MockS3Engine {
         // Real S3 returns each `CommonPrefix` once; multiple keys
         // sharing a parent dir don't multiply the listing.
         let mut prefix_set: BTreeSet<String> = BTreeSet::new();
+        // like S3, group the keys after the start of the bounds and stop at
+        // the first result past their end
+        let after_start = |p: &str| self.bounds.start_after.as_deref().is_none_or(|a| p > a);
         self.paths
             .iter()
-            .filter(|p| p.starts_with(prefix))
+            .filter(|p| p.starts_with(prefix) && after_start(p))
             .for_each(|p| {
                 let matched_prefix = if let Some(end) = p[prefix.len()..].find(delimiter) {
                     // only return the prefix up to the delimiter
-                    p[..prefix.len() + end + 1].to_string()
+                    p[..prefix.len() + end + delimiter.len()].to_string()
                 } else {
                     p.to_string()
                 };
@@ -716,6 +1147,8 @@ impl MockS3Engine {
                 }
             });
 
+        objects.retain(|o| !self.bounds.is_past_end(o.key().unwrap()));
+        prefix_set.retain(|p| !self.bounds.is_past_end(p));
         Ok(ScanResult {
             prefixes: prefix_set.into_iter().collect(),
             objects,
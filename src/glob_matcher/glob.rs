@@ -1,7 +1,10 @@
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context as _, Result, anyhow, bail};
 use itertools::Itertools as _;
 
-use super::prefix_join;
+#[cfg(test)]
+use super::key_regex::KeyRegex;
+use super::key_regex::{delimiter_class, literal_re};
+use super::{GlobDialect, date_range, prefix_join};
 
 /// A single part of a glob pattern
 ///
@@ -17,6 +20,13 @@ pub(crate) enum Glob {
     SyntheticAny,
     /// A literal string or group of alternatives, like `foo` or `{foo,bar}` or `[abc]`
     Choice { raw: String, allowed: Vec<String> },
+    /// A `Choice` that must be matched case-insensitively, but has too many
+    /// case variants to enumerate.
+    ///
+    /// Instead of appending each variant and checking that it exists, the
+    /// matcher scans with the delimiter and keeps whatever casing of the
+    /// alternatives is actually in the bucket.
+    FoldedChoice { raw: String, allowed: Vec<String> },
     /// A recursive glob, always `**`
     Recursive,
 }
@@ -27,6 +37,7 @@ impl Glob {
             Glob::Any { raw, .. } => format!("Any({raw})"),
             Glob::Recursive => "Recursive(**)".to_string(),
             Glob::Choice { raw, .. } => format!("Choice({raw})"),
+            Glob::FoldedChoice { raw, .. } => format!("FoldedChoice({raw})"),
             Glob::SyntheticAny => "SyntheticAny".to_string(),
         }
     }
@@ -35,7 +46,7 @@ impl Glob {
         match self {
             Glob::Any { raw, .. } => raw,
             Glob::Recursive => "**",
-            Glob::Choice { raw, .. } => raw,
+            Glob::Choice { raw, .. } | Glob::FoldedChoice { raw, .. } => raw,
             Glob::SyntheticAny => "",
         }
     }
@@ -44,7 +55,7 @@ impl Glob {
         match self {
             Glob::Any { raw, .. } => raw.len(),
             Glob::Recursive => 2,
-            Glob::Choice { raw, .. } => raw.len(),
+            Glob::Choice { raw, .. } | Glob::FoldedChoice { raw, .. } => raw.len(),
             Glob::SyntheticAny => 0,
         }
     }
@@ -68,6 +79,12 @@ impl Glob {
         matches!(self, Glob::Recursive)
     }
 
+    /// True if this is a case-insensitive choice that must be resolved by
+    /// scanning rather than by enumerating its alternatives
+    pub(crate) fn is_folded(&self) -> bool {
+        matches!(self, Glob::FoldedChoice { .. })
+    }
+
     pub(crate) fn re_string(&self, delimiter: &str, cross_delim: bool) -> String {
         match self {
             Glob::Any {
@@ -79,42 +96,118 @@ impl Glob {
                     if cross_delim {
                         format!("[^{chars}]")
                     } else {
-                        format!("[^{chars}{delimiter}]")
+                        format!("[^{chars}{}]", delimiter_class(delimiter))
                     }
                 }
                 ("?", _) => {
                     if cross_delim {
                         ".".to_string()
                     } else {
-                        format!("[^{delimiter}]")
+                        format!("[^{}]", delimiter_class(delimiter))
                     }
                 }
-                ("*", _) => format!("[^{delimiter}]*"),
+                ("*", _) => without_delimiter(delimiter),
                 (_, _) => panic!("invalid any pattern: {raw}"),
             },
-            Glob::Choice { allowed, .. } => {
+            Glob::Choice { allowed, .. } | Glob::FoldedChoice { allowed, .. } => {
                 if allowed.is_empty() {
                     "".to_string()
                 } else if allowed.len() == 1 {
-                    regex::escape(&allowed[0])
+                    literal_re(&allowed[0], delimiter)
                 } else {
-                    let re_alts = allowed.iter().map(|a| regex::escape(a)).join("|");
+                    let re_alts = allowed.iter().map(|a| literal_re(a, delimiter)).join("|");
                     format!("({})", re_alts)
                 }
             }
             Glob::Recursive => ".*".to_string(),
-            Glob::SyntheticAny => format!("[^{delimiter}]*"),
+            Glob::SyntheticAny => without_delimiter(delimiter),
         }
     }
 
     /// True if this glob is a literal part and ends with the delimiter
     pub(crate) fn ends_with(&self, delimiter: &str) -> bool {
         match self {
-            Glob::Choice { allowed, .. } => allowed.iter().any(|a| a.ends_with(delimiter)),
+            Glob::Choice { allowed, .. } | Glob::FoldedChoice { allowed, .. } => {
+                allowed.iter().any(|a| a.ends_with(delimiter))
+            }
+            _ => false,
+        }
+    }
+
+    /// True if this is a literal part and every alternative ends with the delimiter
+    pub(crate) fn all_end_with(&self, delimiter: &str) -> bool {
+        match self {
+            Glob::Choice { allowed, .. } | Glob::FoldedChoice { allowed, .. } => {
+                allowed.iter().all(|a| a.ends_with(delimiter))
+            }
+            _ => false,
+        }
+    }
+
+    /// True if this is a literal part and every alternative starts with the delimiter
+    pub(crate) fn all_start_with(&self, delimiter: &str) -> bool {
+        match self {
+            Glob::Choice { allowed, .. } | Glob::FoldedChoice { allowed, .. } => {
+                allowed.iter().all(|a| a.starts_with(delimiter))
+            }
             _ => false,
         }
     }
 
+    /// A copy of this part with a leading delimiter removed from each alternative
+    pub(crate) fn strip_leading(&self, delimiter: &str) -> Glob {
+        let strip = |allowed: &[String]| {
+            allowed
+                .iter()
+                .map(|a| a.strip_prefix(delimiter).unwrap_or(a).to_string())
+                .collect()
+        };
+        match self {
+            Glob::Choice { raw, allowed } => Glob::Choice {
+                raw: raw.clone(),
+                allowed: strip(allowed),
+            },
+            Glob::FoldedChoice { raw, allowed } => Glob::FoldedChoice {
+                raw: raw.clone(),
+                allowed: strip(allowed),
+            },
+            other => other.clone(),
+        }
+    }
+
+    /// Make a literal part match case-insensitively
+    ///
+    /// Choices whose alternatives have at most `max_variants` case variants
+    /// in total are expanded to list every variant, so they keep narrowing
+    /// prefixes exactly like a literal does. Larger choices become a
+    /// [`Glob::FoldedChoice`]. Other parts are left alone, the compiled
+    /// regex takes care of them.
+    pub(crate) fn fold_case(&mut self, max_variants: usize) {
+        let Glob::Choice { raw, allowed } = self else {
+            return;
+        };
+        match case_variants(allowed, max_variants) {
+            Some(variants) => *allowed = variants,
+            None => {
+                *self = Glob::FoldedChoice {
+                    raw: std::mem::take(raw),
+                    allowed: std::mem::take(allowed),
+                }
+            }
+        }
+    }
+
+    /// How many alternatives [`Self::combine_with`] would leave this part
+    /// with
+    pub(crate) fn combined_len(&self, other: &Glob) -> usize {
+        match (self, other) {
+            (Glob::Choice { allowed: sa, .. }, Glob::Choice { allowed: oa, .. }) => {
+                sa.len().saturating_mul(oa.len())
+            }
+            _ => panic!("Cannot combine glob with non-choice glob"),
+        }
+    }
+
     /// Create the combination of two glob patterns
     ///
     /// This will merge all of other into self
@@ -135,19 +228,72 @@ impl Glob {
     }
 
     #[cfg(test)]
-    pub(crate) fn re(&self, delimiter: &str) -> regex::Regex {
-        use regex::Regex;
-
+    pub(crate) fn re(&self, delimiter: &str) -> KeyRegex {
         // Tests historically asserted the strict per-segment behavior; keep
         // that here so the existing assertions don't shift. The user-facing
         // default is `cross_delim = true`, but the test helper preserves
         // the stricter semantics it was originally written against.
-        Regex::new(&self.re_string(delimiter, false)).unwrap()
+        KeyRegex::new(&self.re_string(delimiter, false), delimiter).unwrap()
+    }
+}
+
+/// Every upper/lower case spelling of each of `alternatives`
+///
+/// Returns `None` if there would be more than `max_variants` of them.
+fn case_variants(alternatives: &[String], max_variants: usize) -> Option<Vec<String>> {
+    let mut total = 0usize;
+    for alt in alternatives {
+        let mut count = 1usize;
+        for c in alt.chars() {
+            count = count.checked_mul(char_variants(c).len())?;
+            if count > max_variants {
+                return None;
+            }
+        }
+        total += count;
+        if total > max_variants {
+            return None;
+        }
+    }
+
+    let mut variants = Vec::with_capacity(total);
+    for alt in alternatives {
+        let mut spellings = vec![String::new()];
+        for c in alt.chars() {
+            let options = char_variants(c);
+            spellings = spellings
+                .iter()
+                .flat_map(|s| options.iter().map(move |o| format!("{s}{o}")))
+                .collect();
+        }
+        variants.extend(spellings);
+    }
+    Some(variants.into_iter().unique().collect())
+}
+
+/// The distinct spellings of `c` under case conversion, `c` itself first
+fn char_variants(c: char) -> Vec<String> {
+    [
+        c.to_string(),
+        c.to_lowercase().collect(),
+        c.to_uppercase().collect(),
+    ]
+    .into_iter()
+    .unique()
+    .collect()
+}
+
+/// A regex matching any run of characters that does not contain `delimiter`
+pub(crate) fn without_delimiter(delimiter: &str) -> String {
+    if delimiter.is_empty() {
+        ".*".to_string()
+    } else {
+        format!("[^{}]*", delimiter_class(delimiter))
     }
 }
 
 /// Convert a single pattern into something useful for searching
-pub(super) fn parse_pattern(raw: &str) -> Result<Glob> {
+pub(super) fn parse_pattern(raw: &str, dialect: GlobDialect) -> Result<Glob> {
     let mut iter = raw.chars().peekable();
     let mut raw = String::new();
     Ok(match iter.next().expect("next char must exist") {
@@ -158,6 +304,10 @@ pub(super) fn parse_pattern(raw: &str) -> Result<Glob> {
         },
         '*' => {
             if matches!(iter.peek(), Some('*')) {
+                iter.next();
+                if dialect != GlobDialect::S3glob && matches!(iter.peek(), Some('*')) {
+                    bail!("Too many consecutive `*`, `**` is the longest valid run");
+                }
                 Glob::Recursive
             } else {
                 Glob::Any {
@@ -190,6 +340,11 @@ pub(super) fn parse_pattern(raw: &str) -> Result<Glob> {
             if !ended {
                 bail!("Alternation has no closing brace (missing '}}'): {}", raw);
             }
+            // the format may contain commas, so use the raw token body
+            if let Some(spec) = raw[1..raw.len() - 1].strip_prefix("date:") {
+                alternatives = date_range::expand(spec, chrono::Utc::now().date_naive())
+                    .with_context(|| format!("Expanding date range {raw}"))?;
+            }
             Glob::Choice {
                 raw,
                 allowed: alternatives,
@@ -267,6 +422,7 @@ pub(super) fn parse_pattern(raw: &str) -> Result<Glob> {
 #[cfg(test)]
 mod tests {
     use assert2::{assert, check};
+    use rstest::rstest;
 
     use super::*;
     use crate::glob_matcher::S3GlobMatcher;
@@ -595,10 +751,227 @@ mod tests {
         assert!(err_msg.contains("Empty character class: []"));
     }
 
+    #[test]
+    fn test_ignore_case_expands_variants() -> Result<()> {
+        let mut scanner = S3GlobMatcher::parse("a/{x,y1}*".to_string(), "/", false)?;
+        scanner.set_ignore_case(16);
+
+        assert_scanner_part!(
+            &scanner.parts[0],
+            Choice(vec![
+                "a/x", "a/X", "A/x", "A/X", "a/y1", "a/Y1", "A/y1", "A/Y1"
+            ])
+        );
+        assert!(scanner.matches_key("A/Y1-anything"));
+        assert!(!scanner.matches_key("b/x"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_case_folds_over_limit() -> Result<()> {
+        let mut scanner = S3GlobMatcher::parse("invoice/*.csv".to_string(), "/", false)?;
+        scanner.set_ignore_case(16);
+
+        assert!(scanner.parts[0].is_folded());
+        // `.csv` only has 8 spellings
+        assert!(!scanner.parts[2].is_folded());
+        assert!(scanner.matches_key("INVOICE/a.CSV"));
+        assert!(scanner.matches_key("Invoice/b.csv"));
+        assert!(!scanner.matches_key("Invoices/b.csv"));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(GlobDialect::Globset, "a**b")]
+    #[case(GlobDialect::Globset, "a/**b")]
+    #[case(GlobDialect::Globset, "a**/b")]
+    #[case(GlobDialect::Globset, "a/***/b")]
+    #[case(GlobDialect::Globset, "**.txt")]
+    #[case(GlobDialect::Gitignore, "logs/**.txt")]
+    fn test_parse_strict_recursive_must_be_component(
+        #[case] dialect: GlobDialect,
+        #[case] pattern: &str,
+    ) {
+        let result = S3GlobMatcher::parse_with_dialect(pattern.to_string(), "/", true, dialect);
+        assert!(result.is_err(), "{pattern} should not parse");
+    }
+
+    #[test]
+    fn test_parse_s3glob_recursive_anywhere() -> Result<()> {
+        let scanner = S3GlobMatcher::parse("a**b".to_string(), "/", true)?;
+        assert!(scanner.matches_key("a/x/y/b"));
+        let scanner = S3GlobMatcher::parse("a/**/*b".to_string(), "/", true)?;
+        // the delimiter after `**` is optional
+        assert!(scanner.matches_key("a/xb"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_globset_recursive() -> Result<()> {
+        let scanner = S3GlobMatcher::parse_with_dialect(
+            "a/**/b".to_string(),
+            "/",
+            true,
+            GlobDialect::Globset,
+        )?;
+        assert!(scanner.matches_key("a/b"));
+        assert!(scanner.matches_key("a/x/b"));
+        assert!(scanner.matches_key("a/x/y/b"));
+        assert!(!scanner.matches_key("a/xb"));
+        assert!(!scanner.matches_key("a/x/yb"));
+
+        let scanner = S3GlobMatcher::parse_with_dialect(
+            "**/*.txt".to_string(),
+            "/",
+            true,
+            GlobDialect::Globset,
+        )?;
+        assert!(scanner.matches_key("a.txt"));
+        assert!(scanner.matches_key("x/y/a.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_globset_single_segment_wildcards() -> Result<()> {
+        // cross_delim is ignored by the strict dialects
+        let scanner = S3GlobMatcher::parse_with_dialect(
+            "a?b/[!x]/*".to_string(),
+            "/",
+            true,
+            GlobDialect::Globset,
+        )?;
+        assert!(scanner.matches_key("azb/y/c"));
+        assert!(!scanner.matches_key("a/b/y/c"));
+        assert!(!scanner.matches_key("azb///c"));
+        assert!(!scanner.matches_key("azb/y/c/d"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_gitignore_anchoring() -> Result<()> {
+        let unanchored = S3GlobMatcher::parse_with_dialect(
+            "*.log".to_string(),
+            "/",
+            true,
+            GlobDialect::Gitignore,
+        )?;
+        assert!(unanchored.matches_key("x.log"));
+        assert!(unanchored.matches_key("a/b/x.log"));
+        assert!(!unanchored.is_complete());
+
+        let anchored = S3GlobMatcher::parse_with_dialect(
+            "/*.log".to_string(),
+            "/",
+            true,
+            GlobDialect::Gitignore,
+        )?;
+        assert!(anchored.matches_key("x.log"));
+        assert!(!anchored.matches_key("a/x.log"));
+        assert!(anchored.pattern() == "*.log");
+
+        let with_delim = S3GlobMatcher::parse_with_dialect(
+            "logs/*.log".to_string(),
+            "/",
+            true,
+            GlobDialect::Gitignore,
+        )?;
+        assert!(with_delim.matches_key("logs/x.log"));
+        assert!(!with_delim.matches_key("a/logs/x.log"));
+        Ok(())
+    }
+
     #[test]
     fn test_parse_range_dash_only() -> Result<()> {
         let scanner = S3GlobMatcher::parse("[-]".to_string(), "/", false)?;
         assert_scanner_part!(&scanner.parts[0], Choice(vec!["-"]));
         Ok(())
     }
+
+    #[rstest]
+    #[case("::")]
+    #[case("->")]
+    #[case("aba")]
+    fn test_without_delimiter_multi_char(#[case] delimiter: &str) {
+        let re = KeyRegex::new(&format!("^{}$", without_delimiter(delimiter)), delimiter).unwrap();
+        let alphabet = delimiter.chars().chain(['x']).unique().collect::<Vec<_>>();
+        let mut candidates = vec![String::new()];
+        for _ in 0..6 {
+            candidates = candidates
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |c| format!("{s}{c}")))
+                .collect();
+            for candidate in &candidates {
+                check!(
+                    re.is_match(candidate) == !candidate.contains(delimiter),
+                    "{candidate:?} with delimiter {delimiter:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_multi_char_delimiter() -> Result<()> {
+        let scanner = S3GlobMatcher::parse("foo::*::b?r".to_string(), "::", false)?;
+        assert_scanner_part!(&scanner.parts[0], OneChoice("foo::"));
+        assert_scanner_part!(&scanner.parts[1], Any("*"));
+        assert_scanner_part!(&scanner.parts[2], OneChoice("::b"));
+
+        // a lone `:` is not the delimiter, so wildcards may match it
+        check!(scanner.matches_key("foo::x:y::bar"));
+        check!(scanner.matches_key("foo::x::b:r"));
+        check!(scanner.matches_key("foo::::bar"));
+        check!(!scanner.matches_key("foo::x::y::bar"));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("::", "a?b", "a:b")]
+    #[case("::", "a[!x]b", "a:b")]
+    #[case("->", "a*b", "a-b")]
+    #[case("->", "a*b", "a>-b")]
+    #[case("->", "a?b", "a>b")]
+    fn test_wildcards_match_single_delimiter_chars(
+        #[case] delimiter: &str,
+        #[case] pattern: &str,
+        #[case] key: &str,
+    ) -> Result<()> {
+        for dialect in [GlobDialect::S3glob, GlobDialect::Globset] {
+            let scanner =
+                S3GlobMatcher::parse_with_dialect(pattern.to_string(), delimiter, false, dialect)?;
+            check!(scanner.matches_key(key), "{dialect:?}");
+            let across = key.replacen(&key[1..2], delimiter, 1);
+            check!(!scanner.matches_key(&across), "{dialect:?} on {across:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_date_range() -> Result<()> {
+        let scanner = S3GlobMatcher::parse(
+            "logs/dt={date:2024-05-31..2024-06-01}/*.gz".to_string(),
+            "/",
+            false,
+        )?;
+        assert_scanner_part!(
+            &scanner.parts[0],
+            Choice(vec!["logs/dt=2024-05-31/", "logs/dt=2024-06-01/"])
+        );
+        assert_scanner_part!(&scanner.parts[1], Any("*"));
+
+        // formats may contain commas and the delimiter
+        let scanner = S3GlobMatcher::parse(
+            "{date:2023-12-31..2024-01-01:%Y/%m,%d}".to_string(),
+            "/",
+            false,
+        )?;
+        assert_scanner_part!(&scanner.parts[0], Choice(vec!["2023/12,31", "2024/01,01"]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_date_range_invalid() {
+        let err = S3GlobMatcher::parse("{date:2024-06-02..2024-06-01}".to_string(), "/", false)
+            .unwrap_err();
+        check!(format!("{err:#}").contains("starts after it ends"));
+    }
 }
@@ -0,0 +1,418 @@
+//! Key ranges: bounding listings with `StartAfter`, and splitting a flat,
+//! dense prefix into ranges that can be listed in parallel
+//!
+//! S3 can't tell us how many keys are under a prefix, but `StartAfter` lets
+//! us ask for the first key after any string. Bisecting the key space with
+//! one-key probes finds split points where keys actually are, so each range
+//! ends up with a share of the prefix instead of an even share of the
+//! (mostly empty) space of possible keys.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context as _, Result};
+use tracing::debug;
+
+use super::engine::Engine;
+use super::fan_out_per_prefix;
+
+/// The most rounds of probes spent partitioning a single prefix
+///
+/// A round that finds the top half of a range empty only narrows the range,
+/// that takes about seven rounds per character of the key that the split
+/// point has to move past.
+const MAX_ROUNDS: usize = 64;
+
+/// The characters split points are built from, in byte order
+const FIRST_CHAR: u8 = b' ';
+const LAST_CHAR: u8 = b'~';
+const BASE: u64 = (LAST_CHAR - FIRST_CHAR + 1) as u64;
+
+/// How many characters past the common prefix a split point may have
+const DIGITS: usize = 4;
+
+/// The keys that sort strictly between two bounds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct KeyBounds {
+    /// Only keys after this one, passed to S3 as `StartAfter`
+    pub(crate) start_after: Option<String>,
+    /// Only keys before this one, S3 has no such parameter so listing stops
+    /// at the first page that reaches it
+    pub(crate) end_before: Option<String>,
+}
+
+impl KeyBounds {
+    /// The bounds of every key that starts with one of `alternatives`
+    pub(crate) fn of_alternatives(alternatives: &[String]) -> Self {
+        if alternatives.iter().any(|alt| alt.is_empty()) {
+            return Self::default();
+        }
+        let start_after = alternatives.iter().min().and_then(|min| before(min));
+        let end_before = alternatives
+            .iter()
+            .map(|alt| after_all_starting_with(alt))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|ends| ends.into_iter().max());
+        Self {
+            start_after,
+            end_before,
+        }
+    }
+
+    /// The keys that are within both `self` and `other`
+    pub(crate) fn intersect(&self, other: &KeyBounds) -> KeyBounds {
+        let start_after = match (&self.start_after, &other.start_after) {
+            (Some(a), Some(b)) => Some(a.max(b).clone()),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        let end_before = match (&self.end_before, &other.end_before) {
+            (Some(a), Some(b)) => Some(a.min(b).clone()),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        KeyBounds {
+            start_after,
+            end_before,
+        }
+    }
+
+    pub(crate) fn is_unbounded(&self) -> bool {
+        self.start_after.is_none() && self.end_before.is_none()
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.start_after.as_deref().is_none_or(|after| key > after) && !self.is_past_end(key)
+    }
+
+    /// True if `key` and every key after it are outside of the bounds
+    pub(crate) fn is_past_end(&self, key: &str) -> bool {
+        self.end_before.as_deref().is_some_and(|end| key >= end)
+    }
+
+    /// True if some key that starts with `prefix` may be within the bounds
+    pub(crate) fn may_contain_prefix(&self, prefix: &str) -> bool {
+        let after_start = self
+            .start_after
+            .as_deref()
+            .is_none_or(|after| prefix > after || after.starts_with(prefix));
+        after_start && !self.is_past_end(prefix)
+    }
+}
+
+/// A string just below `key`, to use as `StartAfter` when listing from `key`
+///
+/// The only strings between the two start with the result, so listing
+/// after it includes at most a few keys too many.
+fn before(key: &str) -> Option<String> {
+    let mut chars = key.chars();
+    let last = chars.next_back()?;
+    let rest = chars.as_str();
+    Some(match (last as u32).checked_sub(1) {
+        Some(prev) => {
+            // the only invalid chars are the surrogates, skip below them
+            let prev = char::from_u32(prev).unwrap_or('\u{d7ff}');
+            format!("{rest}{prev}{}", char::MAX)
+        }
+        None => rest.to_string(),
+    })
+}
+
+/// The smallest string that is above every string starting with `prefix`
+fn after_all_starting_with(prefix: &str) -> Option<String> {
+    let mut chars = prefix.chars();
+    let last = chars.next_back()?;
+    let rest = chars.as_str();
+    let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+    match next {
+        Some(next) => Some(format!("{rest}{next}")),
+        None => after_all_starting_with(rest),
+    }
+}
+
+/// The keys under `prefix` that are within `bounds`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KeyRange {
+    pub(crate) prefix: String,
+    pub(crate) bounds: KeyBounds,
+}
+
+impl KeyRange {
+    /// Every key under `prefix`
+    pub(crate) fn whole(prefix: String) -> Self {
+        Self {
+            prefix,
+            bounds: KeyBounds::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix) && self.bounds.contains(key)
+    }
+}
+
+/// A range that might still be split
+struct Pending {
+    range: KeyRange,
+    /// The first key in the range
+    first: String,
+    /// Every key in the range is at most this, once probes have found the
+    /// top of the range empty
+    hi: Option<String>,
+}
+
+/// Split the keys under `prefix` into at most `target` disjoint ranges
+///
+/// `first` is the first key under `prefix`. Each round probes the midpoint
+/// of every range that can still be split: if there is a key after the
+/// midpoint the range is split in two, otherwise its top half is empty and
+/// the next midpoint is taken from the bottom half.
+pub(super) async fn partition<E: Engine + Clone>(
+    engine: &E,
+    prefix: &str,
+    first: String,
+    target: usize,
+    max_parallelism: usize,
+) -> Result<Vec<KeyRange>> {
+    let mut done = Vec::new();
+    let mut pending = vec![Pending {
+        range: KeyRange::whole(prefix.to_string()),
+        first,
+        hi: None,
+    }];
+    let mut rounds = 0;
+    let mut probes = 0;
+    while rounds < MAX_ROUNDS && !pending.is_empty() && done.len() + pending.len() < target {
+        rounds += 1;
+        // every split adds one range, so don't probe more than can be used
+        let budget = target - done.len() - pending.len();
+        let mut by_mid = BTreeMap::new();
+        let mut next = Vec::new();
+        for p in pending {
+            if by_mid.len() >= budget {
+                next.push(p);
+                continue;
+            }
+            match midpoint(prefix, &p.first, p.hi.as_deref()) {
+                Some(mid) => {
+                    by_mid.insert(mid, p);
+                }
+                None => done.push(p.range),
+            }
+        }
+
+        let mids: BTreeSet<String> = by_mid.keys().cloned().collect();
+        let results = {
+            let engine = engine.clone();
+            let prefix = prefix.to_string();
+            fan_out_per_prefix(&mids, max_parallelism, move |mid| {
+                let mut engine = engine.clone();
+                let prefix = prefix.clone();
+                async move { engine.first_key_after(&prefix, &mid).await }
+            })
+            .await
+        };
+        probes += results.len();
+        for (mid, result) in results {
+            let found = result.context("sampling key range")?;
+            let p = by_mid
+                .remove(&mid)
+                .expect("every probe is for a pending range");
+            match found.filter(|key| !p.range.bounds.is_past_end(key)) {
+                Some(key) => {
+                    // nothing is between `mid` and `key`, so ending the
+                    // bottom half before `key` is the same as ending it
+                    // after `mid`
+                    next.push(Pending {
+                        range: KeyRange {
+                            prefix: prefix.to_string(),
+                            bounds: KeyBounds {
+                                start_after: p.range.bounds.start_after,
+                                end_before: Some(key.clone()),
+                            },
+                        },
+                        first: p.first,
+                        hi: Some(mid.clone()),
+                    });
+                    next.push(Pending {
+                        range: KeyRange {
+                            prefix: prefix.to_string(),
+                            bounds: KeyBounds {
+                                start_after: Some(mid),
+                                end_before: p.range.bounds.end_before,
+                            },
+                        },
+                        first: key,
+                        hi: p.hi,
+                    });
+                }
+                None => next.push(Pending { hi: Some(mid), ..p }),
+            }
+        }
+        pending = next;
+    }
+    done.extend(pending.into_iter().map(|p| p.range));
+    done.sort_by(|a, b| a.bounds.start_after.cmp(&b.bounds.start_after));
+    debug!(
+        prefix,
+        range_count = done.len(),
+        rounds,
+        probes,
+        "partitioned flat prefix into key ranges"
+    );
+    Ok(done)
+}
+
+/// A string under `prefix` roughly halfway between `lo` and `hi`
+///
+/// `hi` defaults to the end of `prefix`. Past the part `lo` and `hi` have in
+/// common the strings are read as base-95 numbers of printable ASCII, so the
+/// result is valid UTF-8 and safe to send to S3. Returns `None` if there is
+/// no such string strictly between `lo` and `hi` within [`DIGITS`]
+/// characters.
+fn midpoint(prefix: &str, lo: &str, hi: Option<&str>) -> Option<String> {
+    let mut start = match hi {
+        Some(hi) => lo
+            .bytes()
+            .zip(hi.bytes())
+            .take_while(|(a, b)| a == b)
+            .count(),
+        None => prefix.len(),
+    };
+    while !lo.is_char_boundary(start) {
+        start -= 1;
+    }
+    let value = |s: &str| {
+        (start..start + DIGITS).fold(0, |acc, i| {
+            let digit = s.as_bytes().get(i).map_or(0, |b| {
+                u64::from(b.clamp(&FIRST_CHAR, &LAST_CHAR) - FIRST_CHAR)
+            });
+            acc * BASE + digit
+        })
+    };
+    let lo_value = value(lo);
+    let hi_value = hi.map_or(BASE.pow(DIGITS as u32), value);
+    let mut mid_value = lo_value.midpoint(hi_value);
+
+    let mut digits = [0u8; DIGITS];
+    for digit in digits.iter_mut().rev() {
+        *digit = FIRST_CHAR + (mid_value % BASE) as u8;
+        mid_value /= BASE;
+    }
+    let mut mid = lo[..start].to_string();
+    mid.extend(digits.iter().map(|&b| char::from(b)));
+    let mid = mid.trim_end_matches(char::from(FIRST_CHAR));
+
+    let in_range = mid.starts_with(prefix) && mid > lo && hi.is_none_or(|hi| mid < hi);
+    in_range.then(|| mid.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::unbounded("p/", "p/0000", None, "p/W")]
+    #[case::shared_prefix("p/", "p/0000", Some("p/1"), "p/0W")]
+    #[case::narrowed("p/", "p/0000", Some("p/0W"), "p/0C")]
+    fn test_midpoint(
+        #[case] prefix: &str,
+        #[case] lo: &str,
+        #[case] hi: Option<&str>,
+        #[case] expected: &str,
+    ) {
+        let mid = midpoint(prefix, lo, hi).unwrap();
+        check!(mid.starts_with(expected), "{mid:?}");
+        check!(mid.as_str() > lo);
+        check!(hi.is_none_or(|hi| mid.as_str() < hi));
+    }
+
+    #[rstest]
+    #[case::adjacent("p/a", Some("p/a "))]
+    #[case::equal("p/a", Some("p/a"))]
+    #[case::past_the_end("p/\u{7f}", None)]
+    fn test_midpoint_none(#[case] lo: &str, #[case] hi: Option<&str>) {
+        check!(midpoint("p/", lo, hi) == None);
+    }
+
+    #[test]
+    fn test_midpoint_non_ascii_is_a_valid_split() {
+        let mid = midpoint("p/", "p/é1", Some("p/é9")).unwrap();
+        check!(mid.as_str() > "p/é1");
+        check!(mid.as_str() < "p/é9");
+    }
+
+    #[test]
+    fn test_key_range_contains() {
+        let range = KeyRange {
+            prefix: "p/".to_string(),
+            bounds: KeyBounds {
+                start_after: Some("p/b".to_string()),
+                end_before: Some("p/d".to_string()),
+            },
+        };
+        check!(!range.contains("p/b"));
+        check!(range.contains("p/b0"));
+        check!(range.contains("p/c"));
+        check!(!range.contains("p/d"));
+        check!(!range.contains("q/c"));
+        check!(range.bounds.is_past_end("p/e"));
+    }
+
+    #[test]
+    fn test_bounds_of_character_class() {
+        let alternatives = ["6", "7", "8", "9"].map(|d| format!("logs/2024-0{d}"));
+        let bounds = KeyBounds::of_alternatives(&alternatives);
+        check!(bounds.start_after.as_deref() == Some("logs/2024-05\u{10ffff}"));
+        check!(bounds.end_before.as_deref() == Some("logs/2024-0:"));
+        check!(!bounds.contains("logs/2024-05-31.json"));
+        check!(bounds.contains("logs/2024-06"));
+        check!(bounds.contains("logs/2024-09-30.json"));
+        check!(!bounds.contains("logs/2024-10-01.json"));
+    }
+
+    #[test]
+    fn test_bounds_of_alternatives_of_different_lengths() {
+        let alternatives = ["ab".to_string(), "abc".to_string()];
+        let bounds = KeyBounds::of_alternatives(&alternatives);
+        // everything starting with "ab" is in bounds, including "abz"
+        check!(bounds.end_before.as_deref() == Some("ac"));
+        check!(bounds.contains("abz"));
+        check!(bounds.contains("ab"));
+    }
+
+    #[test]
+    fn test_bounds_of_empty_alternative_are_unbounded() {
+        let alternatives = [String::new(), "a".to_string()];
+        check!(KeyBounds::of_alternatives(&alternatives).is_unbounded());
+    }
+
+    #[test]
+    fn test_bounds_intersect() {
+        let pattern = KeyBounds {
+            start_after: Some("b".to_string()),
+            end_before: Some("y".to_string()),
+        };
+        let user = KeyBounds {
+            start_after: Some("c".to_string()),
+            end_before: None,
+        };
+        let both = pattern.intersect(&user);
+        check!(both.start_after.as_deref() == Some("c"));
+        check!(both.end_before.as_deref() == Some("y"));
+    }
+
+    #[rstest]
+    #[case::after_start("logs/b", true)]
+    #[case::contains_start("logs/", true)]
+    #[case::before_start("logs/0", false)]
+    #[case::at_end("logs/x", false)]
+    fn test_bounds_may_contain_prefix(#[case] prefix: &str, #[case] expected: bool) {
+        let bounds = KeyBounds {
+            start_after: Some("logs/a5".to_string()),
+            end_before: Some("logs/x".to_string()),
+        };
+        check!(bounds.may_contain_prefix(prefix) == expected);
+    }
+}
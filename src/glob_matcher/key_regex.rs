@@ -0,0 +1,108 @@
+//! Matching keys the way S3 splits them at the delimiter
+//!
+//! S3 splits a key at the first occurrence of the delimiter, then at the
+//! first one after that, and so on. With a one-character delimiter a
+//! wildcard only has to exclude that character, but with a longer one like
+//! `::` a wildcard may match a lone `:`, just not a whole delimiter or the
+//! part of one. A regex can't say that without lookaround, so for longer
+//! delimiters every delimiter in a key is replaced by a stand-in character
+//! before matching, and patterns are compiled with the same stand-in.
+
+use std::borrow::Cow;
+
+use regex::Regex;
+
+/// Stands in for a multi-character delimiter, a noncharacter that text is
+/// never supposed to contain
+const STAND_IN: &str = "\u{FDD0}";
+
+/// A compiled pattern that matches keys split at a delimiter
+#[derive(Debug, Clone)]
+pub(crate) struct KeyRegex {
+    regex: Regex,
+    /// The delimiter to replace before matching, if it's longer than one
+    /// character
+    replace: Option<String>,
+}
+
+impl KeyRegex {
+    /// Compile `re`, written with [`delimiter_re`] and [`literal_re`], to
+    /// match keys split at `delimiter`
+    pub(crate) fn new(re: &str, delimiter: &str) -> Result<Self, regex::Error> {
+        Ok(KeyRegex {
+            regex: Regex::new(re)?,
+            replace: (delimiter.chars().count() > 1).then(|| delimiter.to_string()),
+        })
+    }
+
+    /// Match keys as they are, for a regex written by the user
+    pub(crate) fn verbatim(regex: Regex) -> Self {
+        KeyRegex {
+            regex,
+            replace: None,
+        }
+    }
+
+    pub(crate) fn is_match(&self, key: &str) -> bool {
+        match &self.replace {
+            Some(delimiter) => self.regex.is_match(&key.replace(delimiter, STAND_IN)),
+            None => self.regex.is_match(key),
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        self.regex.as_str()
+    }
+}
+
+/// The regex for one delimiter
+pub(crate) fn delimiter_re(delimiter: &str) -> String {
+    regex::escape(&stand_in(delimiter, delimiter))
+}
+
+/// The regex for a character class that excludes the delimiter, without
+/// the brackets
+pub(crate) fn delimiter_class(delimiter: &str) -> String {
+    if delimiter.chars().count() > 1 {
+        STAND_IN.to_string()
+    } else {
+        regex::escape(delimiter)
+    }
+}
+
+/// The regex for the literal text `text`
+pub(crate) fn literal_re(text: &str, delimiter: &str) -> String {
+    regex::escape(&stand_in(text, delimiter))
+}
+
+/// `text` with each delimiter S3 would split it at replaced by the stand-in
+fn stand_in<'a>(text: &'a str, delimiter: &str) -> Cow<'a, str> {
+    if delimiter.chars().count() > 1 {
+        Cow::Owned(text.replace(delimiter, STAND_IN))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+
+    use super::*;
+
+    #[test]
+    fn test_delimiters_are_split_like_s3_does() {
+        let re = KeyRegex::new(&format!("^a{}b$", delimiter_re("::")), "::").unwrap();
+        check!(re.is_match("a::b"));
+        // `:::` is a delimiter followed by a `:`
+        check!(!re.is_match("a:::b"));
+
+        let re = KeyRegex::new(&format!("^a[^{}]b$", delimiter_class("::")), "::").unwrap();
+        check!(re.is_match("a:b"));
+        check!(!re.is_match("a::b"));
+
+        let re = KeyRegex::new(&format!("^{}$", literal_re("a:::b", "::")), "::").unwrap();
+        check!(re.is_match("a:::b"));
+        check!(!re.is_match("a::b"));
+    }
+}
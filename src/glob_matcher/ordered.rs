@@ -0,0 +1,271 @@
+//! Listing results in key order without holding all of them
+//!
+//! The prefixes and key ranges that `find_prefixes` settles on are
+//! disjoint, and S3 lists the keys in each of them in order, so listing
+//! them one after the other in the order they start in gives every key in
+//! order. They are still listed in parallel, each into its own channel, and
+//! the channels are drained one at a time: a listing's results are only
+//! held until every listing before it has finished. Listings only start
+//! once they are less than a window of segments ahead of the one being
+//! drained, so a slow listing holds back the ones after it instead of
+//! letting all of their results pile up.
+
+use std::sync::Arc;
+
+use aws_sdk_s3::types::Object;
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tracing::debug;
+
+use super::engine::Engine;
+use super::key_regex::KeyRegex;
+use super::{KeyRange, LiveStatus, PrefixResult, PrefixSearchResult};
+use crate::S3Object;
+
+/// A part of the key space whose results come out in order
+#[derive(Debug)]
+enum Segment {
+    /// A prefix or key range that still needs to be listed
+    Listing(KeyRange),
+    /// Keys found while searching for prefixes, sorted
+    Objects(Vec<Object>),
+}
+
+impl Segment {
+    /// The key that everything in the segment sorts at or after
+    fn start(&self) -> &str {
+        match self {
+            Segment::Listing(range) => range.bounds.start_after.as_deref().unwrap_or(&range.prefix),
+            Segment::Objects(objects) => objects.first().and_then(|o| o.key()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Split everything `presult` covers into segments in key order
+///
+/// Objects that were found along the way go between the listings around
+/// them.
+fn segments(presult: PrefixSearchResult) -> Vec<Segment> {
+    let mut listings = presult
+        .prefixes
+        .into_iter()
+        .map(KeyRange::whole)
+        .chain(presult.ranges)
+        .map(Segment::Listing)
+        .collect::<Vec<_>>();
+    listings.sort_by(|a, b| a.start().cmp(b.start()));
+    let mut objects = presult.objects;
+    objects.sort_by(|a, b| a.key().cmp(&b.key()));
+
+    let mut segments = Vec::with_capacity(listings.len() * 2 + 1);
+    let mut objects = objects.into_iter().peekable();
+    for listing in listings {
+        let before = std::iter::from_fn(|| {
+            objects.next_if(|o| o.key().is_some_and(|key| key < listing.start()))
+        })
+        .collect::<Vec<_>>();
+        if !before.is_empty() {
+            segments.push(Segment::Objects(before));
+        }
+        segments.push(listing);
+    }
+    let rest = objects.collect::<Vec<_>>();
+    if !rest.is_empty() {
+        segments.push(Segment::Objects(rest));
+    }
+    segments
+}
+
+/// List everything in `presult` that matches `matcher` and send it to `tx`
+/// in key order
+///
+/// Runs in the background, `tx` is closed once everything has been sent.
+pub(super) fn get_all_children<E: Engine + Clone>(
+    engine: E,
+    presult: PrefixSearchResult,
+    matcher: Arc<KeyRegex>,
+    status: LiveStatus,
+    tx: UnboundedSender<Vec<PrefixResult>>,
+    permit: Arc<Semaphore>,
+) {
+    let segments = segments(presult);
+    debug!(
+        segment_count = segments.len(),
+        "listing segments in key order"
+    );
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        segments.iter().map(|_| unbounded_channel()).unzip();
+    // as many segments as can be listed at once
+    let window = Arc::new(Semaphore::new(permit.available_permits().max(1)));
+    tokio::spawn(forward_in_order(receivers, tx, window.clone()));
+    tokio::spawn(async move {
+        for (segment, tx) in segments.into_iter().zip(senders) {
+            // closed once nobody is reading anymore
+            let Ok(slot) = window.acquire().await else {
+                return;
+            };
+            slot.forget();
+            match segment {
+                Segment::Objects(objects) => {
+                    let _ = tx.send(
+                        objects
+                            .into_iter()
+                            .filter(|o| o.key().is_some_and(|key| matcher.is_match(key)))
+                            .map(|o| PrefixResult::Object(S3Object::from(o)))
+                            .collect(),
+                    );
+                }
+                Segment::Listing(range) => {
+                    let prefix = range.prefix.clone();
+                    let presult = PrefixSearchResult {
+                        prefixes: Vec::new(),
+                        objects: Vec::new(),
+                        ranges: vec![range],
+                        max_candidate_prefixes: 1,
+                        estimated_list_calls: None,
+                        cut_off: false,
+                    };
+                    // the listing keeps its own handle on `tx` until it's
+                    // done, which is what tells the forwarder to move on
+                    let listed = engine
+                        .get_all_children(presult, matcher.clone(), &status, &tx, permit.clone())
+                        .await;
+                    if let Err(e) = listed {
+                        let _ = tx.send(vec![PrefixResult::failed(&prefix, e)]);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Send everything from each of `receivers` to `tx`, draining them one
+/// after the other
+///
+/// Every drained receiver lets one more segment start listing through
+/// `window`.
+async fn forward_in_order(
+    receivers: Vec<UnboundedReceiver<Vec<PrefixResult>>>,
+    tx: UnboundedSender<Vec<PrefixResult>>,
+    window: Arc<Semaphore>,
+) {
+    for mut rx in receivers {
+        while let Some(results) = rx.recv().await {
+            if tx.send(results).is_err() {
+                // nobody is reading anymore
+                window.close();
+                return;
+            }
+        }
+        window.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::assert;
+    use itertools::Itertools as _;
+
+    use super::*;
+    use crate::glob_matcher::KeyBounds;
+
+    fn object(key: &str) -> Object {
+        Object::builder().key(key).build()
+    }
+
+    #[test]
+    fn test_segments_interleave_objects_between_listings() {
+        let presult = PrefixSearchResult {
+            prefixes: vec!["b/".to_string(), "d/x/".to_string()],
+            objects: vec![object("e"), object("a"), object("c/1"), object("c/2")],
+            ranges: vec![
+                KeyRange {
+                    prefix: "d/y/".to_string(),
+                    bounds: KeyBounds {
+                        start_after: Some("d/y/5".to_string()),
+                        end_before: None,
+                    },
+                },
+                KeyRange {
+                    prefix: "d/y/".to_string(),
+                    bounds: KeyBounds {
+                        start_after: None,
+                        end_before: Some("d/y/5".to_string()),
+                    },
+                },
+            ],
+            max_candidate_prefixes: 0,
+            estimated_list_calls: None,
+            cut_off: false,
+        };
+        let starts = segments(presult)
+            .iter()
+            .map(|s| match s {
+                Segment::Listing(_) => format!("list {}", s.start()),
+                Segment::Objects(objects) => {
+                    format!(
+                        "objects {}",
+                        objects.iter().filter_map(|o| o.key()).join(",")
+                    )
+                }
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            starts
+                == [
+                    "objects a",
+                    "list b/",
+                    "objects c/1,c/2",
+                    "list d/x/",
+                    "list d/y/",
+                    "list d/y/5",
+                    "objects e",
+                ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_in_order_holds_later_results_back() {
+        let (first_tx, first_rx) = unbounded_channel();
+        let (second_tx, second_rx) = unbounded_channel();
+        let (third_tx, third_rx) = unbounded_channel();
+        let (tx, mut rx) = unbounded_channel();
+        // a window of two, taken by the first two listings
+        let window = Arc::new(Semaphore::new(0));
+        // the second listing finishes before the first one has started
+        second_tx
+            .send(vec![PrefixResult::Prefix("b".to_string())])
+            .unwrap();
+        drop(second_tx);
+        let third = {
+            let window = window.clone();
+            tokio::spawn(async move {
+                window.acquire().await.unwrap().forget();
+                third_tx
+                    .send(vec![PrefixResult::Prefix("c".to_string())])
+                    .unwrap();
+            })
+        };
+        let forwarder = tokio::spawn(forward_in_order(
+            vec![first_rx, second_rx, third_rx],
+            tx,
+            window.clone(),
+        ));
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+        // the third listing doesn't start until the first has been drained
+        assert!(!third.is_finished());
+
+        first_tx
+            .send(vec![PrefixResult::Prefix("a".to_string())])
+            .unwrap();
+        drop(first_tx);
+        forwarder.await.unwrap();
+        third.await.unwrap();
+        let mut keys = Vec::new();
+        while let Some(results) = rx.recv().await {
+            keys.extend(results.iter().map(PrefixResult::key));
+        }
+        assert!(keys == ["a", "b", "c"]);
+    }
+}
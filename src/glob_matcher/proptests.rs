@@ -69,7 +69,7 @@ enum Tok {
     NegClass(Vec<char>),
     Brace(Vec<String>),
     Recursive,
-    Delim(char),
+    Delim(&'static str),
 }
 
 impl Tok {
@@ -82,7 +82,7 @@ impl Tok {
             Tok::NegClass(cs) => format!("[!{}]", cs.iter().collect::<String>()),
             Tok::Brace(alts) => format!("{{{}}}", alts.join(",")),
             Tok::Recursive => "**".to_string(),
-            Tok::Delim(c) => c.to_string(),
+            Tok::Delim(d) => d.to_string(),
         }
     }
 }
@@ -109,13 +109,14 @@ fn brace_alts_strategy() -> impl Strategy<Value = Vec<String>> {
 }
 
 /// Delimiters chosen to avoid clashing with the alphabet, glob metacharacters
-/// (`*?[{`), the brace separator (`,`), or anything that needs escaping inside
-/// a regex character class.
-fn delimiter_strategy() -> impl Strategy<Value = char> {
-    prop::sample::select(vec!['/', ':'])
+/// (`*?[{`) or the brace separator (`,`). Both single- and multi-character
+/// delimiters are covered; `::` can overlap itself while `->` cannot, and
+/// `-` needs escaping inside a regex character class.
+fn delimiter_strategy() -> impl Strategy<Value = &'static str> {
+    prop::sample::select(vec!["/", ":", "::", "->"])
 }
 
-fn tok_strategy(delim: char) -> impl Strategy<Value = Tok> {
+fn tok_strategy(delim: &'static str) -> impl Strategy<Value = Tok> {
     prop_oneof![
         4 => literal_strategy().prop_map(Tok::Literal),
         2 => Just(Tok::Star),
@@ -128,19 +129,30 @@ fn tok_strategy(delim: char) -> impl Strategy<Value = Tok> {
     ]
 }
 
-fn pattern_strategy(delim: char) -> impl Strategy<Value = String> {
+fn pattern_strategy(delim: &'static str) -> impl Strategy<Value = String> {
     prop::collection::vec(tok_strategy(delim), 1..=6)
         .prop_map(|toks| toks.iter().map(Tok::render).collect::<String>())
         .prop_filter("non-empty pattern", |s| !s.is_empty())
 }
 
-fn key_strategy(delim: char) -> impl Strategy<Value = String> {
-    let segment = prop::collection::vec(alpha_char_strategy(), 1..=3)
+/// Key characters: the alphabet, plus the characters of a multi-character
+/// delimiter, which on their own are ordinary characters that wildcards
+/// have to match
+fn key_char_strategy(delim: &'static str) -> impl Strategy<Value = char> {
+    let mut chars = ALPHA.to_vec();
+    if delim.chars().count() > 1 {
+        chars.extend(delim.chars());
+    }
+    prop::sample::select(chars)
+}
+
+fn key_strategy(delim: &'static str) -> impl Strategy<Value = String> {
+    let segment = prop::collection::vec(key_char_strategy(delim), 1..=3)
         .prop_map(|cs| cs.into_iter().collect::<String>());
-    prop::collection::vec(segment, 1..=4).prop_map(move |segs| segs.join(&delim.to_string()))
+    prop::collection::vec(segment, 1..=4).prop_map(move |segs| segs.join(delim))
 }
 
-fn bucket_strategy(delim: char) -> impl Strategy<Value = Vec<String>> {
+fn bucket_strategy(delim: &'static str) -> impl Strategy<Value = Vec<String>> {
     prop::collection::vec(key_strategy(delim), 1..=20).prop_map(|mut keys| {
         keys.sort();
         keys.dedup();
@@ -150,7 +162,7 @@ fn bucket_strategy(delim: char) -> impl Strategy<Value = Vec<String>> {
 
 /// Generate `(delimiter, pattern, bucket)` such that the pattern and bucket
 /// are constructed using the same delimiter. Patterns may include `**`.
-fn delim_pattern_bucket() -> impl Strategy<Value = (char, String, Vec<String>)> {
+fn delim_pattern_bucket() -> impl Strategy<Value = (&'static str, String, Vec<String>)> {
     delimiter_strategy().prop_flat_map(|d| (Just(d), pattern_strategy(d), bucket_strategy(d)))
 }
 
@@ -164,21 +176,19 @@ fn run_async<F: std::future::Future>(fut: F) -> F::Output {
 
 /// Bucket keys plus every delimiter-suffixed proper prefix of each key — the
 /// set of S3 entities the matcher could legitimately return.
-fn logical_universe(bucket: &[String], delimiter: char) -> BTreeSet<String> {
+fn logical_universe(bucket: &[String], delimiter: &str) -> BTreeSet<String> {
     let mut universe = BTreeSet::new();
     for key in bucket {
         universe.insert(key.clone());
-        for (idx, ch) in key.char_indices() {
-            if ch == delimiter {
-                universe.insert(key[..idx + ch.len_utf8()].to_string());
-            }
+        for (idx, _) in key.match_indices(delimiter) {
+            universe.insert(key[..idx + delimiter.len()].to_string());
         }
     }
     universe
 }
 
 /// Strip a single trailing delimiter so `"b"` and `"b/"` compare equal.
-fn normalize(s: &str, delimiter: char) -> &str {
+fn normalize<'a>(s: &'a str, delimiter: &str) -> &'a str {
     s.strip_suffix(delimiter).unwrap_or(s)
 }
 
@@ -214,7 +224,7 @@ proptest! {
         // them to lax would let the regex oracle accept entities the
         // prefix-enumeration algorithm intentionally does not surface.
         let mut matcher =
-            S3GlobMatcher::parse(pattern.clone(), &delim.to_string(), false)
+            S3GlobMatcher::parse(pattern.clone(), delim, false)
                 .map_err(|e| TestCaseError::fail(format!("parse failed: {e}")))?;
         matcher.set_min_prefixes(0);
 
@@ -283,7 +293,7 @@ proptest! {
         // `cross_delim = false` so the per-segment invariants hold,
         // independent of the user-facing default.
         let mut matcher =
-            S3GlobMatcher::parse(pattern.clone(), &delim.to_string(), false)
+            S3GlobMatcher::parse(pattern.clone(), delim, false)
                 .map_err(|e| TestCaseError::fail(format!("parse failed: {e}")))?;
         matcher.set_min_prefixes(0);
 
@@ -0,0 +1,85 @@
+//! Derive the prefixes to list for a `--regex` pattern
+//!
+//! We can't enumerate an arbitrary regex the way we do a glob, but most
+//! useful regexes start with some literal text or a small alternation of
+//! literals. `regex-syntax` already knows how to pull those out, and every
+//! key the regex matches must start with one of them, so they are exactly the
+//! prefixes a glob with the same shape would have produced.
+
+use anyhow::{Context as _, Result};
+use regex_syntax::ParserBuilder;
+use regex_syntax::hir::literal::{ExtractKind, Extractor};
+
+/// The literal prefixes that every match of `raw` starts with
+///
+/// Prefixes that are covered by a shorter prefix are dropped. An empty result
+/// means the regex has no useful literal prefix and the whole bucket has to
+/// be listed.
+pub(super) fn literal_prefixes(raw: &str, ignore_case: bool) -> Result<Vec<String>> {
+    let hir = ParserBuilder::new()
+        .case_insensitive(ignore_case)
+        .build()
+        .parse(raw)
+        .with_context(|| format!("Parsing regex {raw}"))?;
+    let mut extractor = Extractor::new();
+    extractor.kind(ExtractKind::Prefix);
+    let seq = extractor.extract(&hir);
+    let Some(literals) = seq.literals() else {
+        return Ok(Vec::new());
+    };
+
+    let mut prefixes = literals
+        .iter()
+        .map(|lit| match std::str::from_utf8(lit.as_bytes()) {
+            Ok(s) => s.to_string(),
+            // a class can split a multi-byte character, keep the whole chars
+            Err(e) => String::from_utf8_lossy(&lit.as_bytes()[..e.valid_up_to()]).into_owned(),
+        })
+        .collect::<Vec<_>>();
+    prefixes.sort();
+    let mut minimal: Vec<String> = Vec::new();
+    for prefix in prefixes {
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+        if minimal.last().is_some_and(|kept| prefix.starts_with(kept)) {
+            continue;
+        }
+        minimal.push(prefix);
+    }
+    Ok(minimal)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(r"events/(\d{4})-(0[1-6])/.*\.parquet", &["events/"])]
+    #[case(r"logs/(app|web)/2024-.*", &["logs/app/2024-", "logs/web/2024-"])]
+    #[case(r"a/b/[xy]\.txt", &["a/b/x.txt", "a/b/y.txt"])]
+    #[case(r"^data/.*", &["data/"])]
+    #[case(r"(foo|foobar).*", &["foo"])]
+    #[case(r".*\.csv", &[])]
+    #[case(r"(a/.*|.*b)", &[])]
+    fn test_literal_prefixes(#[case] raw: &str, #[case] expected: &[&str]) -> Result<()> {
+        let actual = literal_prefixes(raw, false)?;
+        check!(actual == expected, "regex: {raw}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_prefixes_ignore_case() -> Result<()> {
+        let actual = literal_prefixes("ab/.*", true)?;
+        check!(actual == ["AB/", "Ab/", "aB/", "ab/"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_prefixes_invalid() {
+        check!(literal_prefixes("a/(b", false).is_err());
+    }
+}
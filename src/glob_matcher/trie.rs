@@ -0,0 +1,159 @@
+//! Expanding a run of literal parts one part at a time
+//!
+//! Adjacent literal parts, like `[a-z][a-z][0-9]/x`, are normally combined
+//! into a single list of every combination of their alternatives. When that
+//! list would be too long the parser keeps the parts separate, and their
+//! combinations form a prefix trie with one level per part, rooted at the
+//! prefixes found for the pattern before the run. Instead of listing every
+//! combination, the trie is expanded level by level: before a level would
+//! grow past what can be checked at once, the nodes expanded so far are
+//! checked against the bucket, and only the ones that exist are expanded
+//! further.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use tracing::debug;
+
+use super::engine::Engine;
+use super::glob::Glob;
+use super::{KeyBounds, MAX_CHECK_PREFIXES, check_prefixes, prefix_join, resolve_folded};
+
+/// A run of literal parts in a pattern
+pub(super) struct LiteralTrie<'a> {
+    levels: &'a [Glob],
+}
+
+/// How far a [`LiteralTrie`] was expanded
+#[derive(Debug)]
+pub(super) struct Expansion {
+    /// The nodes of the deepest expanded level that exist in the bucket
+    pub(super) prefixes: BTreeSet<String>,
+    /// How many levels were expanded
+    ///
+    /// Fewer than the trie has if the next level would have had more than
+    /// `max_prefixes` nodes.
+    pub(super) depth: usize,
+}
+
+impl<'a> LiteralTrie<'a> {
+    /// The run of literal parts at the start of a pattern
+    pub(super) fn leading(parts: &'a [Glob]) -> Self {
+        Self::starting_at(parts, 0)
+    }
+
+    /// The run of literal parts that starts at `parts[start]`, empty if that
+    /// isn't a literal
+    pub(super) fn starting_at(parts: &'a [Glob], start: usize) -> Self {
+        let parts = parts.get(start..).unwrap_or_default();
+        let len = parts
+            .iter()
+            .take_while(|p| matches!(p, Glob::Choice { .. } | Glob::FoldedChoice { .. }))
+            .count();
+        Self {
+            levels: &parts[..len],
+        }
+    }
+
+    pub(super) fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Expand every level from the empty prefix, or as many as fit in
+    /// `max_prefixes`
+    pub(super) async fn expand<E: Engine + Clone>(
+        &self,
+        engine: &mut E,
+        delimiter: &str,
+        bounds: &KeyBounds,
+        max_prefixes: usize,
+        max_parallelism: usize,
+        max_candidate_prefixes: &mut usize,
+    ) -> Result<Expansion> {
+        self.expand_from(
+            BTreeSet::from([String::new()]),
+            engine,
+            delimiter,
+            bounds,
+            max_prefixes,
+            max_parallelism,
+            max_candidate_prefixes,
+        )
+        .await
+    }
+
+    /// Expand every level below `roots`, prefixes known to exist, or as
+    /// many as fit in `max_prefixes`
+    ///
+    /// Every alternative is appended to the nodes as it is, so the roots
+    /// must end where the run starts in the pattern.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) async fn expand_from<E: Engine + Clone>(
+        &self,
+        roots: BTreeSet<String>,
+        engine: &mut E,
+        delimiter: &str,
+        bounds: &KeyBounds,
+        max_prefixes: usize,
+        max_parallelism: usize,
+        max_candidate_prefixes: &mut usize,
+    ) -> Result<Expansion> {
+        let mut checked = roots;
+        let mut nodes = checked.clone();
+        let mut is_checked = true;
+        for (depth, level) in self.levels.iter().enumerate() {
+            let (allowed, folded) = match level {
+                Glob::Choice { allowed, .. } => (allowed, false),
+                Glob::FoldedChoice { allowed, .. } => (allowed, true),
+                other => unreachable!("trie levels are literals, got {other:?}"),
+            };
+            let expanded_len = nodes.len().saturating_mul(allowed.len());
+            if !is_checked && (folded || expanded_len > MAX_CHECK_PREFIXES.min(max_prefixes)) {
+                debug!(
+                    depth,
+                    node_count = nodes.len(),
+                    expanded_len,
+                    "checking trie nodes before expanding the next level"
+                );
+                nodes = check_prefixes(engine, &checked, nodes, delimiter, bounds, max_parallelism)
+                    .await?;
+                checked = nodes.clone();
+                is_checked = true;
+            }
+            if nodes.len().saturating_mul(allowed.len()) > max_prefixes {
+                debug!(
+                    depth,
+                    node_count = nodes.len(),
+                    alternative_count = allowed.len(),
+                    max_prefixes,
+                    "next trie level has too many nodes, stopping"
+                );
+                return Ok(Expansion {
+                    prefixes: nodes,
+                    depth,
+                });
+            }
+            if folded {
+                debug!(allowed = %allowed.join(","), "case-insensitive append");
+                nodes = resolve_folded(engine, &nodes, allowed, delimiter, max_parallelism).await?;
+                checked = nodes.clone();
+            } else {
+                debug!(depth, alternative_count = allowed.len(), "simple append");
+                nodes = nodes
+                    .iter()
+                    .flat_map(|node| allowed.iter().map(move |alt| prefix_join(node, alt)))
+                    .collect();
+                is_checked = false;
+            }
+            *max_candidate_prefixes = (*max_candidate_prefixes).max(nodes.len());
+        }
+        if !is_checked {
+            nodes =
+                check_prefixes(engine, &checked, nodes, delimiter, bounds, max_parallelism).await?;
+        }
+        Ok(Expansion {
+            prefixes: nodes,
+            depth: self.depth(),
+        })
+    }
+}
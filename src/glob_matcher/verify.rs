@@ -0,0 +1,216 @@
+//! Verifying candidate prefixes: one delimiter listing per parent with many
+//! candidates, point checks for the rest
+//!
+//! Expanding alternations can produce thousands of candidates, and checking
+//! each one costs at least one request. Candidates that share a parent can
+//! all be verified by listing that parent with the delimiter: every key
+//! that starts with a candidate shows up as a child of the parent that
+//! starts with it, and for an exact key the child is the key itself.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use aws_sdk_s3::types::Object;
+use tracing::debug;
+
+use super::KeyBounds;
+use super::engine::Engine;
+use super::fan_out_per_prefix;
+
+/// The fewest candidates under one parent that are verified by listing it
+///
+/// Below this, a listing is unlikely to be cheaper than a request per
+/// candidate.
+const MIN_CANDIDATES_TO_SCAN: usize = 16;
+
+/// How a set of candidates will be verified
+#[derive(Debug, Default)]
+pub(super) struct Plan {
+    /// Candidates grouped by the parent that will be listed for them
+    pub(super) scans: BTreeMap<String, BTreeSet<String>>,
+    /// Candidates that will be checked one request at a time
+    pub(super) points: BTreeSet<String>,
+}
+
+impl Plan {
+    /// Group `candidates` by parent, keeping the parents with enough
+    /// candidates to scan
+    pub(super) fn new(candidates: impl IntoIterator<Item = String>, delimiter: &str) -> Self {
+        let mut plan = Plan::default();
+        if delimiter.is_empty() {
+            plan.points.extend(candidates);
+            return plan;
+        }
+        let mut by_parent: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for candidate in candidates {
+            by_parent
+                .entry(parent_of(&candidate, delimiter).to_string())
+                .or_default()
+                .insert(candidate);
+        }
+        for (parent, candidates) in by_parent {
+            if candidates.len() >= MIN_CANDIDATES_TO_SCAN {
+                debug!(
+                    parent,
+                    candidate_count = candidates.len(),
+                    "verifying candidates by scanning their parent"
+                );
+                plan.scans.insert(parent, candidates);
+            } else {
+                plan.points.extend(candidates);
+            }
+        }
+        debug!(
+            scanned_parents = plan.scans.len(),
+            scanned_candidates = plan.scans.values().map(BTreeSet::len).sum::<usize>(),
+            point_checks = plan.points.len(),
+            "planned candidate verification"
+        );
+        plan
+    }
+}
+
+/// What listing the parents of a plan found
+#[derive(Debug, Default)]
+pub(super) struct Scanned {
+    /// Candidates that some key starts with
+    pub(super) existing: BTreeSet<String>,
+    /// Candidates that are keys themselves
+    pub(super) objects: Vec<Object>,
+    /// Candidates followed by the delimiter that some key starts with
+    pub(super) directories: BTreeSet<String>,
+    /// Candidates whose parent was too big to list, these still need point
+    /// checks
+    pub(super) unresolved: BTreeSet<String>,
+}
+
+/// List each parent in `scans` once with the delimiter, within `bounds`,
+/// and sort its candidates by what was found
+///
+/// A listing gives up after as many pages as it has candidates, at which
+/// point point checks would have been cheaper.
+pub(super) async fn scan_parents<E: Engine + Clone>(
+    engine: &E,
+    bounds: &KeyBounds,
+    scans: BTreeMap<String, BTreeSet<String>>,
+    delimiter: &str,
+    max_parallelism: usize,
+) -> Result<Scanned> {
+    let parents = scans.keys().cloned().collect::<BTreeSet<_>>();
+    let results = fan_out_per_prefix(&parents, max_parallelism, |parent| {
+        let mut engine = engine.clone();
+        let candidates = scans[&parent].iter().cloned().collect::<Vec<_>>();
+        engine.set_bounds(bounds.intersect(&KeyBounds::of_alternatives(&candidates)));
+        let delimiter = delimiter.to_string();
+        async move {
+            engine
+                .scan_pages(&parent, &delimiter, candidates.len())
+                .await
+        }
+    })
+    .await;
+
+    let mut scanned = Scanned::default();
+    for (parent, result) in results {
+        let candidates = &scans[&parent];
+        let result = result?;
+        if result.truncated {
+            debug!(
+                parent,
+                candidate_count = candidates.len(),
+                "parent has too many children, checking its candidates individually"
+            );
+            scanned.unresolved.extend(candidates.iter().cloned());
+            continue;
+        }
+        let children = result
+            .prefixes
+            .iter()
+            .map(String::as_str)
+            .chain(result.objects.iter().filter_map(|o| o.key()))
+            .collect::<BTreeSet<_>>();
+        for candidate in candidates {
+            let exists = children
+                .range(candidate.as_str()..)
+                .next()
+                .is_some_and(|child| child.starts_with(candidate.as_str()));
+            if exists {
+                scanned.existing.insert(candidate.clone());
+            }
+            let directory = format!("{candidate}{delimiter}");
+            if result.prefixes.contains(&directory) {
+                scanned.directories.insert(directory);
+            }
+        }
+        debug!(
+            parent,
+            candidate_count = candidates.len(),
+            child_count = children.len(),
+            "verified candidates by scanning their parent"
+        );
+        scanned.objects.extend(
+            result
+                .objects
+                .into_iter()
+                .filter(|o| o.key().is_some_and(|key| candidates.contains(key))),
+        );
+    }
+    Ok(scanned)
+}
+
+/// Everything up to and including the last delimiter before the final
+/// component of `candidate`
+fn parent_of<'a>(candidate: &'a str, delimiter: &str) -> &'a str {
+    let name = candidate.strip_suffix(delimiter).unwrap_or(candidate);
+    match name.rfind(delimiter) {
+        Some(idx) => &candidate[..idx + delimiter.len()],
+        None => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::assert;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::glob_matcher::engine::MockS3Engine;
+
+    #[rstest]
+    #[case::top_level("foo", "/", "")]
+    #[case::nested("a/b/c", "/", "a/b/")]
+    #[case::trailing_delimiter("a/b/", "/", "a/")]
+    #[case::multi_char_delimiter("a::b::c", "::", "a::b::")]
+    fn test_parent_of(#[case] candidate: &str, #[case] delimiter: &str, #[case] expected: &str) {
+        assert!(parent_of(candidate, delimiter) == expected);
+    }
+
+    #[tokio::test]
+    async fn test_scan_parents_gives_up_after_a_page_per_candidate() -> Result<()> {
+        // one sub-directory, and far more flat keys among the candidates than
+        // fit in a page per candidate
+        let candidates = (0..MIN_CANDIDATES_TO_SCAN)
+            .map(|i| format!("logs/a{i:02}"))
+            .collect::<BTreeSet<_>>();
+        let mut paths = vec!["logs/a00/x".to_string()];
+        paths.extend((0..20_000).map(|i| format!("logs/a05-{i:05}")));
+        let engine = MockS3Engine::new(paths);
+
+        let scans = BTreeMap::from([("logs/".to_string(), candidates.clone())]);
+        let scanned = scan_parents(&engine, &KeyBounds::default(), scans, "/", 4).await?;
+        assert!(scanned.unresolved == candidates);
+        assert!(scanned.existing.is_empty());
+        assert!(engine.api_calls.stats().total().list_objects_v2 == MIN_CANDIDATES_TO_SCAN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_scans_only_dense_parents() {
+        let dense = (0..MIN_CANDIDATES_TO_SCAN).map(|i| format!("logs/{i:02}"));
+        let sparse = ["other/a".to_string(), "other/b".to_string()];
+        let plan = Plan::new(dense.chain(sparse), "/");
+        assert!(plan.scans.keys().collect::<Vec<_>>() == ["logs/"]);
+        assert!(plan.scans["logs/"].len() == MIN_CANDIDATES_TO_SCAN);
+        assert!(plan.points == BTreeSet::from(["other/a".to_string(), "other/b".to_string()]));
+    }
+}
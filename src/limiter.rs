@@ -0,0 +1,217 @@
+//! Backing off when S3 tells us to slow down
+//!
+//! S3 scales request capacity per prefix as load grows, and answers with
+//! `SlowDown` (or other 503s) until it has. Rather than keep
+//! `--max-parallelism` requests in flight regardless, requests go through
+//! an AIMD limiter: every throttled response halves how many may be in
+//! flight, and every successful one grows it back a little, the same way
+//! TCP finds the capacity of a link. When hard request-rate limits are set,
+//! requests also wait for those before taking a slot.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::rate_limit::RequestRate;
+
+/// The limit never drops below this, so requests always make progress
+const MIN_LIMIT: f64 = 1.0;
+
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    state: Mutex<State>,
+    freed: Notify,
+    max: f64,
+    throttles: AtomicUsize,
+    rate: Arc<RequestRate>,
+}
+
+#[derive(Debug)]
+struct State {
+    limit: f64,
+    in_flight: usize,
+    /// Bumped on every decrease, so a burst of throttles from requests that
+    /// were all sent at the old limit only counts once
+    epoch: u64,
+}
+
+impl Limiter {
+    /// A limiter that starts at, and never grows past, `max` requests in flight
+    pub(crate) fn new(max: usize) -> Self {
+        let max = (max as f64).max(MIN_LIMIT);
+        Self {
+            state: Mutex::new(State {
+                limit: max,
+                in_flight: 0,
+                epoch: 0,
+            }),
+            freed: Notify::new(),
+            max,
+            throttles: AtomicUsize::new(0),
+            rate: Arc::default(),
+        }
+    }
+
+    /// Also keep requests under `rate`
+    pub(crate) fn with_request_rate(mut self, rate: Arc<RequestRate>) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// A limiter that never makes anything wait
+    pub(crate) fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    /// Wait until another request for `key` may be sent
+    pub(crate) async fn acquire(&self, key: &str) -> Permit<'_> {
+        self.rate.wait(key).await;
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            {
+                let mut state = self.lock();
+                if (state.in_flight as f64) < state.limit.floor() {
+                    state.in_flight += 1;
+                    return Permit {
+                        limiter: self,
+                        epoch: state.epoch,
+                    };
+                }
+            }
+            freed.await;
+        }
+    }
+
+    /// How many requests may currently be in flight
+    pub(crate) fn limit(&self) -> usize {
+        self.lock().limit as usize
+    }
+
+    /// How many responses told us to slow down
+    pub(crate) fn throttles(&self) -> usize {
+        self.throttles.load(Ordering::Relaxed)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("limiter lock is never poisoned")
+    }
+}
+
+/// Permission to send one request, given back when dropped
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    limiter: &'a Limiter,
+    epoch: u64,
+}
+
+impl Permit<'_> {
+    /// The request succeeded, allow a little more concurrency
+    pub(crate) fn succeeded(self) {
+        let mut state = self.limiter.lock();
+        state.limit = (state.limit + 1.0 / state.limit).min(self.limiter.max);
+    }
+
+    /// The request was throttled, halve the concurrency
+    pub(crate) fn throttled(self) {
+        self.limiter.throttles.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.limiter.lock();
+        if state.epoch == self.epoch {
+            state.limit = (state.limit / 2.0).max(MIN_LIMIT);
+            state.epoch += 1;
+            tracing::debug!(limit = state.limit, "throttled, reducing concurrency");
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.lock().in_flight -= 1;
+        self.limiter.freed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert2::check;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_throttle_halves_and_success_grows_back() {
+        let limiter = Limiter::new(8);
+        limiter.acquire("").await.throttled();
+        check!(limiter.limit() == 4);
+        limiter.acquire("").await.throttled();
+        check!(limiter.limit() == 2);
+        // additive increase: roughly one more for every `limit` successes
+        for _ in 0..2 {
+            limiter.acquire("").await.succeeded();
+        }
+        check!(limiter.limit() == 2);
+        limiter.acquire("").await.succeeded();
+        check!(limiter.limit() == 3);
+        check!(limiter.throttles() == 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_throttles_count_once() {
+        let limiter = Limiter::new(8);
+        let permits = [
+            limiter.acquire("").await,
+            limiter.acquire("").await,
+            limiter.acquire("").await,
+        ];
+        for permit in permits {
+            permit.throttled();
+        }
+        check!(limiter.limit() == 4);
+        check!(limiter.throttles() == 3);
+    }
+
+    #[tokio::test]
+    async fn test_never_below_one_or_above_max() {
+        let limiter = Limiter::new(2);
+        for _ in 0..10 {
+            limiter.acquire("").await.throttled();
+        }
+        check!(limiter.limit() == 1);
+        for _ in 0..100 {
+            limiter.acquire("").await.succeeded();
+        }
+        check!(limiter.limit() == 2);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_the_request_rate() {
+        let rate = Arc::new(RequestRate::new(None, Some(20.0), "/"));
+        let limiter = Limiter::new(8).with_request_rate(rate.clone());
+        for _ in 0..20 {
+            limiter.acquire("a/1").await.succeeded();
+        }
+        check!(rate.waited() == Duration::ZERO, "a second's worth of burst");
+        limiter.acquire("b/1").await.succeeded();
+        check!(
+            rate.waited() == Duration::ZERO,
+            "b/ has a budget of its own"
+        );
+        limiter.acquire("a/2").await.succeeded();
+        check!(rate.waited() > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_a_free_slot() {
+        let limiter = Limiter::new(2);
+        limiter.acquire("").await.throttled();
+        let held = limiter.acquire("").await;
+        let waiting = tokio::time::timeout(Duration::from_millis(20), limiter.acquire("")).await;
+        check!(waiting.is_err(), "only one request may be in flight");
+        drop(held);
+        let next = tokio::time::timeout(Duration::from_secs(1), limiter.acquire("")).await;
+        check!(next.is_ok());
+    }
+}
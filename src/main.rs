@@ -1,29 +1,36 @@
 use std::io::{self, IsTerminal as _, Write as _};
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Context as _, Result, anyhow, bail};
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::operation::head_object::HeadObjectOutput;
 use aws_sdk_s3::primitives::DateTime;
 use aws_sdk_s3::types::Object;
 use aws_sdk_s3::{Client, config::BehaviorVersion, config::Region};
 use clap::{ArgAction, Parser, Subcommand, ValueEnum};
-use glob_matcher::{ListResult, PrefixResult, S3Engine, S3GlobMatcher};
+use glob_matcher::{GlobDialect, ListFailure, ListResult, PrefixResult, S3Engine, S3GlobMatcher};
 use humansize::{DECIMAL, FormatSizeOptions, SizeFormatter};
 use messaging::{MESSAGE_LEVEL, MessageLevel};
 use num_format::{Locale, ToFormattedString};
 use regex::Regex;
 use serde::Serialize;
+use stats::{ApiCalls, ApiStats, CallCounts, PriceTable};
 use tokio::runtime::Runtime;
 use tracing::debug;
 
 mod download;
 mod glob_matcher;
+mod limiter;
 mod messaging;
 mod platform_tls;
 mod progress;
+mod rate_limit;
+mod retry;
+mod stats;
 
 #[derive(Debug, Subcommand)]
 enum Command {
@@ -70,7 +77,10 @@ enum Command {
         #[clap(short, long, verbatim_doc_comment)]
         format: Option<String>,
 
-        /// Stream keys as they are found, rather than sorting and printing at the end
+        /// Print keys as soon as they are found, in no particular order
+        ///
+        /// Without this keys are printed in order, each one as soon as every
+        /// key before it has been listed.
         #[clap(long)]
         stream: bool,
 
@@ -83,6 +93,14 @@ enum Command {
         /// JSON records carry the full object metadata: type ("object" or "prefix"),
         /// bucket, key, uri, size, last_modified, etag, storage_class,
         /// checksum_algorithms, and restore_status.
+        ///
+        /// A prefix that could not be listed shows up as a record with type "error"
+        /// (bucket, prefix, uri, error). In text mode failures are listed on stderr
+        /// after the matches. Either way s3glob exits nonzero.
+        ///
+        /// The S3 requests made are reported on stderr, in the JSON formats as a
+        /// single record with type "stats": the requests by phase (api_calls),
+        /// their total_api_calls, and with --cost-estimate the estimated_cost_usd.
         #[clap(short, long, verbatim_doc_comment, default_value = "text")]
         output: OutputFormat,
     },
@@ -119,21 +137,161 @@ enum Command {
         #[clap(short, long, verbatim_doc_comment, default_value = "from-first-glob")]
         path_mode: PathMode,
 
+        /// Download everything under matched prefixes
+        ///
+        /// A pattern without a trailing `*` or `**` can match "directories"
+        /// (the `PRE` lines in `ls`) as well as objects. Without this they
+        /// are skipped.
+        #[clap(long)]
+        recursive: bool,
+
         /// Flatten the downloaded files into a single directory
         ///
-        /// This will replace all slashes in the key path with dashes in the
-        /// downloaded file.
+        /// This will replace every delimiter (and slash) in the key path with
+        /// dashes in the downloaded file.
         #[clap(long)]
         flatten: bool,
 
+        /// What to do when a local file already exists
+        ///
+        /// - overwrite: replace it
+        /// - skip: keep it and don't download the object
+        /// - newer: keep it if it's the same size as the object and at least
+        ///   as new, otherwise replace it
+        /// - error: keep it and report the object as failed
+        /// - rename: keep it and download to `name-1.ext`, `name-2.ext`, ...
+        #[clap(long, verbatim_doc_comment, default_value = "overwrite")]
+        if_exists: IfExists,
+
+        /// What to do with keys that aren't safe to use as local paths
+        ///
+        /// Keys with `.` or `..` segments, empty segments (e.g. a leading,
+        /// trailing or doubled delimiter), path separators inside a segment or
+        /// NUL bytes could otherwise write outside the destination.
+        ///
+        /// - reject: report the object as failed
+        /// - encode: percent-encode the offending parts (e.g. `..` becomes
+        ///   `%2E%2E` and `a//b` becomes `a/%2Fb`)
+        #[clap(long, verbatim_doc_comment, default_value = "reject")]
+        unsafe_keys: UnsafeKeys,
+
+        /// What to do when several objects would be saved to the same path
+        ///
+        /// This happens when flattening (`a/b-c` and `a-b/c` are both
+        /// `a-b-c`), on case-insensitive filesystems (`Foo` and `foo`), and
+        /// when a key is also a "directory" of other keys (`x` and `x/y`).
+        /// The object with the first key keeps the path, except that a file is
+        /// always the one to move out of the way of a directory.
+        ///
+        /// Collisions are rare without `--flatten` on a case-sensitive
+        /// filesystem, so there objects are downloaded as they're found and
+        /// the first one found keeps the path instead.
+        ///
+        /// - suffix: save the others as `name-1.ext`, `name-2.ext`, ...
+        /// - hash: save the others as `name-<hash of the key>.ext`
+        /// - error: list the collisions and don't download anything
+        #[clap(long, verbatim_doc_comment, default_value = "suffix")]
+        on_collision: OnCollision,
+
+        /// Keep object metadata on the downloaded files
+        ///
+        /// Each file's modification time is set to the object's last modified
+        /// time. On Linux the ETag, content type, version id and user metadata
+        /// are also stored as `user.s3glob.*` extended attributes (user metadata
+        /// as `user.s3glob.meta.<name>`).
+        #[clap(long)]
+        preserve: bool,
+
+        /// Check downloaded contents before moving them into place
+        ///
+        /// Objects uploaded with an additional checksum (CRC32, CRC32C,
+        /// CRC64NVME, SHA1 or SHA256) are checked against it, others against
+        /// their ETag when it is an MD5 (single-part uploads without KMS or
+        /// customer-provided keys). Downloads that don't match are retried and
+        /// then reported as failed.
+        #[clap(long)]
+        verify: bool,
+
+        /// Write the SHA256 of every downloaded file to this manifest
+        ///
+        /// The manifest is in `sha256sum` format, with the same paths that are
+        /// printed, so `sha256sum -c <manifest>` from the same directory checks
+        /// the files.
+        #[clap(long, value_name = "PATH")]
+        write_checksums: Option<PathBuf>,
+
         /// Output format: text|json|ndjson
         ///
         /// - `text` (default): one local file path per line on stdout, summary on stderr
         /// - `json`: single buffered `{ "downloads": [...], "summary": {...} }` object
         /// - `ndjson`: streams `{ "event": "downloaded", ... }` per file then a final
         ///   `{ "event": "summary", ... }` record (summary moves to stdout)
+        ///
+        /// Prefixes that could not be listed are reported as `{ "event": "error", ... }`
+        /// records before the summary and in the summary's `failed_prefixes`, and
+        /// s3glob exits nonzero. Objects that could not be downloaded are reported
+        /// as `{ "event": "failed", ... }` records (a `failed` array in `json`) and
+        /// counted in the summary's `failed_objects`. Objects left alone because of
+        /// `--if-exists` are `{ "event": "skipped", ... }` records (a `skipped`
+        /// array in `json`) counted in `skipped_objects`, and downloads saved
+        /// under a new name have `"renamed": true` and are counted in
+        /// `renamed_objects`. The summary's `stats` has the S3 requests made, as
+        /// for `ls`.
         #[clap(short, long, verbatim_doc_comment, default_value = "text")]
         output: OutputFormat,
+
+        /// Exit successfully even if some objects could not be downloaded
+        ///
+        /// Failed downloads are still reported. Transient errors (throttling,
+        /// dropped connections) are always retried before an object counts as
+        /// failed.
+        #[clap(long)]
+        allow_partial: bool,
+
+        /// Download objects at least this big as concurrent byte ranges
+        ///
+        /// Sizes are a number of bytes with an optional K, M, or G suffix
+        /// (powers of 1024).
+        #[clap(long, default_value = "32M", value_parser = parse_byte_size)]
+        multipart_threshold: u64,
+
+        /// The size of each byte range when downloading in parts
+        #[clap(long, default_value = "8M", value_parser = parse_byte_size)]
+        part_size: u64,
+
+        /// How many byte ranges of a single object to download at once
+        ///
+        /// If S3 has a full-object checksum for an object downloaded in parts
+        /// the assembled file is verified against it.
+        #[clap(long, default_value = "8")]
+        part_concurrency: NonZeroUsize,
+
+        /// How many connections downloads start with
+        ///
+        /// Every second the number of connections is adjusted: it grows while
+        /// that makes downloads faster, and shrinks again when it doesn't or
+        /// when responses slow down a lot. It never goes below this.
+        #[clap(long, default_value = "8")]
+        min_connections: NonZeroUsize,
+
+        /// The most connections downloads can grow to
+        ///
+        /// Also limited by --max-parallelism. An object downloaded in parts
+        /// uses up to --part-concurrency connections.
+        #[clap(long, default_value = "256")]
+        max_connections: NonZeroUsize,
+
+        /// The most bytes of objects to be downloading at once
+        ///
+        /// Keeps lots of large objects from being started together when
+        /// finishing a few would be faster. An object bigger than this is
+        /// still downloaded, on its own.
+        #[clap(long, default_value = "2G", value_parser = parse_byte_size)]
+        max_in_flight: u64,
+
+        /// Cap the download bandwidth, in bytes per second (e.g. 10M)
+        #[clap(long, value_parser = parse_byte_size)]
+        limit_rate: Option<u64>,
     },
 
     /// Learn how to tune s3glob's parallelism for better performance
@@ -142,7 +300,9 @@ enum Command {
     /// slower than you hope, or if you're getting a slowdown error.
     ///
     /// If you want to limit parallel API calls, you can use the
-    /// --max-parallelism flag.
+    /// --max-parallelism flag. When S3 throttles listing requests s3glob
+    /// halves how many it sends at once, and grows back towards
+    /// --max-parallelism as requests succeed again.
     ///
     /// You probably want the maximum parallelism possible. Because of the
     /// APIs provided by AWS, s3glob can only meaningfully issue parallel
@@ -154,7 +314,13 @@ enum Command {
     /// expansion: it walks one directory level at a time with `LIST`
     /// calls until it has enough sub-prefixes to scan in parallel.
     /// This typically helps for buckets with broad subtrees under
-    /// `**`. If your bucket shape makes the expansion
+    /// `**`. A directory with lots of keys and no sub-directories is
+    /// instead split into ranges of keys (found with a few one-key
+    /// `LIST` calls using `StartAfter`) which are listed in parallel.
+    /// The same happens to a directory whose first page of keys shows
+    /// that its sub-directories are so small that listing them one by
+    /// one would take many more `LIST` calls than listing it as a
+    /// whole. If your bucket shape makes the expansion
     /// counter-productive (e.g. each level has only one sub-directory
     /// so the expansion just costs extra LISTs) pass
     /// `--no-recursive-auto-parallel` to skip the expansion and list
@@ -200,6 +366,34 @@ enum OutputFormat {
     Ndjson,
 }
 
+/// What `dl` does when a file is already at the local path for an object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "lowercase")]
+enum IfExists {
+    Overwrite,
+    Skip,
+    Newer,
+    Error,
+    Rename,
+}
+
+/// What `dl` does with keys that can't safely be used as local paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "lowercase")]
+enum UnsafeKeys {
+    Reject,
+    Encode,
+}
+
+/// What `dl` does when several objects would be saved to the same local path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[clap(rename_all = "lowercase")]
+enum OnCollision {
+    Suffix,
+    Hash,
+    Error,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum PathMode {
     Abs,
@@ -243,6 +437,46 @@ impl ValueEnum for PathMode {
     }
 }
 
+/// Parse a byte count like `8M`, for clap
+///
+/// The suffixes are powers of 1024, an optional trailing `B` or `iB` is
+/// accepted so `8MiB` and `8MB` mean the same thing.
+fn parse_byte_size(raw: &str) -> Result<u64, String> {
+    let trimmed = raw.trim();
+    let without_b = trimmed
+        .strip_suffix("iB")
+        .or_else(|| trimmed.strip_suffix('B'))
+        .unwrap_or(trimmed);
+    let (digits, multiplier) = match without_b.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&without_b[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&without_b[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&without_b[..i], 1 << 30),
+        _ => (without_b, 1),
+    };
+    let count: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: {raw} (expected e.g. 512K, 8M, or 1G)"))?;
+    match count.checked_mul(multiplier) {
+        Some(0) => Err("size must be greater than zero".to_owned()),
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("size is too large: {raw}")),
+    }
+}
+
+/// Parse a positive rate like `100` or `0.5`, for clap
+fn parse_rate(raw: &str) -> Result<f64, String> {
+    let rate: f64 = raw
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate: {raw} (expected e.g. 100 or 0.5)"))?;
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err("rate must be greater than zero".to_owned())
+    }
+}
+
 #[derive(Debug, Parser)]
 #[command(version, author, about, max_term_width = 80)]
 /// A fast aws s3 ls and downloader that supports glob patterns
@@ -275,8 +509,17 @@ struct Opts {
     ///
     /// and then will list all the objects in these prefixes, filtering them
     /// with the remainder of the pattern.
-    #[clap(short, long, default_value = "/", global = true)]
-    delimiter: char,
+    ///
+    /// The delimiter may be more than one character, e.g. `::`. It also
+    /// decides where the local directories go when downloading.
+    #[clap(
+        short,
+        long,
+        default_value = "/",
+        global = true,
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    delimiter: String,
 
     /// How verbose to be, specify multiple times to increase verbosity
     ///
@@ -308,11 +551,52 @@ struct Opts {
 
     /// Maximum number of parallel requests to make
     ///
-    /// If you get a slowdown error you can use this to limit the number of
-    /// concurrent requests.
+    /// Listing backs off below this on its own when S3 throttles requests,
+    /// but if you keep getting slowdown errors you can use this to limit
+    /// the number of concurrent requests.
     #[clap(short = 'M', long, global = true, default_value = "10000")]
     max_parallelism: usize,
 
+    /// Never send more than this many requests per second
+    ///
+    /// Unlike --max-parallelism this is a hard cap on the request rate,
+    /// for buckets that share their request budget with other traffic.
+    /// It counts every LIST, HEAD and GET, including retries.
+    #[clap(long, global = true, value_parser = parse_rate)]
+    max_requests_per_second: Option<f64>,
+
+    /// Never send more than this many requests per second for keys starting
+    /// with any one character
+    ///
+    /// S3 limits request rates per prefix, so this keeps any one of them
+    /// from being hammered while allowing more in total. Keys are grouped by
+    /// the first character of their top-level prefix, so a flat bucket's
+    /// keys and partial prefixes like `lo` are limited along with `logs/`.
+    #[clap(long, global = true, value_parser = parse_rate)]
+    max_prefix_requests_per_second: Option<f64>,
+
+    /// Estimate what the S3 requests made cost, in USD
+    ///
+    /// The estimate is added to the summary. It covers request charges
+    /// only, not data transfer or storage, at the prices in
+    /// --request-prices.
+    #[clap(long, global = true)]
+    cost_estimate: bool,
+
+    /// USD per 1,000 requests for --cost-estimate
+    ///
+    /// A comma-separated list like `list=0.005,head=0.0004,get=0.0004`.
+    /// Prices left out keep those defaults, which are S3 Standard's in
+    /// us-east-1.
+    #[clap(
+        long,
+        global = true,
+        value_name = "PRICES",
+        value_parser = PriceTable::parse,
+        requires = "cost_estimate"
+    )]
+    request_prices: Option<PriceTable>,
+
     /// Disable automatic parallelization of `**` (recursive) listings
     ///
     /// At a `**` glob component s3glob expands the frontier one
@@ -328,13 +612,42 @@ struct Opts {
     /// Target prefix count for `**` BFS expansion (escape hatch)
     ///
     /// The expansion loop at `**` runs while the discovered prefix
-    /// set is smaller than this. Lower to reduce expansion API
+    /// set is smaller than this, and only expands directories whose
+    /// sub-directories look big enough to be worth listing one by
+    /// one. Lower to reduce expansion API
     /// calls, raise to fan out more aggressively, `0` to skip the
     /// loop entirely (`--no-recursive-auto-parallel` is the
     /// supported way to do that).
     #[clap(long, global = true, default_value = "25", hide = true)]
     min_prefixes: usize,
 
+    /// The most candidate prefixes to generate from a pattern
+    ///
+    /// Alternations and character classes are expanded into the prefixes
+    /// they could start, and those are checked against the bucket as they
+    /// are expanded. If a pattern still has more candidates than this, s3glob
+    /// says so, and lists everything under the prefixes it found up to that
+    /// point, filtering keys with the pattern instead.
+    #[clap(long, global = true, default_value = "100000", value_name = "N")]
+    max_prefixes: usize,
+
+    /// Only match keys that sort after KEY
+    ///
+    /// KEY is a whole key, without the bucket. S3 lists keys in UTF-8 byte
+    /// order, and this starts every listing at KEY instead of at the
+    /// beginning of the prefix. s3glob already
+    /// does this for the literal start of a pattern (`logs/2024-0[6-9]*`
+    /// starts at `logs/2024-06`), use this when you know more about how
+    /// your keys are ordered than the pattern says.
+    #[clap(long, global = true, value_name = "KEY")]
+    start_after: Option<String>,
+
+    /// Only match keys that sort before KEY
+    ///
+    /// Listings stop as soon as they get to KEY, see --start-after.
+    #[clap(long, global = true, value_name = "KEY")]
+    end_before: Option<String>,
+
     /// Use path-style S3 addressing
     ///
     /// By default s3glob uses the standard virtualhost-style addressing,
@@ -357,7 +670,9 @@ struct Opts {
     /// Pass `--no-cross-delim` to make these patterns single-segment:
     /// `?` becomes "any single non-delimiter character" and `[!abc]`
     /// becomes "any single character not in the set and not the
-    /// delimiter". `*` is always single-segment regardless of this flag.
+    /// delimiter". With a multi-character delimiter they may match a single
+    /// character of it, but never a whole delimiter or part of one. `*` is
+    /// always single-segment regardless of this flag.
     ///
     /// A future major release will flip the default to `false`.
     #[clap(
@@ -384,6 +699,45 @@ struct Opts {
         overrides_with = "cross_delim",
     )]
     no_cross_delim: bool,
+
+    /// Which glob rules to interpret the pattern with: s3glob|globset|gitignore
+    ///
+    /// - `s3glob` (default): `**` may appear anywhere and matches across
+    ///   delimiters, and `**/` makes the delimiter after it optional.
+    /// - `globset`: `**` must be a whole path component, `*` and `?`
+    ///   (and `[!...]`) never match the delimiter. Using `**` inside a
+    ///   component is an error.
+    /// - `gitignore`: globset rules, plus a pattern with no delimiter
+    ///   (other than a trailing one) matches at any depth, and a leading
+    ///   delimiter anchors the pattern at the bucket root.
+    ///
+    /// Use a strict dialect to share patterns with other globset-based tools.
+    #[clap(long, global = true, default_value = "s3glob", verbatim_doc_comment)]
+    glob_dialect: GlobDialect,
+
+    /// Match the pattern case-insensitively
+    ///
+    /// Literal parts of the pattern are expanded into their upper- and
+    /// lower-case spellings so that they still narrow down the prefixes
+    /// that need to be listed. Literals with more spellings than
+    /// `--max-case-variants` are instead found by listing the enclosing
+    /// "directory" and comparing case-insensitively.
+    #[clap(short = 'i', long, global = true)]
+    ignore_case: bool,
+
+    /// Maximum number of case variants to expand a literal into with `--ignore-case`
+    #[clap(long, global = true, default_value_t = glob_matcher::DEFAULT_MAX_CASE_VARIANTS)]
+    max_case_variants: usize,
+
+    /// Treat the pattern (after the bucket) as a regex instead of a glob
+    ///
+    /// The regex must match the whole key. Its literal prefix and top-level
+    /// alternations are used to pick the prefixes to list, so
+    /// `events/(2023|2024)-.*\.parquet` only lists `events/2023-` and
+    /// `events/2024-`. A regex that starts with a wildcard lists the
+    /// whole bucket.
+    #[clap(long, global = true, conflicts_with = "glob_dialect")]
+    regex: bool,
 }
 
 impl Opts {
@@ -473,24 +827,63 @@ async fn run(opts: Opts) -> Result<()> {
 
     let client = create_s3_client(&opts, &bucket).await?;
 
-    let engine = S3Engine::new(client.clone(), bucket.clone());
-    let mut matcher = S3GlobMatcher::parse(
-        raw_pattern.clone(),
-        &opts.delimiter.to_string(),
-        opts.cross_delim(),
-    )?;
+    let request_rate = Arc::new(rate_limit::RequestRate::new(
+        opts.max_requests_per_second,
+        opts.max_prefix_requests_per_second,
+        &opts.delimiter,
+    ));
+    let limiter = Arc::new(
+        limiter::Limiter::new(opts.max_parallelism).with_request_rate(request_rate.clone()),
+    );
+    let api_calls = Arc::new(ApiCalls::default());
+    let prices = opts
+        .cost_estimate
+        .then(|| opts.request_prices.unwrap_or_default());
+    let engine = S3Engine::new(
+        client.clone(),
+        bucket.clone(),
+        limiter.clone(),
+        api_calls.clone(),
+    );
+    let mut matcher = if opts.regex {
+        S3GlobMatcher::from_regex(raw_pattern.clone(), &opts.delimiter, opts.ignore_case)?
+    } else {
+        let mut matcher = S3GlobMatcher::parse_with_dialect(
+            raw_pattern.clone(),
+            &opts.delimiter,
+            opts.cross_delim(),
+            opts.glob_dialect,
+        )?;
+        if opts.ignore_case {
+            matcher.set_ignore_case(opts.max_case_variants);
+        }
+        matcher
+    };
     matcher.set_max_parallelism(opts.max_parallelism);
+    matcher.set_key_bounds(opts.start_after.clone(), opts.end_before.clone());
+    // the download path modes look for the first glob character, which
+    // means nothing in a regex
+    let strip_pattern = if opts.regex {
+        matcher.literal_prefix()
+    } else {
+        matcher.pattern()
+    };
     let effective_min_prefixes = if opts.no_recursive_auto_parallel {
         0
     } else {
         opts.min_prefixes
     };
     matcher.set_min_prefixes(effective_min_prefixes);
+    matcher.set_max_prefixes(opts.max_prefixes);
+    if let Command::List { stream, output, .. } = &opts.command {
+        matcher.set_sorted(*output == OutputFormat::Text && !stream);
+    }
     let ListResult {
         status,
         totals,
         mut rx,
-    } = matcher.get_objects(engine).await?;
+        sorted,
+    } = matcher.get_objects(engine.clone()).await?;
 
     match opts.command {
         Command::List {
@@ -516,12 +909,16 @@ async fn run(opts: Opts) -> Result<()> {
                     false
                 }
             };
+            // Sorted results can be written as they arrive, the rest are
+            // sorted at the end
+            let write_now = stream_mode || (sorted && output == OutputFormat::Text);
             let mut matching_objects: Vec<PrefixResult> = Vec::new();
             // The matcher surfaces both real objects and logical prefixes
             // (directories) as matches; count them separately so the
             // summary doesn't report directories as "objects".
             let mut object_count = 0;
             let mut prefix_count = 0;
+            let mut failures: Vec<ListFailure> = Vec::new();
             let decimal = decimal_format();
             let matches_progress = if !matcher.is_complete() {
                 Some(progress::get().spinner(progress::matches_spinner_style()))
@@ -534,9 +931,10 @@ async fn run(opts: Opts) -> Result<()> {
                     match result {
                         PrefixResult::Object(_) => object_count += 1,
                         PrefixResult::Prefix(_) => prefix_count += 1,
+                        PrefixResult::Failed(failure) => failures.push(failure.clone()),
                     }
                 }
-                if stream_mode {
+                if write_now {
                     for result in &results {
                         let written = match output {
                             OutputFormat::Text => write_prefix_result(
@@ -581,7 +979,7 @@ async fn run(opts: Opts) -> Result<()> {
             if let Some(matches_progress) = &matches_progress {
                 matches_progress.finish_and_clear();
             }
-            if !stream_mode {
+            if !write_now {
                 let mut objects = matching_objects;
                 objects.sort_by_key(|r| r.key().to_owned());
                 match output {
@@ -630,52 +1028,110 @@ async fn run(opts: Opts) -> Result<()> {
                     elapsed,
                 );
             }
+            report_throttling(&limiter, &request_rate);
+            log_list_estimate(totals.estimated_list_calls, &api_calls.stats());
+            if output == OutputFormat::Text {
+                report_api_calls(&api_calls.stats(), prices.as_ref());
+            } else {
+                // on stderr so that stdout keeps to the records it always had
+                let stats = JsonLsRecord::Stats {
+                    stats: JsonStats::new(&api_calls, prices.as_ref()),
+                };
+                write_json_line(&mut io::stderr(), &stats)?;
+            }
+            check_list_failures(&failures)?;
         }
         Command::Download {
             dest,
             path_mode,
+            recursive,
             flatten,
+            if_exists,
+            unsafe_keys,
+            on_collision,
+            preserve,
+            verify,
+            write_checksums,
             output,
+            allow_partial,
+            multipart_threshold,
+            part_size,
+            part_concurrency,
+            min_connections,
+            max_connections,
+            max_in_flight,
+            limit_rate,
             ..
         } => {
+            let ranged = download::RangedGets {
+                threshold: multipart_threshold,
+                part_size,
+                concurrency: part_concurrency.get(),
+            };
+            let interrupt = download::Interrupt::on_ctrl_c();
             let mut total_matches = 0;
-            let pools = download::DlPools::new(opts.max_parallelism);
-            let prefix_to_strip = download::extract_prefix_to_strip(&raw_pattern, path_mode, &[]);
             let (ntfctn_tx, mut ntfctn_rx) =
                 tokio::sync::mpsc::unbounded_channel::<download::Notification>();
-            let base_path = PathBuf::from(dest);
-            let dl = download::Downloader::new(
-                client.clone(),
-                bucket.clone(),
-                prefix_to_strip,
-                flatten,
-                base_path.clone(),
-                ntfctn_tx.clone(),
-            );
             let matches_progress = if !matcher.is_complete() {
                 Some(progress::get().spinner(progress::matches_spinner_style()))
             } else {
                 None
             };
-            // if the path_mode is shortest then we need to know all the paths to be able to extract the shortest
+            let scheduler = download::Scheduler::start(download::SchedulerLimits {
+                min_connections: min_connections.get(),
+                max_connections: max_connections.get().min(opts.max_parallelism.max(1)),
+                max_in_flight_bytes: max_in_flight,
+                rate_limit: limit_rate,
+            });
+            let mut dl = download::Downloader::new(
+                client,
+                bucket.clone(),
+                download::extract_prefix_to_strip(&strip_pattern, path_mode, &opts.delimiter, &[]),
+                opts.delimiter.clone(),
+                flatten,
+                PathBuf::from(dest),
+                ntfctn_tx,
+            )
+            .with_ranged_gets(ranged)
+            .with_interrupt(interrupt.clone())
+            .with_if_exists(if_exists)
+            .with_preserve(preserve)
+            .with_verification(verify, write_checksums.is_some())
+            .with_unsafe_keys(unsafe_keys)
+            .with_on_collision(on_collision)
+            .with_scheduler(&scheduler)
+            .with_request_rate(request_rate.clone())
+            .with_api_calls(api_calls.clone());
+            // we need to know all the paths to extract the shortest prefix,
+            // and sometimes to keep objects from colliding locally,
+            // otherwise objects are downloaded as they're found
+            let stream =
+                !matches!(path_mode, PathMode::Shortest | PathMode::S) && !dl.needs_every_path();
             let mut objects_to_download = Vec::new();
-            while let Some(result) = rx.recv().await {
+            let mut download = |obj: S3Object, dl: &mut download::Downloader| {
+                if stream {
+                    if let Some((obj, path)) = dl.place(obj) {
+                        scheduler.download_object(dl.fresh(), obj, path);
+                    }
+                } else {
+                    objects_to_download.push(obj);
+                }
+            };
+            let mut matched_prefixes = Vec::new();
+            let mut list_failures: Vec<ListFailure> = Vec::new();
+            while let Some(result) = tokio::select! {
+                result = rx.recv() => result,
+                () = interrupt.wait() => None,
+            } {
                 total_matches += result
                     .iter()
                     .filter(|r| matches!(r, PrefixResult::Object(_)))
                     .count();
                 for obj in result {
                     match obj {
-                        PrefixResult::Object(obj) => {
-                            if matches!(path_mode, PathMode::Shortest | PathMode::S) {
-                                objects_to_download.push(obj);
-                            } else {
-                                pools.download_object(dl.fresh(), obj);
-                            }
-                        }
-                        PrefixResult::Prefix(prefix) => {
-                            debug!("Skipping prefix: {}", prefix);
-                        }
+                        PrefixResult::Object(obj) => download(obj, &mut dl),
+                        PrefixResult::Prefix(prefix) => matched_prefixes.push(prefix),
+                        PrefixResult::Failed(failure) => list_failures.push(failure),
                     }
                 }
                 if let Some(matches_progress) = &matches_progress {
@@ -695,39 +1151,73 @@ async fn run(opts: Opts) -> Result<()> {
             if let Some(matches_progress) = matches_progress {
                 matches_progress.finish_and_clear();
             }
-            // close the tx so the downloaders know to finish
-            drop(dl);
-            drop(pools);
-            if matches!(path_mode, PathMode::Shortest | PathMode::S) {
-                let prefix_to_strip = download::extract_prefix_to_strip(
-                    &raw_pattern,
+            if !matched_prefixes.is_empty() && !interrupt.is_set() {
+                if recursive {
+                    progressln!(
+                        "Listing everything under {} matching prefixes",
+                        matched_prefixes.len()
+                    );
+                    let ListResult { mut rx, .. } =
+                        matcher.get_all_under(engine, matched_prefixes).await?;
+                    while let Some(result) = tokio::select! {
+                        result = rx.recv() => result,
+                        () = interrupt.wait() => None,
+                    } {
+                        for obj in result {
+                            match obj {
+                                // objects that matched directly can also be
+                                // under a matching prefix
+                                PrefixResult::Object(obj) if !matcher.matches_key(&obj.key) => {
+                                    total_matches += 1;
+                                    download(obj, &mut dl);
+                                }
+                                PrefixResult::Object(_) => {}
+                                PrefixResult::Prefix(prefix) => {
+                                    debug!("Skipping prefix: {}", prefix);
+                                }
+                                PrefixResult::Failed(failure) => list_failures.push(failure),
+                            }
+                        }
+                    }
+                } else {
+                    progressln!(
+                        "Skipping {} matching prefixes, pass --recursive to download everything under them",
+                        matched_prefixes.len()
+                    );
+                }
+            }
+            if stream {
+                dl.report_placed();
+            } else {
+                dl.prefix_to_strip = download::extract_prefix_to_strip(
+                    &strip_pattern,
                     path_mode,
+                    &opts.delimiter,
                     &objects_to_download,
                 );
-                progressln!(
-                    "Stripping longest common prefix from keys: {}",
-                    prefix_to_strip
-                );
-                let dl = download::Downloader::new(
-                    client,
-                    bucket.clone(),
-                    prefix_to_strip,
-                    flatten,
-                    base_path,
-                    ntfctn_tx,
-                );
-                let pools = download::DlPools::new(opts.max_parallelism);
-                for obj in objects_to_download {
-                    pools.download_object(dl.fresh(), obj);
+                if matches!(path_mode, PathMode::Shortest | PathMode::S) {
+                    progressln!(
+                        "Stripping longest common prefix from keys: {}",
+                        dl.prefix_to_strip
+                    );
+                }
+                let planned = dl.plan(objects_to_download)?;
+                for (obj, path) in planned {
+                    scheduler.download_object(dl.fresh(), obj, path);
                 }
-            } else {
-                drop(ntfctn_tx);
             }
+            // close the tx so the downloaders know to finish
+            drop(dl);
+            drop(scheduler);
             let start_time = Instant::now();
             let mut downloaded_matches = 0;
             let mut total_bytes = 0_usize;
+            let mut resumed_bytes = 0_usize;
+            let mut interrupted = 0_usize;
             let mut speed = 0.0;
             let mut records: Vec<DownloadedRecord> = Vec::with_capacity(total_matches);
+            let mut failed: Vec<FailedRecord> = Vec::new();
+            let mut skipped: Vec<DownloadedRecord> = Vec::new();
             let downloads_progress = progress::get().spinner(progress::downloads_count_style());
             downloads_progress.set_length(total_matches as u64);
             let bytes_progress = progress::get().bar(progress::downloads_bytes_style());
@@ -735,10 +1225,20 @@ async fn run(opts: Opts) -> Result<()> {
                 matches!(output, OutputFormat::Ndjson).then(|| io::stdout().lock());
             while let Some(n) = ntfctn_rx.recv().await {
                 match n {
-                    download::Notification::ObjectDownloaded { object, local_path } => {
+                    download::Notification::ObjectDownloaded {
+                        object,
+                        local_path,
+                        renamed,
+                        sha256,
+                    } => {
                         downloaded_matches += 1;
                         downloads_progress.set_position(downloaded_matches as u64);
-                        let record = DownloadedRecord { object, local_path };
+                        let record = DownloadedRecord {
+                            object,
+                            local_path,
+                            renamed,
+                            sha256,
+                        };
                         if let Some(out) = &mut ndjson_stdout {
                             let event = JsonDlEvent::Downloaded {
                                 record: JsonDlObject::new(&bucket, &record),
@@ -749,26 +1249,83 @@ async fn run(opts: Opts) -> Result<()> {
                         }
                         records.push(record);
                     }
+                    download::Notification::Skipped { object, local_path } => {
+                        downloaded_matches += 1;
+                        downloads_progress.set_position(downloaded_matches as u64);
+                        let record = DownloadedRecord {
+                            object,
+                            local_path,
+                            renamed: false,
+                            sha256: None,
+                        };
+                        if let Some(out) = &mut ndjson_stdout {
+                            let event = JsonDlEvent::Skipped {
+                                record: JsonDlObject::new(&bucket, &record),
+                            };
+                            if !keep_writing(write_json_line(out, &event))? {
+                                ndjson_stdout = None;
+                            }
+                        }
+                        skipped.push(record);
+                    }
+                    download::Notification::Failed { object, error } => {
+                        let record = FailedRecord { object, error };
+                        if let Some(out) = &mut ndjson_stdout {
+                            let event = JsonDlEvent::Failed {
+                                record: JsonDlFailure::new(&bucket, &record),
+                            };
+                            if !keep_writing(write_json_line(out, &event))? {
+                                ndjson_stdout = None;
+                            }
+                        }
+                        failed.push(record);
+                    }
+                    download::Notification::Interrupted => interrupted += 1,
                     download::Notification::BytesDownloaded(bytes) => {
                         total_bytes += bytes;
                         bytes_progress.set_position(total_bytes as u64);
                     }
+                    download::Notification::BytesResumed(bytes) => {
+                        total_bytes += bytes;
+                        resumed_bytes += bytes;
+                        bytes_progress.set_position(total_bytes as u64);
+                    }
+                    download::Notification::BytesDiscarded(bytes) => {
+                        total_bytes -= bytes;
+                        bytes_progress.set_position(total_bytes as u64);
+                    }
                 }
                 let elapsed = start_time.elapsed().as_secs_f64();
-                speed = total_bytes as f64 / elapsed;
+                speed = total_bytes.saturating_sub(resumed_bytes) as f64 / elapsed;
             }
             downloads_progress.finish_and_clear();
             bytes_progress.finish_and_clear();
-            if records.is_empty() {
+            if records.is_empty()
+                && skipped.is_empty()
+                && failed.is_empty()
+                && list_failures.is_empty()
+                && !interrupt.is_set()
+            {
                 bail!("No objects found matching the pattern.");
             }
+            if let Some(manifest) = &write_checksums {
+                write_checksum_manifest(manifest, &records)?;
+            }
             let dl_ms = start_time.elapsed().as_millis() as u64;
             let summary = JsonDlSummary {
                 bytes: total_bytes,
                 discovery_ms: start_time.duration_since(start).as_millis() as u64,
                 download_ms: dl_ms,
                 bytes_per_sec: speed.round() as u64,
+                failed_objects: failed.len(),
+                skipped_objects: skipped.len(),
+                renamed_objects: records.iter().filter(|r| r.renamed).count(),
+                failed_prefixes: list_failures.iter().map(|f| f.prefix.clone()).collect(),
+                throttled_requests: limiter.throttles(),
+                rate_limited_ms: request_rate.waited().as_millis() as u64,
+                stats: JsonStats::new(&api_calls, prices.as_ref()),
             };
+            log_list_estimate(totals.estimated_list_calls, &summary.stats.api_calls);
             match output {
                 OutputFormat::Text => {
                     let mut files: Vec<String> = records
@@ -790,9 +1347,29 @@ async fn run(opts: Opts) -> Result<()> {
                         Duration::from_millis(dl_ms),
                         SizeFormatter::new(speed.round() as u64, decimal_format()),
                     );
+                    if summary.skipped_objects > 0 {
+                        progressln!(
+                            "Skipped {} objects that already exist locally",
+                            summary.skipped_objects
+                        );
+                    }
+                    if summary.renamed_objects > 0 {
+                        progressln!(
+                            "Renamed {} downloads to keep existing local files",
+                            summary.renamed_objects
+                        );
+                    }
+                    report_throttling(&limiter, &request_rate);
+                    report_api_calls(&summary.stats.api_calls, prices.as_ref());
                 }
                 OutputFormat::Ndjson => {
                     if let Some(mut out) = ndjson_stdout {
+                        for failure in &list_failures {
+                            let event = JsonDlEvent::Error {
+                                failure: JsonListFailure::new(&bucket, failure),
+                            };
+                            keep_writing(write_json_line(&mut out, &event))?;
+                        }
                         let event = JsonDlEvent::Summary { record: &summary };
                         keep_writing(write_json_line(&mut out, &event))?;
                     }
@@ -803,14 +1380,43 @@ async fn run(opts: Opts) -> Result<()> {
                         .iter()
                         .map(|r| JsonDlObject::new(&bucket, r))
                         .collect();
+                    skipped.sort_by(|a, b| a.object.key.cmp(&b.object.key));
+                    failed.sort_by(|a, b| a.object.key.cmp(&b.object.key));
                     let wrapper = JsonDlWrapper {
                         downloads,
+                        skipped: skipped
+                            .iter()
+                            .map(|r| JsonDlObject::new(&bucket, r))
+                            .collect(),
+                        failed: failed
+                            .iter()
+                            .map(|r| JsonDlFailure::new(&bucket, r))
+                            .collect(),
                         summary: &summary,
                     };
                     let mut stdout = io::stdout().lock();
                     keep_writing(write_json_line(&mut stdout, &wrapper))?;
                 }
             }
+            if !failed.is_empty() {
+                message_err!("Failed to download {} objects:", failed.len());
+                for record in &failed {
+                    message_err!("  {}: {}", record.object.key, record.error);
+                }
+            }
+            check_list_failures(&list_failures)?;
+            if interrupt.is_set() {
+                bail!(
+                    "Interrupted before {} downloads finished, run the same command again to resume them",
+                    interrupted
+                );
+            }
+            if !failed.is_empty() && !allow_partial {
+                bail!(
+                    "{} objects could not be downloaded (pass --allow-partial to ignore)",
+                    failed.len()
+                );
+            }
         }
         Command::Parallelism { .. } => {
             progressln!("This is just for documentation, run instead: s3glob help parallelism");
@@ -827,6 +1433,10 @@ fn write_prefix_result(
     decimal: FormatSizeOptions,
     result: &PrefixResult,
 ) -> io::Result<()> {
+    if let PrefixResult::Failed(_) = result {
+        // failures go to stderr with the summary, not between the matches
+        return Ok(());
+    }
     if let Some(user_fmt) = user_format {
         writeln!(stdout, "{}", format_user(bucket, result, user_fmt))
     } else {
@@ -839,6 +1449,7 @@ fn write_prefix_result(
                 obj.key,
             ),
             PrefixResult::Prefix(prefix) => writeln!(stdout, "PRE     {prefix}"),
+            PrefixResult::Failed(_) => unreachable!("failures are skipped above"),
         }
     }
 }
@@ -902,6 +1513,35 @@ enum JsonLsRecord<'a> {
         key: &'a str,
         uri: String,
     },
+    Error {
+        #[serde(flatten)]
+        failure: JsonListFailure<'a>,
+    },
+    /// Written to stderr, after the other records
+    Stats {
+        #[serde(flatten)]
+        stats: JsonStats,
+    },
+}
+
+/// A prefix that could not be fully listed
+#[derive(Serialize)]
+struct JsonListFailure<'a> {
+    bucket: &'a str,
+    prefix: &'a str,
+    uri: String,
+    error: &'a str,
+}
+
+impl<'a> JsonListFailure<'a> {
+    fn new(bucket: &'a str, failure: &'a ListFailure) -> Self {
+        Self {
+            bucket,
+            prefix: &failure.prefix,
+            uri: s3_uri(bucket, &failure.prefix),
+            error: &failure.error,
+        }
+    }
 }
 
 impl<'a> JsonLsRecord<'a> {
@@ -916,6 +1556,9 @@ impl<'a> JsonLsRecord<'a> {
                 key: prefix,
                 uri: s3_uri(bucket, prefix),
             },
+            PrefixResult::Failed(failure) => JsonLsRecord::Error {
+                failure: JsonListFailure::new(bucket, failure),
+            },
         }
     }
 }
@@ -924,6 +1567,8 @@ impl<'a> JsonLsRecord<'a> {
 struct DownloadedRecord {
     object: S3Object,
     local_path: PathBuf,
+    renamed: bool,
+    sha256: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -932,6 +1577,12 @@ struct JsonDlObject<'a> {
     #[serde(flatten)]
     meta: ObjectMetadata<'a>,
     local_path: String,
+    /// Only present (and true) when saved under a new name by `--if-exists rename`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    renamed: bool,
+    /// Only present with `--write-checksums`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<&'a str>,
 }
 
 impl<'a> JsonDlObject<'a> {
@@ -940,6 +1591,32 @@ impl<'a> JsonDlObject<'a> {
             bucket,
             meta: ObjectMetadata::new(bucket, &rec.object),
             local_path: rec.local_path.display().to_string(),
+            renamed: rec.renamed,
+            sha256: rec.sha256.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FailedRecord {
+    object: S3Object,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct JsonDlFailure<'a> {
+    bucket: &'a str,
+    #[serde(flatten)]
+    meta: ObjectMetadata<'a>,
+    error: &'a str,
+}
+
+impl<'a> JsonDlFailure<'a> {
+    fn new(bucket: &'a str, rec: &'a FailedRecord) -> Self {
+        Self {
+            bucket,
+            meta: ObjectMetadata::new(bucket, &rec.object),
+            error: &rec.error,
         }
     }
 }
@@ -950,11 +1627,48 @@ struct JsonDlSummary {
     discovery_ms: u64,
     download_ms: u64,
     bytes_per_sec: u64,
+    /// Objects that could not be downloaded, even after retrying
+    failed_objects: usize,
+    /// Objects not downloaded because of a file that was already there
+    skipped_objects: usize,
+    /// Objects saved under a new name next to a file that was already there
+    renamed_objects: usize,
+    /// Prefixes that could not be fully listed, their matches may be missing
+    failed_prefixes: Vec<String>,
+    /// Listing requests S3 asked us to slow down for
+    throttled_requests: usize,
+    /// Time requests spent waiting for the request-rate limits, added up
+    rate_limited_ms: u64,
+    stats: JsonStats,
+}
+
+/// The S3 requests a run made, and what they cost with `--cost-estimate`
+#[derive(Serialize)]
+struct JsonStats {
+    /// By the phase they were made in
+    api_calls: ApiStats,
+    total_api_calls: CallCounts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_cost_usd: Option<f64>,
+}
+
+impl JsonStats {
+    fn new(calls: &ApiCalls, prices: Option<&PriceTable>) -> Self {
+        let api_calls = calls.stats();
+        let total_api_calls = api_calls.total();
+        Self {
+            api_calls,
+            total_api_calls,
+            estimated_cost_usd: prices.map(|p| p.cost(total_api_calls)),
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct JsonDlWrapper<'a> {
     downloads: Vec<JsonDlObject<'a>>,
+    skipped: Vec<JsonDlObject<'a>>,
+    failed: Vec<JsonDlFailure<'a>>,
     summary: &'a JsonDlSummary,
 }
 
@@ -965,10 +1679,137 @@ enum JsonDlEvent<'a> {
         #[serde(flatten)]
         record: JsonDlObject<'a>,
     },
+    Skipped {
+        #[serde(flatten)]
+        record: JsonDlObject<'a>,
+    },
+    Failed {
+        #[serde(flatten)]
+        record: JsonDlFailure<'a>,
+    },
     Summary {
         #[serde(flatten)]
         record: &'a JsonDlSummary,
     },
+    Error {
+        #[serde(flatten)]
+        failure: JsonListFailure<'a>,
+    },
+}
+
+/// Write a `sha256sum -c` manifest of everything that was downloaded
+fn write_checksum_manifest(path: &Path, records: &[DownloadedRecord]) -> Result<()> {
+    let mut lines = records
+        .iter()
+        .filter_map(|r| Some((&r.local_path, r.sha256.as_deref()?)))
+        .collect::<Vec<_>>();
+    lines.sort_unstable();
+    let manifest: String = lines
+        .into_iter()
+        .map(|(local_path, sha256)| download::manifest_line(sha256, local_path))
+        .collect();
+    std::fs::write(path, manifest)
+        .with_context(|| format!("Failed to write checksums to {}", path.display()))
+}
+
+/// Tell the user if S3 throttled listing and how far we backed off, and
+/// how long requests waited for the request-rate limits
+fn report_throttling(limiter: &limiter::Limiter, request_rate: &rate_limit::RequestRate) {
+    let throttles = limiter.throttles();
+    if throttles > 0 {
+        progressln!(
+            "S3 throttled {} requests, backed off to {} concurrent requests",
+            throttles,
+            limiter.limit()
+        );
+    }
+    let waited = request_rate.waited();
+    if !waited.is_zero() {
+        progressln!(
+            "Requests waited {:.1?} in total to stay under the request-rate limits",
+            waited
+        );
+    }
+}
+
+/// Log how many LIST requests the `**` expansion expected the expansion and
+/// listing to take next to how many they did, to tune its estimates against
+fn log_list_estimate(estimated: Option<usize>, stats: &ApiStats) {
+    if let Some(estimated) = estimated {
+        let actual = stats.expansion.list_objects_v2 + stats.listing.list_objects_v2;
+        debug!(
+            estimated,
+            actual, "LIST requests for ** expansion and listing"
+        );
+    }
+}
+
+/// Tell the user how many requests were made, and what they cost
+fn report_api_calls(stats: &ApiStats, prices: Option<&PriceTable>) {
+    progressln!("{}", describe_api_calls(stats));
+    if let Some(prices) = prices {
+        progressln!(
+            "Estimated request cost: ${:.4} (requests only, not data transfer)",
+            prices.cost(stats.total())
+        );
+    }
+}
+
+/// e.g. `API calls: 12 LIST (2 discovery, 10 listing), 3 GET (download)`
+fn describe_api_calls(stats: &ApiStats) -> String {
+    let phases = [
+        ("discovery", stats.discovery),
+        ("** expansion", stats.expansion),
+        ("listing", stats.listing),
+        ("download", stats.download),
+    ];
+    let mut parts = Vec::new();
+    for (i, name) in ["LIST", "HEAD", "GET"].into_iter().enumerate() {
+        let by_phase: Vec<(&str, usize)> = phases
+            .iter()
+            .map(|(phase, c)| (*phase, [c.list_objects_v2, c.head_object, c.get_object][i]))
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        let total: usize = by_phase.iter().map(|(_, n)| n).sum();
+        if total == 0 {
+            continue;
+        }
+        let mut part = format!("{} {name}", total.to_formatted_string(&Locale::en));
+        if let [(phase, _)] = by_phase[..] {
+            part.push_str(&format!(" ({phase})"));
+        } else {
+            let breakdown = by_phase
+                .iter()
+                .map(|(phase, n)| format!("{} {phase}", n.to_formatted_string(&Locale::en)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            part.push_str(&format!(" ({breakdown})"));
+        }
+        parts.push(part);
+    }
+    if parts.is_empty() {
+        "API calls: none".to_owned()
+    } else {
+        format!("API calls: {}", parts.join(", "))
+    }
+}
+
+/// Report prefixes that could not be listed, failing if there were any.
+///
+/// Called after the results have been written, so the caller still gets
+/// everything that could be listed but also a nonzero exit.
+fn check_list_failures(failures: &[ListFailure]) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    message_err!("Failed to list {} prefixes:", failures.len());
+    for failure in failures {
+        message_err!("  {}: {}", failure.prefix, failure.error);
+    }
+    bail!(
+        "{} prefixes could not be fully listed, results are incomplete",
+        failures.len()
+    );
 }
 
 /// Classify the result of a write to stdout.
@@ -1135,32 +1976,32 @@ fn compile_format(format: &str) -> Result<Vec<FormatToken>> {
                 })),
                 "size_bytes" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => obj.size.to_string(),
-                    PrefixResult::Prefix(_) => "-1".to_owned(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => "-1".to_owned(),
                 })),
                 "size_human" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => {
                         SizeFormatter::new(obj.size as u64, decimal_format()).to_string()
                     }
-                    PrefixResult::Prefix(_) => "-1".to_owned(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => "-1".to_owned(),
                 })),
                 "last_modified" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => obj.last_modified.to_string(),
-                    PrefixResult::Prefix(_) => "-1".to_owned(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => "-1".to_owned(),
                 })),
                 "etag" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => obj.etag.clone().unwrap_or_default(),
-                    PrefixResult::Prefix(_) => String::new(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => String::new(),
                 })),
                 "storage_class" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => obj.storage_class.clone().unwrap_or_default(),
-                    PrefixResult::Prefix(_) => String::new(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => String::new(),
                 })),
                 "restore_in_progress" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => match &obj.restore_status {
                         Some(rs) => rs.in_progress.to_string(),
                         None => String::new(),
                     },
-                    PrefixResult::Prefix(_) => String::new(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => String::new(),
                 })),
                 "restore_expiry" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => obj
@@ -1168,7 +2009,7 @@ fn compile_format(format: &str) -> Result<Vec<FormatToken>> {
                         .as_ref()
                         .and_then(|rs| rs.expiry.clone())
                         .unwrap_or_default(),
-                    PrefixResult::Prefix(_) => String::new(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => String::new(),
                 })),
                 "checksums" => tokens.push(FormatToken::Variable(|_, obj| match obj {
                     PrefixResult::Object(obj) => obj
@@ -1176,7 +2017,7 @@ fn compile_format(format: &str) -> Result<Vec<FormatToken>> {
                         .as_ref()
                         .map(|v| v.join(","))
                         .unwrap_or_default(),
-                    PrefixResult::Prefix(_) => String::new(),
+                    PrefixResult::Prefix(_) | PrefixResult::Failed(_) => String::new(),
                 })),
                 _ => {
                     return Err(anyhow::anyhow!(
@@ -1379,4 +2220,137 @@ mod tests {
         assert!(v.get("size").is_none());
         assert!(v.get("last_modified").is_none());
     }
+
+    #[test]
+    fn test_describe_api_calls() {
+        let mut stats = ApiStats::default();
+        assert_eq!(describe_api_calls(&stats), "API calls: none");
+        stats.discovery.list_objects_v2 = 2;
+        stats.listing.list_objects_v2 = 1_500;
+        stats.listing.head_object = 3;
+        stats.download.get_object = 40;
+        assert_eq!(
+            describe_api_calls(&stats),
+            "API calls: 1,502 LIST (2 discovery, 1,500 listing), 3 HEAD (listing), 40 GET (download)"
+        );
+    }
+
+    #[test]
+    fn test_json_stats_shape() {
+        let calls = ApiCalls::default();
+        calls.record(stats::Phase::Expansion, stats::Api::ListObjectsV2);
+        calls.record(stats::Phase::Download, stats::Api::GetObject);
+        let record = JsonLsRecord::Stats {
+            stats: JsonStats::new(&calls, None),
+        };
+        let v = serde_json::to_value(&record).unwrap();
+        assert_eq!(v["type"], "stats");
+        assert_eq!(v["api_calls"]["expansion"]["list_objects_v2"], 1);
+        assert_eq!(v["total_api_calls"]["get_object"], 1);
+        assert!(v.get("estimated_cost_usd").is_none());
+
+        let stats = JsonStats::new(&calls, Some(&PriceTable::default()));
+        let v = serde_json::to_value(&stats).unwrap();
+        assert_eq!(v["estimated_cost_usd"], (0.005 + 0.0004) / 1000.0);
+    }
+
+    #[test]
+    fn test_json_list_failure_shapes() {
+        let result = PrefixResult::failed("dir/", anyhow!("AccessDenied"));
+        let record = JsonLsRecord::from_result("bkt", &result);
+        let v = serde_json::to_value(&record).unwrap();
+        assert_eq!(v["type"], "error");
+        assert_eq!(v["bucket"], "bkt");
+        assert_eq!(v["prefix"], "dir/");
+        assert_eq!(v["uri"], "s3://bkt/dir/");
+        assert_eq!(v["error"], "AccessDenied");
+
+        let PrefixResult::Failed(failure) = &result else {
+            unreachable!()
+        };
+        let event = JsonDlEvent::Error {
+            failure: JsonListFailure::new("bkt", failure),
+        };
+        let v = serde_json::to_value(&event).unwrap();
+        assert_eq!(v["event"], "error");
+        assert_eq!(v["prefix"], "dir/");
+    }
+
+    #[rstest]
+    #[case("1024", 1024)]
+    #[case("512K", 512 << 10)]
+    #[case("8M", 8 << 20)]
+    #[case("8MiB", 8 << 20)]
+    #[case("8MB", 8 << 20)]
+    #[case("1g", 1 << 30)]
+    fn test_parse_byte_size(#[case] raw: &str, #[case] expected: u64) {
+        assert_eq!(parse_byte_size(raw), Ok(expected));
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("0")]
+    #[case("M")]
+    #[case("8T")]
+    #[case("-1K")]
+    fn test_parse_byte_size_invalid(#[case] raw: &str) {
+        assert!(parse_byte_size(raw).is_err(), "raw: {raw}");
+    }
+
+    #[rstest]
+    #[case("100", Ok(100.0))]
+    #[case(" 0.5 ", Ok(0.5))]
+    #[case("0", Err(()))]
+    #[case("-3", Err(()))]
+    #[case("inf", Err(()))]
+    #[case("fast", Err(()))]
+    fn test_parse_rate(#[case] raw: &str, #[case] expected: Result<f64, ()>) {
+        assert_eq!(parse_rate(raw).map_err(|_| ()), expected);
+    }
+
+    #[test]
+    fn test_json_dl_skipped_and_renamed_shapes() {
+        let object = Object::builder().key("a/b.txt").size(42).build();
+        let mut record = DownloadedRecord {
+            object: S3Object::from(object),
+            local_path: PathBuf::from("out/b.txt"),
+            renamed: false,
+            sha256: None,
+        };
+        let event = JsonDlEvent::Skipped {
+            record: JsonDlObject::new("bkt", &record),
+        };
+        let v = serde_json::to_value(&event).unwrap();
+        assert_eq!(v["event"], "skipped");
+        assert_eq!(v["local_path"], "out/b.txt");
+        assert!(v.get("renamed").is_none());
+
+        record.local_path = PathBuf::from("out/b-1.txt");
+        record.renamed = true;
+        let event = JsonDlEvent::Downloaded {
+            record: JsonDlObject::new("bkt", &record),
+        };
+        let v = serde_json::to_value(&event).unwrap();
+        assert_eq!(v["event"], "downloaded");
+        assert_eq!(v["renamed"], true);
+    }
+
+    #[test]
+    fn test_json_dl_failed_event_shape() {
+        let object = Object::builder().key("a/b.txt").size(42).build();
+        let record = FailedRecord {
+            object: S3Object::from(object),
+            error: "Failed to download a/b.txt: AccessDenied".to_owned(),
+        };
+        let event = JsonDlEvent::Failed {
+            record: JsonDlFailure::new("bkt", &record),
+        };
+        let v = serde_json::to_value(&event).unwrap();
+        assert_eq!(v["event"], "failed");
+        assert_eq!(v["bucket"], "bkt");
+        assert_eq!(v["key"], "a/b.txt");
+        assert_eq!(v["size"], 42);
+        assert_eq!(v["error"], "Failed to download a/b.txt: AccessDenied");
+        assert!(v.get("local_path").is_none());
+    }
 }
@@ -0,0 +1,273 @@
+//! Hard caps on how fast we talk to S3
+//!
+//! Unlike the AIMD [`Limiter`](crate::limiter::Limiter), which finds out how
+//! much S3 will take, these never let more than a fixed number of requests
+//! (or bytes) per second through. That's what buckets that share a request
+//! budget with production traffic need. S3 scales request rates per
+//! partitioned prefix, so requests can also be capped per prefix.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Paces tokens to `rate` per second, allowing bursts of up to a second's worth
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: f64, now: Instant) -> Self {
+        let rate = rate.max(f64::MIN_POSITIVE);
+        let burst = rate.max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    /// Take `n` tokens, returning how long until the bucket is out of debt
+    pub(crate) fn reserve(&mut self, n: u64, now: Instant) -> Duration {
+        let refill = now.saturating_duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.burst) - n as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// True if the bucket would have refilled completely by `now`, so
+    /// starting over with a new one makes no difference
+    fn is_full(&self, now: Instant) -> bool {
+        let refill = now.saturating_duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens + refill >= self.burst
+    }
+}
+
+/// Requests per second, overall and for each prefix
+#[derive(Debug, Default)]
+pub(crate) struct RequestRate {
+    overall: Option<Mutex<TokenBucket>>,
+    per_prefix: Option<PerPrefix>,
+    waited_micros: AtomicU64,
+}
+
+#[derive(Debug)]
+struct PerPrefix {
+    rate: f64,
+    delimiter: String,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RequestRate {
+    /// Limit requests to `overall` per second, and to `per_prefix` per second
+    /// for keys under each prefix (see [`prefix_bucket`])
+    pub(crate) fn new(overall: Option<f64>, per_prefix: Option<f64>, delimiter: &str) -> Self {
+        let now = Instant::now();
+        Self {
+            overall: overall.map(|rate| Mutex::new(TokenBucket::new(rate, now))),
+            per_prefix: per_prefix.map(|rate| PerPrefix {
+                rate,
+                delimiter: delimiter.to_owned(),
+                buckets: Mutex::new(HashMap::new()),
+            }),
+            waited_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait until a request for `key` (or a listing of it) may be sent
+    pub(crate) async fn wait(&self, key: &str) {
+        let delay = self.reserve(key, Instant::now());
+        if !delay.is_zero() {
+            self.waited_micros
+                .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// How long requests have spent waiting, added up across all of them
+    pub(crate) fn waited(&self) -> Duration {
+        Duration::from_micros(self.waited_micros.load(Ordering::Relaxed))
+    }
+
+    fn reserve(&self, key: &str, now: Instant) -> Duration {
+        let mut delay = Duration::ZERO;
+        if let Some(bucket) = &self.overall {
+            let mut bucket = bucket.lock().expect("rate limit lock is never poisoned");
+            delay = delay.max(bucket.reserve(1, now));
+        }
+        if let Some(per_prefix) = &self.per_prefix {
+            let name = prefix_bucket(key, &per_prefix.delimiter);
+            let mut buckets = per_prefix
+                .buckets
+                .lock()
+                .expect("rate limit lock is never poisoned");
+            if !buckets.contains_key(name) {
+                // only buckets that were used recently are worth keeping
+                buckets.retain(|_, bucket| !bucket.is_full(now));
+            }
+            let bucket = buckets
+                .entry(name.to_owned())
+                .or_insert_with(|| TokenBucket::new(per_prefix.rate, now));
+            delay = delay.max(bucket.reserve(1, now));
+        }
+        delay
+    }
+}
+
+/// The name of the per-prefix budget that requests for `key` count against
+///
+/// This is the first character of the key's top-level prefix, so that a
+/// listing of the partial prefix `lo` and requests for `logs` and
+/// `logs/2024/a.txt` all count against the same budget, and so do the keys
+/// of a flat bucket, however many there are. Only listing the whole bucket
+/// is on a budget of its own.
+fn prefix_bucket<'a>(key: &'a str, delimiter: &str) -> &'a str {
+    let top = match key.find(delimiter) {
+        Some(i) => &key[..i],
+        None => key,
+    };
+    let first = top.chars().next().map_or(0, char::len_utf8);
+    &top[..first]
+}
+
+#[cfg(test)]
+impl RequestRate {
+    fn bucket_count(&self) -> usize {
+        self.per_prefix.as_ref().map_or(0, |per_prefix| {
+            per_prefix
+                .buckets
+                .lock()
+                .expect("rate limit lock is never poisoned")
+                .len()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_paces_to_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1_000.0, start);
+        check!(
+            bucket.reserve(1_000, start) == Duration::ZERO,
+            "a second's worth of burst"
+        );
+        check!(bucket.reserve(500, start) == Duration::from_millis(500));
+        // half a second later the debt is paid
+        let later = start + Duration::from_millis(500);
+        check!(bucket.reserve(0, later) == Duration::ZERO);
+        // and the burst is capped at a second's worth, however long we wait
+        let much_later = later + Duration::from_secs(60);
+        check!(bucket.reserve(2_000, much_later) == Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_token_bucket_below_one_per_second() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(0.5, start);
+        check!(bucket.reserve(1, start) == Duration::ZERO);
+        check!(bucket.reserve(1, start) == Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_prefix_bucket() {
+        check!(prefix_bucket("logs/2024/a.txt", "/") == "l");
+        check!(prefix_bucket("logs/", "/") == "l");
+        check!(prefix_bucket("lo", "/") == "l");
+        check!(prefix_bucket("logs::a", "::") == "l");
+        check!(prefix_bucket("été/a.txt", "/") == "é");
+        check!(prefix_bucket("a.txt", "/") == "a");
+        check!(prefix_bucket("/a.txt", "/") == "");
+        check!(prefix_bucket("", "/") == "");
+    }
+
+    #[test]
+    fn test_request_rate_per_prefix() {
+        let rate = RequestRate::new(None, Some(2.0), "/");
+        let now = Instant::now();
+        check!(rate.reserve("a/1", now) == Duration::ZERO);
+        check!(rate.reserve("a/2", now) == Duration::ZERO);
+        check!(rate.reserve("a/3", now) == Duration::from_millis(500));
+        // other prefixes have their own budget
+        check!(rate.reserve("b/1", now) == Duration::ZERO);
+    }
+
+    #[test]
+    fn test_request_rate_per_prefix_with_or_without_delimiter() {
+        let rate = RequestRate::new(None, Some(2.0), "/");
+        let now = Instant::now();
+        check!(rate.reserve("logs", now) == Duration::ZERO);
+        check!(rate.reserve("logs/", now) == Duration::ZERO);
+        check!(rate.reserve("logs/2024/", now) == Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_request_rate_per_prefix_in_a_flat_bucket() {
+        let rate = RequestRate::new(None, Some(100.0), "/");
+        let now = Instant::now();
+        let delays = (0..10_000)
+            .map(|i| rate.reserve(&format!("key-{i:05}.txt"), now))
+            .collect::<Vec<_>>();
+        check!(delays[99] == Duration::ZERO);
+        check!(delays[100] == Duration::from_millis(10));
+        check!(delays[9_999] == Duration::from_millis(99_000));
+        check!(rate.bucket_count() == 1);
+    }
+
+    #[test]
+    fn test_request_rate_drops_idle_prefixes() {
+        let rate = RequestRate::new(None, Some(10.0), "/");
+        let now = Instant::now();
+        for c in 'a'..='z' {
+            check!(rate.reserve(&format!("{c}/1"), now) == Duration::ZERO);
+        }
+        check!(rate.bucket_count() == 26);
+        // a second later every bucket has refilled, and only the ones used
+        // since are kept
+        let later = now + Duration::from_secs(1);
+        check!(rate.reserve("z/2", later) == Duration::ZERO);
+        check!(rate.reserve("0/1", later) == Duration::ZERO);
+        check!(rate.bucket_count() == 2);
+    }
+
+    #[test]
+    fn test_request_rate_overall_applies_across_prefixes() {
+        let rate = RequestRate::new(Some(1.0), Some(10.0), "/");
+        let now = Instant::now();
+        check!(rate.reserve("a/1", now) == Duration::ZERO);
+        check!(rate.reserve("b/1", now) == Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        let rate = RequestRate::default();
+        let now = Instant::now();
+        for _ in 0..1_000 {
+            check!(rate.reserve("a/1", now) == Duration::ZERO);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_adds_up_time_spent_waiting() {
+        let rate = RequestRate::new(Some(100.0), None, "/");
+        for _ in 0..101 {
+            rate.wait("a").await;
+        }
+        check!(rate.waited() > Duration::ZERO);
+        check!(rate.waited() <= Duration::from_millis(10));
+    }
+}
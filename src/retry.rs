@@ -0,0 +1,220 @@
+//! Retrying S3 requests that fail for reasons that are likely to go away
+//!
+//! The SDK already retries a couple of times, but during a large listing
+//! or download a burst of throttling can outlast that. We'd rather wait a
+//! little longer than report a prefix as failed.
+
+use std::future::Future;
+use std::time::Duration;
+
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use tracing::debug;
+
+use crate::limiter::Limiter;
+
+/// How many times to try a request before giving up on a transient error
+pub(crate) const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before the first retry, doubled for each one after that
+const BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// The longest we'll wait between two attempts
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Error codes S3 uses for throttling and server-side hiccups
+const TRANSIENT_CODES: &[&str] = &[
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "RequestTimeout",
+    "RequestTimeTooSkewed",
+    "InternalError",
+    "ServiceUnavailable",
+];
+
+/// True if `err` is worth retrying
+///
+/// Timeouts and connection failures are, as are throttling responses and
+/// 5xx errors. Anything else (access denied, missing bucket, ...) will fail
+/// the same way the next time.
+pub(crate) fn is_transient<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service) => {
+            let status = service.raw().status().as_u16();
+            status == 429
+                || status >= 500
+                || service
+                    .err()
+                    .code()
+                    .is_some_and(|code| TRANSIENT_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+/// Error codes S3 uses to ask for fewer requests
+const THROTTLE_CODES: &[&str] = &["SlowDown", "Throttling", "ThrottlingException"];
+
+/// True if `err` is S3 asking us to send fewer requests
+///
+/// That's a 503 (which is how `SlowDown` arrives), a 429, or one of the
+/// throttling error codes.
+pub(crate) fn is_throttle<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::ServiceError(service) => {
+            let status = service.raw().status().as_u16();
+            status == 503
+                || status == 429
+                || service
+                    .err()
+                    .code()
+                    .is_some_and(|code| THROTTLE_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+/// How long to wait after failed attempt number `attempt` (starting at 1)
+pub(crate) fn backoff(attempt: u32) -> Duration {
+    BASE_DELAY
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_DELAY)
+}
+
+/// Run `op` until it succeeds, fails permanently, or runs out of attempts
+///
+/// `what` describes the request for the debug log.
+pub(crate) async fn retry<T, E, F, Fut>(what: &str, op: F) -> Result<T, SdkError<E, HttpResponse>>
+where
+    E: ProvideErrorMetadata,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+{
+    retry_limited(&Limiter::unlimited(), "", what, op).await
+}
+
+/// Like [`retry`], with each attempt waiting its turn in `limiter`
+///
+/// Throttled attempts shrink the limiter and successful ones grow it.
+/// `key` is the key or prefix the request is for, which decides which
+/// per-prefix request rate it counts against.
+pub(crate) async fn retry_limited<T, E, F, Fut>(
+    limiter: &Limiter,
+    key: &str,
+    what: &str,
+    mut op: F,
+) -> Result<T, SdkError<E, HttpResponse>>
+where
+    E: ProvideErrorMetadata,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+{
+    let mut attempt = 1;
+    loop {
+        let permit = limiter.acquire(key).await;
+        let result = op().await;
+        match &result {
+            Ok(_) => permit.succeeded(),
+            Err(err) if is_throttle(err) => permit.throttled(),
+            Err(_) => drop(permit),
+        }
+        match result {
+            Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                let delay = backoff(attempt);
+                debug!(%what, attempt, ?delay, code = ?err.code(), "retrying transient error");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use assert2::check;
+    use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Error;
+    use aws_smithy_types::body::SdkBody;
+    use aws_smithy_types::error::ErrorMetadata;
+
+    use super::*;
+
+    fn service_error(status: u16, code: &str) -> SdkError<ListObjectsV2Error, HttpResponse> {
+        let err = ListObjectsV2Error::generic(ErrorMetadata::builder().code(code).build());
+        let raw = HttpResponse::new(status.try_into().unwrap(), SdkBody::empty());
+        SdkError::service_error(err, raw)
+    }
+
+    #[test]
+    fn test_is_transient() {
+        check!(is_transient(&service_error(503, "SlowDown")));
+        check!(is_transient(&service_error(500, "InternalError")));
+        check!(is_transient(&service_error(400, "RequestTimeout")));
+        check!(is_transient(
+            &SdkError::<ListObjectsV2Error, _>::timeout_error("slow")
+        ));
+        check!(!is_transient(&service_error(403, "AccessDenied")));
+        check!(!is_transient(&service_error(404, "NoSuchBucket")));
+    }
+
+    #[test]
+    fn test_is_throttle() {
+        check!(is_throttle(&service_error(503, "SlowDown")));
+        check!(is_throttle(&service_error(429, "TooManyRequests")));
+        check!(is_throttle(&service_error(400, "ThrottlingException")));
+        check!(!is_throttle(&service_error(500, "InternalError")));
+        check!(!is_throttle(
+            &SdkError::<ListObjectsV2Error, _>::timeout_error("slow")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_limited_backs_off_on_throttles() {
+        let limiter = Limiter::new(8);
+        let attempts = AtomicU32::new(0);
+        let result = retry_limited(&limiter, "", "test", || async {
+            if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                Err(service_error(503, "SlowDown"))
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+        check!(result.is_ok());
+        check!(limiter.throttles() == 2);
+        check!(limiter.limit() == 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let attempts = AtomicU32::new(0);
+        let result = retry("test", || async {
+            if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                Err(service_error(503, "SlowDown"))
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+        check!(result.is_ok());
+        check!(attempts.load(Ordering::Relaxed) == 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_stops_on_permanent_error() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = retry("test", || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(service_error(403, "AccessDenied"))
+        })
+        .await;
+        check!(result.is_err());
+        check!(attempts.load(Ordering::Relaxed) == 1);
+    }
+}
@@ -0,0 +1,218 @@
+//! Counting the S3 requests a run makes, and what they cost
+//!
+//! S3 bills per request, so batch jobs want to know how many LISTs a
+//! pattern took. Every attempt is counted, retries included, since those
+//! are billed too.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+/// What a request was made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Finding the prefixes to list from the pattern
+    Discovery,
+    /// Splitting up the prefixes at a `**` to list them in parallel
+    Expansion,
+    /// Listing the matching objects under the discovered prefixes
+    Listing,
+    /// Downloading the matches
+    Download,
+}
+
+/// The S3 operations we make
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Api {
+    ListObjectsV2,
+    HeadObject,
+    GetObject,
+}
+
+const PHASES: [Phase; 4] = [
+    Phase::Discovery,
+    Phase::Expansion,
+    Phase::Listing,
+    Phase::Download,
+];
+
+/// Request counts by phase and operation, shared by everything that sends
+/// requests
+#[derive(Debug, Default)]
+pub(crate) struct ApiCalls {
+    counts: [[AtomicUsize; 3]; 4],
+}
+
+impl ApiCalls {
+    /// Count one request
+    pub(crate) fn record(&self, phase: Phase, api: Api) {
+        self.counts[phase as usize][api as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The requests made so far
+    pub(crate) fn stats(&self) -> ApiStats {
+        let [discovery, expansion, listing, download] = PHASES.map(|phase| {
+            let counts = &self.counts[phase as usize];
+            let count = |api: Api| counts[api as usize].load(Ordering::Relaxed);
+            CallCounts {
+                list_objects_v2: count(Api::ListObjectsV2),
+                head_object: count(Api::HeadObject),
+                get_object: count(Api::GetObject),
+            }
+        });
+        ApiStats {
+            discovery,
+            expansion,
+            listing,
+            download,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct CallCounts {
+    pub(crate) list_objects_v2: usize,
+    pub(crate) head_object: usize,
+    pub(crate) get_object: usize,
+}
+
+impl CallCounts {
+    fn add(self, other: Self) -> Self {
+        Self {
+            list_objects_v2: self.list_objects_v2 + other.list_objects_v2,
+            head_object: self.head_object + other.head_object,
+            get_object: self.get_object + other.get_object,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct ApiStats {
+    /// Requests made while finding the prefixes to list
+    pub(crate) discovery: CallCounts,
+    /// Requests made while expanding `**` into more prefixes
+    pub(crate) expansion: CallCounts,
+    /// Requests made while listing the discovered prefixes
+    pub(crate) listing: CallCounts,
+    /// Requests made while downloading
+    pub(crate) download: CallCounts,
+}
+
+impl ApiStats {
+    pub(crate) fn total(&self) -> CallCounts {
+        self.discovery
+            .add(self.expansion)
+            .add(self.listing)
+            .add(self.download)
+    }
+}
+
+/// USD per 1,000 requests of each kind
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PriceTable {
+    pub(crate) list: f64,
+    pub(crate) head: f64,
+    pub(crate) get: f64,
+}
+
+impl Default for PriceTable {
+    /// S3 Standard in us-east-1
+    fn default() -> Self {
+        Self {
+            list: 0.005,
+            head: 0.0004,
+            get: 0.0004,
+        }
+    }
+}
+
+impl PriceTable {
+    /// Parse `list=0.005,get=0.0004`, prices left out keep their default
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        let mut prices = Self::default();
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, price) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid price: {entry} (expected e.g. list=0.005)"))?;
+            let price: f64 = price
+                .trim()
+                .parse()
+                .ok()
+                .filter(|p: &f64| p.is_finite() && *p >= 0.0)
+                .ok_or_else(|| format!("invalid price for {name}: {price}"))?;
+            match name.trim().to_lowercase().as_str() {
+                "list" => prices.list = price,
+                "head" => prices.head = price,
+                "get" => prices.get = price,
+                other => {
+                    return Err(format!(
+                        "unknown request kind: {other} (expected list, head, or get)"
+                    ));
+                }
+            }
+        }
+        Ok(prices)
+    }
+
+    /// What `calls` cost in USD
+    pub(crate) fn cost(&self, calls: CallCounts) -> f64 {
+        (calls.list_objects_v2 as f64 * self.list
+            + calls.head_object as f64 * self.head
+            + calls.get_object as f64 * self.get)
+            / 1000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert2::check;
+
+    use super::*;
+
+    #[test]
+    fn test_counts_by_phase() {
+        let calls = ApiCalls::default();
+        calls.record(Phase::Discovery, Api::ListObjectsV2);
+        calls.record(Phase::Discovery, Api::ListObjectsV2);
+        calls.record(Phase::Expansion, Api::ListObjectsV2);
+        calls.record(Phase::Listing, Api::HeadObject);
+        calls.record(Phase::Download, Api::GetObject);
+        let stats = calls.stats();
+        check!(stats.discovery.list_objects_v2 == 2);
+        check!(stats.expansion.list_objects_v2 == 1);
+        check!(stats.listing.head_object == 1);
+        check!(stats.download.get_object == 1);
+        check!(
+            stats.total()
+                == CallCounts {
+                    list_objects_v2: 3,
+                    head_object: 1,
+                    get_object: 1,
+                }
+        );
+    }
+
+    #[test]
+    fn test_parse_prices() {
+        check!(PriceTable::parse("") == Ok(PriceTable::default()));
+        let prices = PriceTable::parse("list=0.0054, GET=0.00043").unwrap();
+        check!(prices.list == 0.0054);
+        check!(prices.get == 0.00043);
+        check!(prices.head == PriceTable::default().head);
+        check!(PriceTable::parse("put=0.005").is_err());
+        check!(PriceTable::parse("list").is_err());
+        check!(PriceTable::parse("list=-1").is_err());
+    }
+
+    #[test]
+    fn test_cost() {
+        let prices = PriceTable::default();
+        let calls = CallCounts {
+            list_objects_v2: 2_000,
+            head_object: 1_000,
+            get_object: 10_000,
+        };
+        let cost = prices.cost(calls);
+        check!((cost - (0.01 + 0.0004 + 0.004)).abs() < 1e-12);
+    }
+}
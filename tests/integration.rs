@@ -680,6 +680,360 @@ async fn test_download_flatten() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_download_ranged() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "ranged-test";
+    client.create_bucket().bucket(bucket).send().await?;
+
+    // distinct bytes everywhere so misplaced ranges would show up
+    let body: Vec<u8> = (0..10_000_u32).map(|i| (i % 251) as u8).collect();
+    client
+        .put_object()
+        .bucket(bucket)
+        .key("prefix/big.bin")
+        .checksum_algorithm(sha256())
+        .body(ByteStream::from(body.clone()))
+        .send()
+        .await?;
+
+    let tempdir = TempDir::new()?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--multipart-threshold",
+            "4K",
+            "--part-size",
+            "1K",
+            "--part-concurrency",
+            "3",
+            format!("s3://{bucket}/prefix/*").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+
+    let downloaded = std::fs::read(tempdir.child("big.bin").path())?;
+    assert!(
+        downloaded == body,
+        "ranged download reassembled incorrectly"
+    );
+    Ok(())
+}
+
+#[rstest]
+#[case::overwrite("overwrite", "a", None)]
+#[case::skip("skip", "local", None)]
+#[case::newer("newer", "a", None)]
+#[case::rename("rename", "local", Some("file-1.txt"))]
+#[tokio::test]
+async fn test_download_if_exists(
+    #[case] policy: &str,
+    #[case] expected_contents: &str,
+    #[case] renamed_to: Option<&str>,
+) -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = format!("if-exists-{policy}");
+    client.create_bucket().bucket(&bucket).send().await?;
+    create_object(&client, &bucket, "prefix/file.txt").await?;
+
+    // a different size than the object, and written before it was uploaded
+    let tempdir = TempDir::new()?;
+    tempdir.child("file.txt").write_str("local")?;
+    let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+    std::fs::File::options()
+        .write(true)
+        .open(tempdir.child("file.txt").path())?
+        .set_modified(old)?;
+
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--if-exists",
+            policy,
+            format!("s3://{bucket}/prefix/*").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+
+    tempdir.child("file.txt").assert(expected_contents);
+    if let Some(renamed_to) = renamed_to {
+        tempdir.child(renamed_to).assert("a");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_if_exists_error() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "if-exists-error";
+    client.create_bucket().bucket(bucket).send().await?;
+    create_object(&client, bucket, "prefix/file.txt").await?;
+
+    let tempdir = TempDir::new()?;
+    tempdir.child("file.txt").write_str("local")?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--if-exists",
+            "error",
+            format!("s3://{bucket}/prefix/*").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().failure().stderr(contains("already exists"));
+    tempdir.child("file.txt").assert("local");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_preserve() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "preserve-test";
+    client.create_bucket().bucket(bucket).send().await?;
+    client
+        .put_object()
+        .bucket(bucket)
+        .key("prefix/file.txt")
+        .content_type("text/plain")
+        .metadata("owner", "data-team")
+        .body(ByteStream::from_static(b"hello"))
+        .send()
+        .await?;
+    let head = client
+        .head_object()
+        .bucket(bucket)
+        .key("prefix/file.txt")
+        .send()
+        .await?;
+    let last_modified = std::time::SystemTime::try_from(*head.last_modified().unwrap())?;
+
+    let tempdir = TempDir::new()?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--preserve",
+            format!("s3://{bucket}/prefix/*").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+
+    let path = tempdir.child("file.txt");
+    assert_eq!(std::fs::metadata(path.path())?.modified()?, last_modified);
+    #[cfg(target_os = "linux")]
+    if let Ok(Some(content_type)) = xattr::get(path.path(), "user.s3glob.content_type") {
+        // only when the temp dir's filesystem supports user xattrs
+        assert_eq!(content_type, b"text/plain");
+        let owner = xattr::get(path.path(), "user.s3glob.meta.owner")?;
+        assert_eq!(owner.as_deref(), Some(&b"data-team"[..]));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_verify_and_write_checksums() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "verify-test";
+    client.create_bucket().bucket(bucket).send().await?;
+    // one checked against its ETag, one against its additional checksum
+    create_object(&client, bucket, "prefix/etag.txt").await?;
+    create_object_with_checksum(&client, bucket, "prefix/sha.txt", 1, sha256()).await?;
+
+    let tempdir = TempDir::new()?;
+    let manifest = tempdir.child("sha256sum.txt");
+    let out = tempdir.child("out");
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--verify",
+            "--write-checksums",
+            manifest.path().to_str().unwrap(),
+            format!("s3://{bucket}/prefix/*").as_str(),
+            out.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+
+    // sha256("a")
+    let hash = "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb";
+    let expected = format!(
+        "{hash}  {}\n{hash}  {}\n",
+        out.child("etag.txt").path().display(),
+        out.child("sha.txt").path().display(),
+    );
+    manifest.assert(expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_flatten_collisions() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "collisions-test";
+    client.create_bucket().bucket(bucket).send().await?;
+    create_object(&client, bucket, "prefix/a/b-c.txt").await?;
+    create_object(&client, bucket, "prefix/a-b/c.txt").await?;
+
+    let tempdir = TempDir::new()?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--flatten",
+            "--on-collision",
+            "error",
+            format!("s3://{bucket}/prefix/**/*.txt").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("would both be downloaded to"));
+    tempdir
+        .child("a-b-c.txt")
+        .assert(predicate::path::missing());
+
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--flatten",
+            format!("s3://{bucket}/prefix/**/*.txt").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+    tempdir.child("a-b-c.txt").assert(predicate::path::exists());
+    tempdir
+        .child("a-b-c-1.txt")
+        .assert(predicate::path::exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_ignore_case_strips_prefix_in_any_case() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "ignore-case-test";
+    client.create_bucket().bucket(bucket).send().await?;
+    create_object(&client, bucket, "INVOICE/a.csv").await?;
+    create_object(&client, bucket, "Invoice/b.csv").await?;
+
+    let tempdir = TempDir::new()?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "-i",
+            format!("s3://{bucket}/Invoice/*.csv").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+    tempdir.child("a.csv").assert(predicate::path::exists());
+    tempdir.child("b.csv").assert(predicate::path::exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_anchored_gitignore_pattern() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "gitignore-test";
+    client.create_bucket().bucket(bucket).send().await?;
+    create_object(&client, bucket, "logs/x.log").await?;
+    create_object(&client, bucket, "other/logs/y.log").await?;
+
+    let tempdir = TempDir::new()?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--glob-dialect",
+            "gitignore",
+            format!("s3://{bucket}//logs/*.log").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+    tempdir.child("x.log").assert(predicate::path::exists());
+    tempdir.child("y.log").assert(predicate::path::missing());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_download_recursive_prefixes() -> anyhow::Result<()> {
+    let (_node, port, client) = minio_and_client().await;
+
+    let bucket = "recursive-test";
+    client.create_bucket().bucket(bucket).send().await?;
+    for key in [
+        "data/2023-12/z.csv",
+        "data/2024-01/a.csv",
+        "data/2024-01/nested/b.csv",
+        "data/2024-02/c.csv",
+        "data/2024-notes.txt",
+    ] {
+        create_object(&client, bucket, key).await?;
+    }
+
+    let tempdir = TempDir::new()?;
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            format!("s3://{bucket}/data/2024-*").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("pass --recursive"));
+    tempdir
+        .child("2024-notes.txt")
+        .assert(predicate::path::exists());
+    tempdir.child("2024-01").assert(predicate::path::missing());
+
+    let mut cmd = run_s3glob(
+        port,
+        &[
+            "dl",
+            "--recursive",
+            format!("s3://{bucket}/data/2024-*").as_str(),
+            tempdir.path().to_str().unwrap(),
+        ],
+    )?;
+    let _ = cmd.assert().success();
+    for expected in [
+        "2024-notes.txt",
+        "2024-01/a.csv",
+        "2024-01/nested/b.csv",
+        "2024-02/c.csv",
+    ] {
+        tempdir.child(expected).assert(predicate::path::exists());
+    }
+    tempdir.child("2023-12").assert(predicate::path::missing());
+
+    Ok(())
+}
+
 //
 // Helpers
 //
@@ -1061,11 +1415,16 @@ async fn test_ls_output_json_round_trip(
     );
 
     let pattern = format!("s3://{bucket}/prefix/*");
-    let stdout = run_and_capture_stdout(run_s3glob(
-        port,
-        &["ls", "--output", mode, pattern.as_str()],
-    )?)?;
+    let output = run_s3glob(port, &["ls", "--output", mode, pattern.as_str()])?.output()?;
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout)?;
     let records = parse_records(&stdout, mode)?;
+    assert!(
+        records
+            .iter()
+            .all(|r| r["type"] == "object" || r["type"] == "prefix"),
+        "only objects and prefixes on stdout: {records:#?}"
+    );
 
     let objects: Vec<&serde_json::Value> =
         records.iter().filter(|r| r["type"] == "object").collect();
@@ -1093,6 +1452,15 @@ async fn test_ls_output_json_round_trip(
         }
     }
 
+    // the S3 requests made go to stderr, among the log lines
+    let stderr = String::from_utf8(output.stderr)?;
+    let stats = stderr
+        .lines()
+        .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+        .find(|r| r["type"] == "stats")
+        .ok_or_else(|| anyhow::anyhow!("expected a stats record on stderr:\n{stderr}"))?;
+    assert!(stats["total_api_calls"]["list_objects_v2"].as_u64() >= Some(1));
+
     if expect_sorted {
         let keys: Vec<&str> = objects.iter().map(|r| r["key"].as_str().unwrap()).collect();
         let mut sorted = keys.clone();
@@ -1246,6 +1614,12 @@ async fn test_dl_output_json_round_trip(
             "summary missing or non-integer {field}: {summary}"
         );
     }
+    let gets = &summary["stats"]["api_calls"]["download"]["get_object"];
+    assert!(
+        gets.as_u64() >= Some(server_by_key.len() as u64),
+        "expected at least one GET per object: {summary}"
+    );
+    assert!(summary["stats"]["total_api_calls"]["list_objects_v2"].as_u64() >= Some(1));
     Ok(())
 }
 